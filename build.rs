@@ -0,0 +1,29 @@
+fn main() {
+    #[cfg(feature = "ffi")]
+    generate_header();
+}
+
+/// Writes the C header for `src/ffi.rs` to `$OUT_DIR/mailbox.h`.
+///
+/// The checked-in `include/mailbox.h` is only rewritten when
+/// `MAILBOX_WRITE_HEADER` is set, so ordinary builds leave the tree clean.
+#[cfg(feature = "ffi")]
+fn generate_header() {
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed=MAILBOX_WRITE_HEADER");
+
+    let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", crate_dir))
+        .expect("invalid cbindgen.toml");
+    let bindings = cbindgen::Builder::new()
+        .with_src(format!("{}/src/ffi.rs", crate_dir))
+        .with_config(config)
+        .generate()
+        .expect("failed to generate the C header");
+    bindings.write_to_file(format!("{}/mailbox.h", out_dir));
+    if std::env::var_os("MAILBOX_WRITE_HEADER").is_some() {
+        bindings.write_to_file(format!("{}/include/mailbox.h", crate_dir));
+    }
+}
@@ -14,7 +14,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mailbox_clone = mailbox.clone();
 
     println!("Starting service at {}", service_addr);
-    mailbox.subscribe(service_addr.parse()?, Box::new(move |msg: MailMessage| {
+    let _service = mailbox.subscribe(service_addr.parse()?, Box::new(move |msg: MailMessage| {
         let mailbox = mailbox_clone.clone();
         Box::pin(async move {
             println!("[Service] Received request: {:?}", msg.body);
@@ -32,50 +32,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
             println!("[Service] Computed result: {}", result);
 
-            // Reply to sender
-            let reply = OutgoingMail {
-                id: None,
-                from: msg.to.clone(),
-                to: msg.from.clone(),
-                body: json!({ "result": result }),
-                headers: HashMap::new(),
-                meta: HashMap::new(),
-            };
-
-            if let Err(e) = mailbox.post(reply).await {
+            // Reply to the caller's reply address
+            if let Err(e) = mailbox.reply(&msg, json!({ "result": result })).await {
                 eprintln!("[Service] Failed to send reply: {}", e);
             }
         })
     })).await?;
 
-    // 2. Client sends request
+    // 2. Client calls the service and waits for the reply
     let client_addr = "mem:client/user1";
-    let (tx, rx) = tokio::sync::oneshot::channel();
-    let tx = std::sync::Arc::new(std::sync::Mutex::new(Some(tx)));
-
-    println!("Starting client at {}", client_addr);
-    mailbox.subscribe(client_addr.parse()?, Box::new(move |msg: MailMessage| {
-        let tx = tx.clone();
-        Box::pin(async move {
-            println!("[Client] Received reply: {:?}", msg.body);
-            if let Some(tx) = tx.lock().unwrap().take() {
-                let _ = tx.send(msg);
-            }
-        })
-    })).await?;
 
     println!("[Client] Sending request: 10 + 20");
-    mailbox.post(OutgoingMail {
+    let reply = mailbox.call(OutgoingMail {
         id: None,
         from: client_addr.parse()?,
         to: service_addr.parse()?,
         body: json!({ "op": "add", "args": [10, 20] }),
         headers: HashMap::new(),
         meta: HashMap::new(),
-    }).await?;
-
-    // Wait for reply
-    let reply = tokio::time::timeout(Duration::from_secs(2), rx).await??;
+    }, Duration::from_secs(2)).await?;
     println!("[Client] Got result: {}", reply.body["result"]);
 
     Ok(())
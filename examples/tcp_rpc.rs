@@ -0,0 +1,115 @@
+//! The `p2p_rpc` example split across two processes over TCP.
+//!
+//! ```bash
+//! cargo run --example tcp_rpc -- server 127.0.0.1:7878
+//! cargo run --example tcp_rpc -- client 127.0.0.1:7878
+//! ```
+
+use mailbox::{Mailbox, OutgoingMail, MailMessage};
+use mailbox::providers::memory::{MemoryBus, MemoryProvider};
+use mailbox::providers::tcp::{TcpProvider, TcpServer};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let role = args.get(1).map(String::as_str).unwrap_or("server");
+    let endpoint = args.get(2).cloned().unwrap_or_else(|| "127.0.0.1:7878".to_string());
+
+    match role {
+        "server" => run_server(&endpoint).await,
+        "client" => run_client(&endpoint).await,
+        other => Err(format!("unknown role '{}', expected 'server' or 'client'", other).into()),
+    }
+}
+
+async fn run_server(endpoint: &str) -> Result<(), Box<dyn std::error::Error>> {
+    // The bus is served over TCP and also used directly by local `mem:` code.
+    let bus = Arc::new(MemoryBus::new());
+    let _server = TcpServer::bind(endpoint, bus.clone()).await?;
+
+    let mut mailbox = Mailbox::new();
+    mailbox.register_provider(Box::new(MemoryProvider::with_bus(bus)));
+    mailbox.register_provider(Box::new(TcpProvider::new()));
+
+    let service_addr = "mem:service/calculator";
+    let mailbox_clone = mailbox.clone();
+
+    println!("Serving {} as tcp://{}/service/calculator", service_addr, endpoint);
+    let _sub = mailbox.subscribe(service_addr.parse()?, Box::new(move |msg: MailMessage| {
+        let mailbox = mailbox_clone.clone();
+        Box::pin(async move {
+            println!("[Service] Received request: {:?}", msg.body);
+
+            let op = msg.body["op"].as_str().unwrap_or("");
+            let args = msg.body["args"].as_array().cloned().unwrap_or_default();
+            let a = args.first().and_then(|v| v.as_i64()).unwrap_or(0);
+            let b = args.get(1).and_then(|v| v.as_i64()).unwrap_or(0);
+
+            let result = match op {
+                "add" => a + b,
+                "sub" => a - b,
+                _ => 0,
+            };
+
+            println!("[Service] Computed result: {}", result);
+
+            // Reply to sender, wherever it lives
+            let reply = OutgoingMail {
+                id: None,
+                from: msg.to.clone(),
+                to: msg.from.clone(),
+                body: json!({ "result": result }),
+                headers: HashMap::new(),
+                meta: HashMap::new(),
+            };
+
+            if let Err(e) = mailbox.post(reply).await {
+                eprintln!("[Service] Failed to send reply: {}", e);
+            }
+        })
+    })).await?;
+
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+async fn run_client(endpoint: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut mailbox = Mailbox::new();
+    mailbox.register_provider(Box::new(TcpProvider::new()));
+
+    // Our inbox lives on the server's bus; we subscribe to it remotely.
+    let client_addr = format!("tcp://{}/client/user1", endpoint);
+    let service_addr = format!("tcp://{}/service/calculator", endpoint);
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let tx = Arc::new(std::sync::Mutex::new(Some(tx)));
+
+    println!("Starting client at {}", client_addr);
+    let _sub = mailbox.subscribe(client_addr.parse()?, Box::new(move |msg: MailMessage| {
+        let tx = tx.clone();
+        Box::pin(async move {
+            println!("[Client] Received reply: {:?}", msg.body);
+            if let Some(tx) = tx.lock().unwrap().take() {
+                let _ = tx.send(msg);
+            }
+        })
+    })).await?;
+
+    println!("[Client] Sending request: 10 + 20");
+    mailbox.post(OutgoingMail {
+        id: None,
+        from: client_addr.parse()?,
+        to: service_addr.parse()?,
+        body: json!({ "op": "add", "args": [10, 20] }),
+        headers: HashMap::new(),
+        meta: HashMap::new(),
+    }).await?;
+
+    let reply = tokio::time::timeout(Duration::from_secs(2), rx).await??;
+    println!("[Client] Got result: {}", reply.body["result"]);
+
+    Ok(())
+}
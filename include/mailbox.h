@@ -0,0 +1,203 @@
+#ifndef MAILBOX_H
+#define MAILBOX_H
+
+/* Generated by cbindgen from src/ffi.rs; do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Result of every `mailbox_*` call.
+ */
+typedef enum MailboxErrorCode {
+  MAILBOX_ERROR_CODE_OK = 0,
+  /**
+   * A required pointer was NULL or a string was not valid UTF-8.
+   */
+  MAILBOX_ERROR_CODE_INVALID_ARGUMENT,
+  MAILBOX_ERROR_CODE_INVALID_ADDRESS,
+  MAILBOX_ERROR_CODE_PROVIDER_NOT_FOUND,
+  MAILBOX_ERROR_CODE_PROVIDER,
+  MAILBOX_ERROR_CODE_SERIALIZATION,
+  MAILBOX_ERROR_CODE_IO,
+  MAILBOX_ERROR_CODE_TIMEOUT,
+  /**
+   * A bounded mailbox rejected the message.
+   */
+  MAILBOX_ERROR_CODE_MAILBOX_FULL,
+  MAILBOX_ERROR_CODE_UNKNOWN,
+} MailboxErrorCode;
+
+/**
+ * A fetched message, settled by `mailbox_ack` / `mailbox_nack` or released
+ * unsettled by `mailbox_ackable_free`.
+ */
+typedef struct MailboxAckable MailboxAckable;
+
+/**
+ * A `Mailbox` plus the runtime its calls are driven on.
+ */
+typedef struct Mailbox Mailbox;
+
+/**
+ * A live subscription, ended by `mailbox_unsubscribe`.
+ */
+typedef struct MailboxSubscription MailboxSubscription;
+
+/**
+ * Called with the JSON of every message pushed to a subscription, on a
+ * worker thread that may call back into this library. The string is only
+ * valid for the duration of the call.
+ */
+typedef void (*MailboxCallback)(void *user_data, const char *message_json);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Creates a mailbox with no providers; returns NULL on failure.
+ */
+struct Mailbox *mailbox_new(void);
+
+/**
+ * Releases a mailbox created by `mailbox_new`. Subscriptions and fetched
+ * messages must be released first.
+ *
+ * # Safety
+ * `handle` must be NULL or a pointer returned by `mailbox_new`.
+ */
+void mailbox_free(struct Mailbox *handle);
+
+/**
+ * Describes the last error on this thread, or NULL. Valid until the next
+ * failing call on the same thread.
+ */
+const char *mailbox_last_error(void);
+
+/**
+ * Releases a string returned by this library.
+ *
+ * # Safety
+ * `s` must be NULL or a string returned through an out-parameter.
+ */
+void mailbox_string_free(char *s);
+
+/**
+ * Registers a `MemoryProvider` for `mem:` on the process-wide bus `name`,
+ * shared with every Rust provider created by `MemoryProvider::shared(name)`;
+ * a NULL `name` gives the mailbox a private bus.
+ *
+ * # Safety
+ * `handle` must come from `mailbox_new`; `name` must be NULL or a
+ * NUL-terminated string.
+ */
+enum MailboxErrorCode mailbox_register_memory(struct Mailbox *handle, const char *name);
+
+/**
+ * Posts the `OutgoingMail` in `mail_json`. When `out_message` is not NULL
+ * it receives the JSON of the message as sent.
+ *
+ * # Safety
+ * `handle` must come from `mailbox_new`; `mail_json` must be a
+ * NUL-terminated string; `out_message` must be NULL or writable.
+ */
+enum MailboxErrorCode mailbox_post(const struct Mailbox *handle,
+                                   const char *mail_json,
+                                   char **out_message);
+
+/**
+ * Calls `callback` with every message pushed to `address` until the
+ * subscription written to `out_subscription` is passed to
+ * `mailbox_unsubscribe`.
+ *
+ * # Safety
+ * `handle` must come from `mailbox_new`; `address` must be a NUL-terminated
+ * string; `out_subscription` must be writable; `user_data` must stay valid
+ * and usable from other threads until `mailbox_unsubscribe` returns.
+ */
+enum MailboxErrorCode mailbox_subscribe(const struct Mailbox *handle,
+                                        const char *address,
+                                        MailboxCallback callback,
+                                        void *user_data,
+                                        struct MailboxSubscription **out_subscription);
+
+/**
+ * Ends and releases a subscription. Callbacks already running are waited
+ * for, so once this returns the callback is not called again and
+ * `user_data` may be freed.
+ *
+ * # Safety
+ * `subscription` must come from `mailbox_subscribe` and not be used again.
+ * It must not be called from the subscription's own callback, which would
+ * wait for itself.
+ */
+enum MailboxErrorCode mailbox_unsubscribe(struct MailboxSubscription *subscription);
+
+/**
+ * Takes the next message queued at `address`. `*out_message` is set to
+ * NULL when the mailbox is empty. An `ack_timeout_ms` of 0 means no timeout.
+ *
+ * # Safety
+ * `handle` must come from `mailbox_new`; `address` must be a NUL-terminated
+ * string; `out_message` must be writable.
+ */
+enum MailboxErrorCode mailbox_fetch(const struct Mailbox *handle,
+                                    const char *address,
+                                    bool manual_ack,
+                                    uint64_t ack_timeout_ms,
+                                    struct MailboxAckable **out_message);
+
+/**
+ * The JSON of a fetched message, valid until it is acked, nacked or freed.
+ *
+ * # Safety
+ * `message` must come from `mailbox_fetch`.
+ */
+const char *mailbox_ackable_json(const struct MailboxAckable *message);
+
+/**
+ * Acks and releases a fetched message.
+ *
+ * # Safety
+ * `message` must come from `mailbox_fetch` and not be used again.
+ */
+enum MailboxErrorCode mailbox_ack(struct MailboxAckable *message);
+
+/**
+ * Nacks and releases a fetched message, returning it to the queue when
+ * `requeue` is true.
+ *
+ * # Safety
+ * `message` must come from `mailbox_fetch` and not be used again.
+ */
+enum MailboxErrorCode mailbox_nack(struct MailboxAckable *message, bool requeue);
+
+/**
+ * Releases a fetched message without settling it; a manual-ack message is
+ * then redelivered once its ack timeout passes.
+ *
+ * # Safety
+ * `message` must be NULL or come from `mailbox_fetch`, and not be used again.
+ */
+void mailbox_ackable_free(struct MailboxAckable *message);
+
+/**
+ * Writes the JSON `MailboxStatus` of `address` to `out_status`.
+ *
+ * # Safety
+ * `handle` must come from `mailbox_new`; `address` must be a NUL-terminated
+ * string; `out_status` must be writable.
+ */
+enum MailboxErrorCode mailbox_status(const struct Mailbox *handle,
+                                     const char *address,
+                                     char **out_status);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* MAILBOX_H */
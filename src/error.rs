@@ -17,6 +17,15 @@ pub enum MailboxError {
     #[error("URL parse error: {0}")]
     UrlParseError(#[from] url::ParseError),
 
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("Timed out after {0:?}")]
+    Timeout(std::time::Duration),
+
+    #[error("Mailbox full: {0}")]
+    MailboxFull(String),
+
     #[error("Unknown error: {0}")]
     Unknown(String),
 }
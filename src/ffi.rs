@@ -0,0 +1,581 @@
+//! C ABI over `Mailbox` (feature `ffi`, native only).
+//!
+//! Messages cross the boundary as NUL-terminated UTF-8 JSON in the shape of
+//! `OutgoingMail` / `MailMessage` / `MailboxStatus`. Every function returns a
+//! `MailboxErrorCode`; on failure `mailbox_last_error` describes the error
+//! raised on the calling thread. Strings returned through out-parameters are
+//! owned by the caller and released with `mailbox_string_free`. The header
+//! `include/mailbox.h` is generated from this file by cbindgen (see build.rs).
+
+use url::Url;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::ptr;
+use std::sync::{Arc, Condvar, Mutex};
+use tokio::runtime::Runtime;
+
+use crate::error::{MailboxError, Result};
+use crate::mailbox::Mailbox;
+use crate::message::{MailMessage, OutgoingMail, FetchOptions};
+use crate::provider::{Subscription, SubscriptionGuard, AckableMessage};
+use crate::providers::memory::MemoryProvider;
+
+/// Result of every `mailbox_*` call.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MailboxErrorCode {
+    Ok = 0,
+    /// A required pointer was NULL or a string was not valid UTF-8.
+    InvalidArgument,
+    InvalidAddress,
+    ProviderNotFound,
+    Provider,
+    Serialization,
+    Io,
+    Timeout,
+    /// A bounded mailbox rejected the message.
+    MailboxFull,
+    Unknown,
+}
+
+impl From<&MailboxError> for MailboxErrorCode {
+    fn from(e: &MailboxError) -> Self {
+        match e {
+            MailboxError::InvalidAddress(_) | MailboxError::UrlParseError(_) => MailboxErrorCode::InvalidAddress,
+            MailboxError::ProviderNotFound(_) => MailboxErrorCode::ProviderNotFound,
+            MailboxError::ProviderError(_) => MailboxErrorCode::Provider,
+            MailboxError::SerializationError(_) => MailboxErrorCode::Serialization,
+            MailboxError::IoError(_) => MailboxErrorCode::Io,
+            MailboxError::Timeout(_) => MailboxErrorCode::Timeout,
+            MailboxError::MailboxFull(_) => MailboxErrorCode::MailboxFull,
+            MailboxError::Unknown(_) => MailboxErrorCode::Unknown,
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Records `result`'s error for `mailbox_last_error` and maps it to a code.
+fn code<T>(result: Result<T>) -> MailboxErrorCode {
+    match result {
+        Ok(_) => MailboxErrorCode::Ok,
+        Err(e) => {
+            set_last_error(e.to_string());
+            MailboxErrorCode::from(&e)
+        }
+    }
+}
+
+fn invalid_argument(what: &str) -> MailboxErrorCode {
+    set_last_error(format!("invalid argument: {}", what));
+    MailboxErrorCode::InvalidArgument
+}
+
+unsafe fn read_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+fn to_c_string(json: String) -> *mut c_char {
+    // serde_json escapes NUL inside strings, so the output never contains one.
+    CString::new(json).map(CString::into_raw).unwrap_or(ptr::null_mut())
+}
+
+/// A `Mailbox` plus the runtime its calls are driven on.
+pub struct MailboxHandle {
+    mailbox: Mailbox,
+    runtime: Runtime,
+}
+
+/// A live subscription, ended by `mailbox_unsubscribe`.
+pub struct MailboxSubscription {
+    inner: SubscriptionGuard,
+    callbacks: Arc<Callbacks>,
+    runtime: tokio::runtime::Handle,
+}
+
+/// Tracks the callbacks of one subscription that are running, so that
+/// `mailbox_unsubscribe` can wait for them before the host frees `user_data`.
+#[derive(Default)]
+struct Callbacks {
+    /// Whether the subscription has ended, and how many callbacks are running.
+    state: Mutex<(bool, usize)>,
+    idle: Condvar,
+}
+
+impl Callbacks {
+    /// Registers a callback about to run, or `None` once the subscription has
+    /// ended.
+    fn enter(self: &Arc<Self>) -> Option<CallbackGuard> {
+        let mut state = self.state.lock().unwrap();
+        if state.0 {
+            return None;
+        }
+        state.1 += 1;
+        Some(CallbackGuard(self.clone()))
+    }
+
+    /// Stops new callbacks and waits for the running ones to return.
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.0 = true;
+        while state.1 > 0 {
+            state = self.idle.wait(state).unwrap();
+        }
+    }
+}
+
+/// Held for the duration of one callback.
+struct CallbackGuard(Arc<Callbacks>);
+
+impl Drop for CallbackGuard {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.1 -= 1;
+        if state.1 == 0 {
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+/// A fetched message, settled by `mailbox_ack` / `mailbox_nack` or released
+/// unsettled by `mailbox_ackable_free`.
+pub struct MailboxAckable {
+    inner: AckableMessage,
+    json: CString,
+    runtime: tokio::runtime::Handle,
+}
+
+/// Called with the JSON of every message pushed to a subscription, on a
+/// worker thread that may call back into this library. The string is only
+/// valid for the duration of the call.
+pub type MailboxCallback = extern "C" fn(user_data: *mut c_void, message_json: *const c_char);
+
+/// Host data handed back to a callback; calls may come from any thread.
+struct UserData(*mut c_void);
+
+impl UserData {
+    fn get(&self) -> *mut c_void {
+        self.0
+    }
+}
+
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+/// Wraps an existing `Mailbox` so that a Rust host can hand it to C code.
+pub fn mailbox_from(mailbox: Mailbox) -> Result<*mut MailboxHandle> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()?;
+    Ok(Box::into_raw(Box::new(MailboxHandle { mailbox, runtime })))
+}
+
+/// Creates a mailbox with no providers; returns NULL on failure.
+#[no_mangle]
+pub extern "C" fn mailbox_new() -> *mut MailboxHandle {
+    match mailbox_from(Mailbox::new()) {
+        Ok(handle) => handle,
+        Err(e) => {
+            set_last_error(e.to_string());
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Releases a mailbox created by `mailbox_new`. Subscriptions and fetched
+/// messages must be released first.
+///
+/// # Safety
+/// `handle` must be NULL or a pointer returned by `mailbox_new`.
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_free(handle: *mut MailboxHandle) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// Describes the last error on this thread, or NULL. Valid until the next
+/// failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn mailbox_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Releases a string returned by this library.
+///
+/// # Safety
+/// `s` must be NULL or a string returned through an out-parameter.
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Registers a `MemoryProvider` for `mem:` on the process-wide bus `name`,
+/// shared with every Rust provider created by `MemoryProvider::shared(name)`;
+/// a NULL `name` gives the mailbox a private bus.
+///
+/// # Safety
+/// `handle` must come from `mailbox_new`; `name` must be NULL or a
+/// NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_register_memory(handle: *mut MailboxHandle, name: *const c_char) -> MailboxErrorCode {
+    let Some(handle) = handle.as_mut() else { return invalid_argument("handle") };
+    let provider = if name.is_null() {
+        MemoryProvider::new()
+    } else {
+        let Some(name) = read_str(name) else { return invalid_argument("name") };
+        MemoryProvider::shared(name)
+    };
+    handle.mailbox.register_provider(Box::new(provider));
+    MailboxErrorCode::Ok
+}
+
+/// Posts the `OutgoingMail` in `mail_json`. When `out_message` is not NULL
+/// it receives the JSON of the message as sent.
+///
+/// # Safety
+/// `handle` must come from `mailbox_new`; `mail_json` must be a
+/// NUL-terminated string; `out_message` must be NULL or writable.
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_post(
+    handle: *const MailboxHandle,
+    mail_json: *const c_char,
+    out_message: *mut *mut c_char,
+) -> MailboxErrorCode {
+    let Some(handle) = handle.as_ref() else { return invalid_argument("handle") };
+    let Some(mail_json) = read_str(mail_json) else { return invalid_argument("mail_json") };
+
+    code(handle.runtime.block_on(async {
+        let mail: OutgoingMail = serde_json::from_str(mail_json)?;
+        let message = handle.mailbox.post(mail).await?;
+        if !out_message.is_null() {
+            *out_message = to_c_string(serde_json::to_string(&message)?);
+        }
+        Ok(())
+    }))
+}
+
+/// Calls `callback` with every message pushed to `address` until the
+/// subscription written to `out_subscription` is passed to
+/// `mailbox_unsubscribe`.
+///
+/// # Safety
+/// `handle` must come from `mailbox_new`; `address` must be a NUL-terminated
+/// string; `out_subscription` must be writable; `user_data` must stay valid
+/// and usable from other threads until `mailbox_unsubscribe` returns.
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_subscribe(
+    handle: *const MailboxHandle,
+    address: *const c_char,
+    callback: MailboxCallback,
+    user_data: *mut c_void,
+    out_subscription: *mut *mut MailboxSubscription,
+) -> MailboxErrorCode {
+    let Some(handle) = handle.as_ref() else { return invalid_argument("handle") };
+    let Some(address) = read_str(address) else { return invalid_argument("address") };
+    if out_subscription.is_null() {
+        return invalid_argument("out_subscription");
+    }
+
+    let user_data = UserData(user_data);
+    let callbacks = Arc::new(Callbacks::default());
+    code(handle.runtime.block_on(async {
+        let address: Url = address.parse()?;
+        let user_data = Arc::new(user_data);
+        let running = callbacks.clone();
+        let inner = handle.mailbox.subscribe(address, Box::new(move |message: MailMessage| {
+            let user_data = user_data.clone();
+            let running = running.clone();
+            Box::pin(async move {
+                let Some(json) = serde_json::to_string(&message).ok().and_then(|json| CString::new(json).ok()) else {
+                    return;
+                };
+                let Some(guard) = running.enter() else { return };
+                // Blocking threads may call back into the library.
+                let _ = tokio::task::spawn_blocking(move || {
+                    let _guard = guard;
+                    callback(user_data.get(), json.as_ptr());
+                }).await;
+            })
+        })).await?;
+
+        *out_subscription = Box::into_raw(Box::new(MailboxSubscription {
+            inner,
+            callbacks,
+            runtime: handle.runtime.handle().clone(),
+        }));
+        Ok(())
+    }))
+}
+
+/// Ends and releases a subscription. Callbacks already running are waited
+/// for, so once this returns the callback is not called again and
+/// `user_data` may be freed.
+///
+/// # Safety
+/// `subscription` must come from `mailbox_subscribe` and not be used again.
+/// It must not be called from the subscription's own callback, which would
+/// wait for itself.
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_unsubscribe(subscription: *mut MailboxSubscription) -> MailboxErrorCode {
+    if subscription.is_null() {
+        return invalid_argument("subscription");
+    }
+    let mut subscription = Box::from_raw(subscription);
+    let runtime = subscription.runtime.clone();
+    let result = runtime.block_on(subscription.inner.unsubscribe());
+    subscription.callbacks.close();
+    code(result)
+}
+
+/// Takes the next message queued at `address`. `*out_message` is set to
+/// NULL when the mailbox is empty. An `ack_timeout_ms` of 0 means no timeout.
+///
+/// # Safety
+/// `handle` must come from `mailbox_new`; `address` must be a NUL-terminated
+/// string; `out_message` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_fetch(
+    handle: *const MailboxHandle,
+    address: *const c_char,
+    manual_ack: bool,
+    ack_timeout_ms: u64,
+    out_message: *mut *mut MailboxAckable,
+) -> MailboxErrorCode {
+    let Some(handle) = handle.as_ref() else { return invalid_argument("handle") };
+    let Some(address) = read_str(address) else { return invalid_argument("address") };
+    if out_message.is_null() {
+        return invalid_argument("out_message");
+    }
+    *out_message = ptr::null_mut();
+
+    let options = FetchOptions {
+        manual_ack,
+        ack_timeout: (ack_timeout_ms > 0).then_some(ack_timeout_ms),
+        ..Default::default()
+    };
+    code(handle.runtime.block_on(async {
+        let Some(inner) = handle.mailbox.fetch(address.parse()?, options).await? else {
+            return Ok(());
+        };
+        let json = CString::new(serde_json::to_string(&inner.message)?)
+            .map_err(|e| MailboxError::Unknown(e.to_string()))?;
+        *out_message = Box::into_raw(Box::new(MailboxAckable {
+            inner,
+            json,
+            runtime: handle.runtime.handle().clone(),
+        }));
+        Ok(())
+    }))
+}
+
+/// The JSON of a fetched message, valid until it is acked, nacked or freed.
+///
+/// # Safety
+/// `message` must come from `mailbox_fetch`.
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_ackable_json(message: *const MailboxAckable) -> *const c_char {
+    message.as_ref().map_or(ptr::null(), |message| message.json.as_ptr())
+}
+
+/// Acks and releases a fetched message.
+///
+/// # Safety
+/// `message` must come from `mailbox_fetch` and not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_ack(message: *mut MailboxAckable) -> MailboxErrorCode {
+    if message.is_null() {
+        return invalid_argument("message");
+    }
+    let message = Box::from_raw(message);
+    let runtime = message.runtime.clone();
+    code(runtime.block_on(message.inner.ack()))
+}
+
+/// Nacks and releases a fetched message, returning it to the queue when
+/// `requeue` is true.
+///
+/// # Safety
+/// `message` must come from `mailbox_fetch` and not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_nack(message: *mut MailboxAckable, requeue: bool) -> MailboxErrorCode {
+    if message.is_null() {
+        return invalid_argument("message");
+    }
+    let message = Box::from_raw(message);
+    let runtime = message.runtime.clone();
+    code(runtime.block_on(message.inner.nack(requeue)))
+}
+
+/// Releases a fetched message without settling it; a manual-ack message is
+/// then redelivered once its ack timeout passes.
+///
+/// # Safety
+/// `message` must be NULL or come from `mailbox_fetch`, and not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_ackable_free(message: *mut MailboxAckable) {
+    if !message.is_null() {
+        drop(Box::from_raw(message));
+    }
+}
+
+/// Writes the JSON `MailboxStatus` of `address` to `out_status`.
+///
+/// # Safety
+/// `handle` must come from `mailbox_new`; `address` must be a NUL-terminated
+/// string; `out_status` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_status(
+    handle: *const MailboxHandle,
+    address: *const c_char,
+    out_status: *mut *mut c_char,
+) -> MailboxErrorCode {
+    let Some(handle) = handle.as_ref() else { return invalid_argument("handle") };
+    let Some(address) = read_str(address) else { return invalid_argument("address") };
+    if out_status.is_null() {
+        return invalid_argument("out_status");
+    }
+
+    code(handle.runtime.block_on(async {
+        let status = handle.mailbox.status(address.parse()?).await?;
+        *out_status = to_c_string(serde_json::to_string(&status)?);
+        Ok(())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::test_mail;
+    use std::sync::mpsc;
+
+    fn c(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    unsafe fn take_string(s: *mut c_char) -> String {
+        let owned = CStr::from_ptr(s).to_str().unwrap().to_string();
+        mailbox_string_free(s);
+        owned
+    }
+
+    #[test]
+    fn test_post_fetch_and_ack() {
+        unsafe {
+            let handle = mailbox_new();
+            assert_eq!(mailbox_register_memory(handle, ptr::null()), MailboxErrorCode::Ok);
+
+            let mail = c(r#"{"from": "mem:ffi/sender", "to": "mem:ffi/inbox", "body": "content"}"#);
+            let mut sent = ptr::null_mut();
+            assert_eq!(mailbox_post(handle, mail.as_ptr(), &mut sent), MailboxErrorCode::Ok);
+            let sent: MailMessage = serde_json::from_str(&take_string(sent)).unwrap();
+            assert!(!sent.id.is_empty());
+
+            let address = c("mem:ffi/inbox");
+            let mut fetched = ptr::null_mut();
+            assert_eq!(mailbox_fetch(handle, address.as_ptr(), true, 0, &mut fetched), MailboxErrorCode::Ok);
+            let json = CStr::from_ptr(mailbox_ackable_json(fetched)).to_str().unwrap();
+            assert_eq!(serde_json::from_str::<MailMessage>(json).unwrap().id, sent.id);
+            assert_eq!(mailbox_nack(fetched, true), MailboxErrorCode::Ok);
+
+            assert_eq!(mailbox_fetch(handle, address.as_ptr(), true, 0, &mut fetched), MailboxErrorCode::Ok);
+            assert!(!fetched.is_null());
+            assert_eq!(mailbox_ack(fetched), MailboxErrorCode::Ok);
+            assert_eq!(mailbox_fetch(handle, address.as_ptr(), false, 0, &mut fetched), MailboxErrorCode::Ok);
+            assert!(fetched.is_null());
+
+            let mut status = ptr::null_mut();
+            assert_eq!(mailbox_status(handle, address.as_ptr(), &mut status), MailboxErrorCode::Ok);
+            assert!(take_string(status).contains(r#""unread_count":0"#));
+
+            let unknown = c("redis:ffi/inbox");
+            assert_eq!(mailbox_status(handle, unknown.as_ptr(), &mut status), MailboxErrorCode::ProviderNotFound);
+            assert!(CStr::from_ptr(mailbox_last_error()).to_str().unwrap().contains("redis"));
+
+            mailbox_free(handle);
+        }
+    }
+
+    extern "C" fn forward(user_data: *mut c_void, message_json: *const c_char) {
+        let tx = unsafe { &*(user_data as *const mpsc::Sender<String>) };
+        let json = unsafe { CStr::from_ptr(message_json) }.to_str().unwrap().to_string();
+        let _ = tx.send(json);
+    }
+
+    #[test]
+    fn test_subscribe_with_callback() {
+        unsafe {
+            let handle = mailbox_new();
+            let bus = c("ffi-test-subscribe");
+            mailbox_register_memory(handle, bus.as_ptr());
+
+            let (tx, rx) = mpsc::channel::<String>();
+            let tx = Box::into_raw(Box::new(tx));
+            let address = c("mem:ffi/events");
+            let mut subscription = ptr::null_mut();
+            assert_eq!(
+                mailbox_subscribe(handle, address.as_ptr(), forward, tx as *mut c_void, &mut subscription),
+                MailboxErrorCode::Ok,
+            );
+
+            // A Rust provider on the same named bus reaches the C subscriber.
+            let mut rust_side = Mailbox::new();
+            rust_side.register_provider(Box::new(MemoryProvider::shared("ffi-test-subscribe")));
+            let mail = test_mail("msg1", &"mem:ffi/events".parse().unwrap());
+            (*handle).runtime.block_on(rust_side.post(mail)).unwrap();
+
+            let json = rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+            assert_eq!(serde_json::from_str::<MailMessage>(&json).unwrap().id, "msg1");
+
+            assert_eq!(mailbox_unsubscribe(subscription), MailboxErrorCode::Ok);
+            mailbox_free(handle);
+            drop(Box::from_raw(tx));
+        }
+    }
+
+    extern "C" fn slow_count(user_data: *mut c_void, _message_json: *const c_char) {
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        let count = unsafe { &*(user_data as *const std::sync::atomic::AtomicUsize) };
+        count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_unsubscribe_waits_for_running_callbacks() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        unsafe {
+            let handle = mailbox_new();
+            mailbox_register_memory(handle, ptr::null());
+
+            let count = Box::into_raw(Box::new(AtomicUsize::new(0)));
+            let address = c("mem:ffi/slow");
+            let mut subscription = ptr::null_mut();
+            assert_eq!(
+                mailbox_subscribe(handle, address.as_ptr(), slow_count, count as *mut c_void, &mut subscription),
+                MailboxErrorCode::Ok,
+            );
+
+            let mail = c(r#"{"from": "mem:ffi/sender", "to": "mem:ffi/slow", "body": "content"}"#);
+            assert_eq!(mailbox_post(handle, mail.as_ptr(), ptr::null_mut()), MailboxErrorCode::Ok);
+            std::thread::sleep(std::time::Duration::from_millis(50));
+
+            assert_eq!(mailbox_unsubscribe(subscription), MailboxErrorCode::Ok);
+            assert_eq!((*count).load(Ordering::SeqCst), 1);
+            drop(Box::from_raw(count));
+            mailbox_free(handle);
+        }
+    }
+}
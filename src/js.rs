@@ -0,0 +1,433 @@
+//! JavaScript bindings for `Mailbox` (wasm32, feature `js`).
+//!
+//! Messages, statuses and options cross the boundary as plain JS objects
+//! shaped like their serde representation; see the TypeScript definitions
+//! below.
+
+use url::Url;
+use std::cell::RefCell;
+use std::rc::Rc;
+use futures::future::FutureExt;
+use js_sys::{Function, Promise, Reflect, JSON};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::future_to_promise;
+
+use crate::error::{MailboxError, Result};
+use crate::mailbox::Mailbox;
+use crate::message::{MailMessage, OutgoingMail, FetchOptions};
+use crate::provider::{Subscription, SubscriptionGuard, AckableMessage};
+use crate::providers::memory::MemoryProvider;
+
+#[wasm_bindgen(typescript_custom_section)]
+const TS_DEFINITIONS: &str = r#"
+export interface OutgoingMail {
+    id?: string;
+    from: string;
+    to: string;
+    body: any;
+    headers?: Record<string, string>;
+    meta?: Record<string, any>;
+}
+
+export interface MailMessage {
+    id: string;
+    from: string;
+    to: string;
+    body: any;
+    headers: Record<string, string>;
+    meta: Record<string, any>;
+}
+
+export interface MailboxStatus {
+    state: string;
+    unread_count?: number;
+    last_activity_time?: string;
+    [extra: string]: any;
+}
+
+export interface FetchOptions {
+    manualAck?: boolean;
+    ackTimeout?: number;
+    maxDeliveries?: number;
+    deadLetterAddress?: string;
+}
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "OutgoingMail")]
+    pub type JsOutgoingMail;
+
+    #[wasm_bindgen(typescript_type = "FetchOptions")]
+    pub type JsFetchOptions;
+
+    #[wasm_bindgen(typescript_type = "(message: MailMessage) => void")]
+    pub type JsListener;
+
+    #[wasm_bindgen(typescript_type = "Promise<MailMessage>")]
+    pub type PromiseMailMessage;
+
+    #[wasm_bindgen(typescript_type = "Promise<JsSubscription>")]
+    pub type PromiseSubscription;
+
+    #[wasm_bindgen(typescript_type = "Promise<JsAckableMessage | null>")]
+    pub type PromiseAckableMessage;
+
+    #[wasm_bindgen(typescript_type = "Promise<MailboxStatus>")]
+    pub type PromiseStatus;
+
+    #[wasm_bindgen(typescript_type = "Promise<void>")]
+    pub type PromiseVoid;
+}
+
+fn to_js_error(e: MailboxError) -> JsValue {
+    js_sys::Error::new(&e.to_string()).into()
+}
+
+fn js_error(e: JsValue) -> MailboxError {
+    MailboxError::ProviderError(format!("js: {:?}", e))
+}
+
+fn from_js<T: DeserializeOwned>(value: &JsValue) -> Result<T> {
+    let text = JSON::stringify(value).map_err(js_error)?;
+    Ok(serde_json::from_str(&String::from(text))?)
+}
+
+fn to_js<T: Serialize>(value: &T) -> Result<JsValue> {
+    JSON::parse(&serde_json::to_string(value)?).map_err(js_error)
+}
+
+fn invalid_option(name: &str, expected: &str) -> MailboxError {
+    MailboxError::ProviderError(format!("js: {} must be {}", name, expected))
+}
+
+/// Reads a whole number in `0..=max` from an options field, if set.
+fn whole_number(value: JsValue, name: &str, max: f64) -> Result<Option<f64>> {
+    if value.is_undefined() || value.is_null() {
+        return Ok(None);
+    }
+    match value.as_f64() {
+        Some(n) if n.fract() == 0.0 && (0.0..=max).contains(&n) => Ok(Some(n)),
+        _ => Err(invalid_option(name, &format!("a whole number from 0 to {}", max))),
+    }
+}
+
+/// Reads a `FetchOptions` object, rejecting fields that are set but invalid.
+fn fetch_options(value: &JsValue) -> Result<FetchOptions> {
+    if value.is_undefined() || value.is_null() {
+        return Ok(FetchOptions::default());
+    }
+    let field = |name: &str| Reflect::get(value, &name.into()).map_err(js_error);
+
+    let manual_ack = field("manualAck")?;
+    let manual_ack = if manual_ack.is_undefined() || manual_ack.is_null() {
+        false
+    } else {
+        manual_ack.as_bool().ok_or_else(|| invalid_option("manualAck", "a boolean"))?
+    };
+    let dead_letter_address = field("deadLetterAddress")?;
+    let dead_letter_address = if dead_letter_address.is_undefined() || dead_letter_address.is_null() {
+        None
+    } else {
+        let address = dead_letter_address.as_string()
+            .ok_or_else(|| invalid_option("deadLetterAddress", "a string"))?;
+        Some(address.parse().map_err(|_| MailboxError::InvalidAddress(address))?)
+    };
+
+    Ok(FetchOptions {
+        manual_ack,
+        // Numbers above 2^53 are not exact in JS, so that is the cap.
+        ack_timeout: whole_number(field("ackTimeout")?, "ackTimeout", 9007199254740991.0)?.map(|ms| ms as u64),
+        max_deliveries: whole_number(field("maxDeliveries")?, "maxDeliveries", u32::MAX as f64)?.map(|n| n as u32),
+        dead_letter_address,
+    })
+}
+
+fn promise<T: Into<JsValue>>(future: impl std::future::Future<Output = Result<T>> + 'static) -> Promise {
+    future_to_promise(future.map(|result| result.map(Into::into).map_err(to_js_error)))
+}
+
+/// A `Mailbox` usable from JavaScript.
+///
+/// A new instance has a `MemoryProvider` registered for `mem:`; the
+/// browser providers compiled into the package can be added with the
+/// `use*` methods.
+#[wasm_bindgen]
+pub struct JsMailbox {
+    inner: Rc<RefCell<Mailbox>>,
+}
+
+#[wasm_bindgen]
+impl JsMailbox {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        let mut mailbox = Mailbox::new();
+        mailbox.register_provider(Box::new(MemoryProvider::new()));
+        Self {
+            inner: Rc::new(RefCell::new(mailbox)),
+        }
+    }
+
+    fn mailbox(&self) -> Mailbox {
+        self.inner.borrow().clone()
+    }
+
+    /// Registers a `WsProvider` for `ws:` addresses.
+    #[cfg(feature = "ws")]
+    #[wasm_bindgen(js_name = useWebSocket)]
+    pub fn use_web_socket(&self) {
+        self.inner.borrow_mut().register_provider(Box::new(crate::providers::ws::WsProvider::new()));
+    }
+
+    /// Registers a `BroadcastChannelProvider` for `bc:` addresses on channel `name`.
+    #[cfg(feature = "bc")]
+    #[wasm_bindgen(js_name = useBroadcastChannel)]
+    pub fn use_broadcast_channel(&self, name: &str) -> std::result::Result<(), JsValue> {
+        let provider = crate::providers::bc::BroadcastChannelProvider::new(name).map_err(to_js_error)?;
+        self.inner.borrow_mut().register_provider(Box::new(provider));
+        Ok(())
+    }
+
+    /// Opens the IndexedDB database `name` and registers it for `idb:` addresses.
+    #[cfg(feature = "idb")]
+    #[wasm_bindgen(js_name = useIndexedDb)]
+    pub fn use_indexed_db(&self, name: String) -> PromiseVoid {
+        let inner = self.inner.clone();
+        promise(async move {
+            let provider = crate::providers::idb::IdbProvider::open(&name).await?;
+            inner.borrow_mut().register_provider(Box::new(provider));
+            Ok(JsValue::UNDEFINED)
+        }).unchecked_into()
+    }
+
+    /// Sends `mail` and resolves with the message as delivered.
+    pub fn post(&self, mail: JsOutgoingMail) -> PromiseMailMessage {
+        let mailbox = self.mailbox();
+        let mail = from_js::<OutgoingMail>(&mail);
+        promise(async move {
+            let message = mailbox.post(mail?).await?;
+            to_js(&message)
+        }).unchecked_into()
+    }
+
+    /// Calls `listener` with every message pushed to `address` until the
+    /// returned subscription is unsubscribed.
+    pub fn subscribe(&self, address: String, listener: JsListener) -> PromiseSubscription {
+        let mailbox = self.mailbox();
+        let listener: Function = listener.unchecked_into();
+        promise(async move {
+            let address: Url = address.parse()?;
+
+            // The JS function is not `Send`, so messages are handed to a
+            // local task that calls it; the task ends once the provider
+            // drops the callback.
+            let (tx, mut rx) = mpsc::unbounded_channel::<MailMessage>();
+            wasm_bindgen_futures::spawn_local(async move {
+                while let Some(message) = rx.recv().await {
+                    if let Ok(value) = to_js(&message) {
+                        let _ = listener.call1(&JsValue::NULL, &value);
+                    }
+                }
+            });
+
+            let subscription = mailbox.subscribe(address, Box::new(move |message| {
+                let _ = tx.send(message);
+                Box::pin(async {})
+            })).await?;
+
+            Ok(JsSubscription {
+                inner: Rc::new(RefCell::new(Some(subscription))),
+            })
+        }).unchecked_into()
+    }
+
+    /// Resolves with the next message queued at `address`, or `null`.
+    pub fn fetch(&self, address: String, options: Option<JsFetchOptions>) -> PromiseAckableMessage {
+        let mailbox = self.mailbox();
+        let options = options.map(|o| fetch_options(&o)).unwrap_or_else(|| Ok(FetchOptions::default()));
+        promise(async move {
+            let fetched = mailbox.fetch(address.parse()?, options?).await?;
+            Ok(match fetched {
+                Some(fetched) => JsAckableMessage::new(fetched)?.into(),
+                None => JsValue::NULL,
+            })
+        }).unchecked_into()
+    }
+
+    /// Resolves with the provider's status for `address`.
+    pub fn status(&self, address: String) -> PromiseStatus {
+        let mailbox = self.mailbox();
+        promise(async move {
+            let status = mailbox.status(address.parse()?).await?;
+            to_js(&status)
+        }).unchecked_into()
+    }
+}
+
+impl Default for JsMailbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Handle returned by `JsMailbox.subscribe`.
+#[wasm_bindgen]
+pub struct JsSubscription {
+    inner: Rc<RefCell<Option<SubscriptionGuard>>>,
+}
+
+#[wasm_bindgen]
+impl JsSubscription {
+    /// Stops delivery; calling it again does nothing.
+    pub fn unsubscribe(&self) -> PromiseVoid {
+        let subscription = self.inner.borrow_mut().take();
+        promise(async move {
+            if let Some(mut subscription) = subscription {
+                subscription.unsubscribe().await?;
+            }
+            Ok(JsValue::UNDEFINED)
+        }).unchecked_into()
+    }
+}
+
+/// A fetched message; with `manualAck` it must be settled with `ack()` or
+/// `nack(requeue)`, either of which may be called once.
+#[wasm_bindgen]
+pub struct JsAckableMessage {
+    message: JsValue,
+    inner: Rc<RefCell<Option<AckableMessage>>>,
+}
+
+impl JsAckableMessage {
+    fn new(fetched: AckableMessage) -> Result<Self> {
+        Ok(Self {
+            message: to_js(&fetched.message)?,
+            inner: Rc::new(RefCell::new(Some(fetched))),
+        })
+    }
+
+    /// Acks the message, or nacks it when `requeue` is given.
+    fn settle(&self, requeue: Option<bool>) -> PromiseVoid {
+        let fetched = self.inner.borrow_mut().take();
+        promise(async move {
+            let fetched = fetched
+                .ok_or_else(|| MailboxError::ProviderError("message was already acked or nacked".to_string()))?;
+            match requeue {
+                None => fetched.ack().await?,
+                Some(requeue) => fetched.nack(requeue).await?,
+            }
+            Ok(JsValue::UNDEFINED)
+        }).unchecked_into()
+    }
+}
+
+#[wasm_bindgen]
+impl JsAckableMessage {
+    #[wasm_bindgen(getter, unchecked_return_type = "MailMessage")]
+    pub fn message(&self) -> JsValue {
+        self.message.clone()
+    }
+
+    pub fn ack(&self) -> PromiseVoid {
+        self.settle(None)
+    }
+
+    pub fn nack(&self, requeue: bool) -> PromiseVoid {
+        self.settle(Some(requeue))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_futures::JsFuture;
+    use wasm_bindgen_test::*;
+
+    fn mail(to: &str) -> JsOutgoingMail {
+        JSON::parse(&format!(r#"{{"from": "mem:test/sender", "to": "{}", "body": "content"}}"#, to))
+            .unwrap()
+            .unchecked_into()
+    }
+
+    fn field(value: &JsValue, name: &str) -> JsValue {
+        Reflect::get(value, &name.into()).unwrap()
+    }
+
+    /// Calls `method` the way JS code would, on the exported class.
+    async fn call(value: &JsValue, method: &str, args: &[JsValue]) -> std::result::Result<JsValue, JsValue> {
+        let method: Function = field(value, method).unchecked_into();
+        let promise = method.apply(value, &args.iter().collect())?;
+        JsFuture::from(promise.unchecked_into::<Promise>()).await
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_post_fetch_and_settle() {
+        let mailbox: JsValue = JsMailbox::new().into();
+        let posted = call(&mailbox, "post", &[mail("mem:test/inbox").into()]).await.unwrap();
+        assert!(field(&posted, "id").as_string().is_some_and(|id| !id.is_empty()));
+
+        let options = JSON::parse(r#"{"manualAck": true}"#).unwrap();
+        let fetched = call(&mailbox, "fetch", &["mem:test/inbox".into(), options]).await.unwrap();
+        assert_eq!(field(&field(&fetched, "message"), "body").as_string().as_deref(), Some("content"));
+        call(&fetched, "nack", &[true.into()]).await.unwrap();
+        assert!(call(&fetched, "ack", &[]).await.is_err());
+
+        let status = call(&mailbox, "status", &["mem:test/inbox".into()]).await.unwrap();
+        assert_eq!(field(&status, "unread_count").as_f64(), Some(1.0));
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_invalid_fetch_options_reject() {
+        let mailbox: JsValue = JsMailbox::new().into();
+        call(&mailbox, "post", &[mail("mem:test/options").into()]).await.unwrap();
+
+        for options in [
+            r#"{"ackTimeout": -1}"#,
+            r#"{"ackTimeout": 1.5}"#,
+            r#"{"maxDeliveries": "3"}"#,
+            r#"{"maxDeliveries": 4294967296}"#,
+            r#"{"deadLetterAddress": "not an address"}"#,
+            r#"{"manualAck": "yes"}"#,
+        ] {
+            let options = JSON::parse(options).unwrap();
+            assert!(call(&mailbox, "fetch", &["mem:test/options".into(), options]).await.is_err());
+        }
+        let nan = js_sys::Object::new();
+        Reflect::set(&nan, &"ackTimeout".into(), &f64::NAN.into()).unwrap();
+        assert!(call(&mailbox, "fetch", &["mem:test/options".into(), nan.into()]).await.is_err());
+
+        // Nothing was taken by the rejected fetches.
+        let status = call(&mailbox, "status", &["mem:test/options".into()]).await.unwrap();
+        assert_eq!(field(&status, "unread_count").as_f64(), Some(1.0));
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_subscribe_and_unsubscribe() {
+        let mailbox: JsValue = JsMailbox::new().into();
+        let received = js_sys::Array::new();
+        let listener = {
+            let received = received.clone();
+            Closure::<dyn FnMut(JsValue)>::new(move |message| {
+                received.push(&message);
+            })
+        };
+
+        let subscription = call(&mailbox, "subscribe", &["mem:test/events".into(), listener.as_ref().clone()])
+            .await
+            .unwrap();
+        call(&mailbox, "post", &[mail("mem:test/events").into()]).await.unwrap();
+        call(&subscription, "unsubscribe", &[]).await.unwrap();
+        call(&mailbox, "post", &[mail("mem:test/events").into()]).await.unwrap();
+
+        // Let the delivery tasks run.
+        let tick = Promise::new(&mut |resolve, _| {
+            let set_timeout: Function = field(&js_sys::global(), "setTimeout").unchecked_into();
+            let _ = set_timeout.call2(&JsValue::NULL, &resolve, &10.into());
+        });
+        JsFuture::from(tick).await.unwrap();
+        assert_eq!(received.length(), 1);
+    }
+}
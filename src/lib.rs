@@ -4,8 +4,14 @@ pub mod provider;
 pub mod mailbox;
 pub mod utils;
 pub mod providers;
+#[cfg(all(feature = "js", target_arch = "wasm32"))]
+pub mod js;
+#[cfg(all(feature = "ffi", not(target_arch = "wasm32")))]
+pub mod ffi;
 
 pub use error::MailboxError;
-pub use message::{MailMessage, OutgoingMail, MailboxStatus, FetchOptions};
-pub use provider::{MailboxProvider, Subscription, AckableMessage};
-pub use mailbox::Mailbox;
+pub use message::{MailMessage, OutgoingMail, MailboxStatus, FetchOptions, TypedMessage};
+pub use provider::{MailboxProvider, Subscription, SubscriptionGuard, AckableMessage, TypedAckableMessage};
+pub use mailbox::{Mailbox, MailboxStream};
+#[cfg(all(feature = "js", target_arch = "wasm32"))]
+pub use js::JsMailbox;
@@ -1,23 +1,58 @@
 use std::collections::HashMap;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::Mutex;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
 use url::Url;
+#[cfg(not(target_arch = "wasm32"))]
+use uuid::Uuid;
 use crate::error::{MailboxError, Result};
-use crate::message::{MailMessage, OutgoingMail, MailboxStatus, FetchOptions};
-use crate::provider::{MailboxProvider, Subscription, AckableMessage};
+use crate::message::{MailMessage, OutgoingMail, MailboxStatus, FetchOptions, TypedMessage};
+use crate::provider::{MailboxProvider, SubscriptionGuard, AckableMessage, TypedAckableMessage};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::provider::Subscription;
 use futures::future::BoxFuture;
+use futures::Stream;
+use tokio::sync::mpsc;
+
+/// Header naming the address a reply should be posted to.
+pub const REPLY_TO_HEADER: &str = "reply-to";
+/// Header tying a reply to its request.
+pub const CORRELATION_ID_HEADER: &str = "correlation-id";
+
+/// How many messages a `subscribe_stream` stream buffers before delivery
+/// waits for the consumer.
+const STREAM_CAPACITY: usize = 64;
+
+/// Called with a message whose body could not be decoded by a typed method.
+pub type DecodeErrorHandler = Arc<dyn Fn(&MailMessage, &serde_json::Error) + Send + Sync>;
 
 #[derive(Clone)]
 pub struct Mailbox {
     providers: HashMap<String, Arc<dyn MailboxProvider>>,
+    decode_error_handler: Option<DecodeErrorHandler>,
 }
 
 impl Mailbox {
     pub fn new() -> Self {
         Self {
             providers: HashMap::new(),
+            decode_error_handler: None,
         }
     }
 
+    /// Sets the handler told about messages that `subscribe_typed` and
+    /// `fetch_typed` cannot decode. Without one they are skipped silently.
+    pub fn on_decode_error(&mut self, handler: impl Fn(&MailMessage, &serde_json::Error) + Send + Sync + 'static) {
+        self.decode_error_handler = Some(Arc::new(handler));
+    }
+
     pub fn register_provider(&mut self, provider: Box<dyn MailboxProvider>) {
         self.providers.insert(provider.protocol().to_string(), Arc::from(provider));
     }
@@ -27,11 +62,7 @@ impl Mailbox {
         // In TS, it does `protocol.slice(0, -1)`.
         // Here, let's assume the provider.protocol() returns "mem" (without colon).
         // And the URL protocol is "mem:".
-        let key = if protocol.ends_with(':') {
-            &protocol[..protocol.len() - 1]
-        } else {
-            protocol
-        };
+        let key = protocol.strip_suffix(':').unwrap_or(protocol);
 
         self.providers
             .get(key)
@@ -50,13 +81,15 @@ impl Mailbox {
         provider.send(message).await
     }
 
+    /// Calls `callback` with every message pushed to `address` until the
+    /// returned guard is dropped or unsubscribed.
     pub async fn subscribe(
         &self,
         address: Url,
         callback: Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>,
-    ) -> Result<Box<dyn Subscription>> {
+    ) -> Result<SubscriptionGuard> {
         let provider = self.get_provider(address.scheme())?;
-        provider.subscribe(address, callback).await
+        Ok(SubscriptionGuard::new(provider.subscribe(address, callback).await?))
     }
 
     pub async fn fetch(&self, address: Url, options: FetchOptions) -> Result<Option<AckableMessage>> {
@@ -68,6 +101,143 @@ impl Mailbox {
         let provider = self.get_provider(address.scheme())?;
         provider.status(address).await
     }
+
+    /// Like `subscribe`, but yields messages as a `Stream`. Up to 64
+    /// messages are buffered; beyond that deliveries wait for the stream to
+    /// be polled. Dropping the stream unsubscribes.
+    pub async fn subscribe_stream(&self, address: Url) -> Result<MailboxStream> {
+        let (tx, rx) = mpsc::channel(STREAM_CAPACITY);
+        let subscription = self.subscribe(address, Box::new(move |msg: MailMessage| {
+            let tx = tx.clone();
+            Box::pin(async move {
+                let _ = tx.send(msg).await;
+            })
+        })).await?;
+
+        Ok(MailboxStream {
+            rx,
+            _subscription: subscription,
+        })
+    }
+
+    /// Posts `mail` with its body serialized to JSON.
+    pub async fn post_typed<T: Serialize>(&self, mail: TypedMessage<T>) -> Result<MailMessage> {
+        self.post(mail.into_outgoing()?).await
+    }
+
+    /// Like `subscribe`, with bodies decoded into `T`. Messages that do not
+    /// decode go to the decode error handler instead of `callback`.
+    pub async fn subscribe_typed<T: DeserializeOwned + Send + 'static>(
+        &self,
+        address: Url,
+        callback: Box<dyn Fn(TypedMessage<T>) -> BoxFuture<'static, ()> + Send + Sync>,
+    ) -> Result<SubscriptionGuard> {
+        let on_error = self.decode_error_handler.clone();
+        self.subscribe(address, Box::new(move |msg: MailMessage| {
+            match TypedMessage::try_from(msg.clone()) {
+                Ok(typed) => callback(typed),
+                Err(e) => {
+                    if let Some(on_error) = &on_error {
+                        on_error(&msg, &e);
+                    }
+                    Box::pin(async {})
+                }
+            }
+        })).await
+    }
+
+    /// Like `fetch`, with the body decoded into `T`. A message that does not
+    /// decode is reported to the decode error handler, nacked without
+    /// requeue and returned as a `SerializationError`.
+    pub async fn fetch_typed<T: DeserializeOwned>(&self, address: Url, options: FetchOptions) -> Result<Option<TypedAckableMessage<T>>> {
+        let Some(fetched) = self.fetch(address, options).await? else {
+            return Ok(None);
+        };
+        match TypedMessage::try_from(fetched.message.clone()) {
+            Ok(message) => Ok(Some(TypedAckableMessage {
+                message,
+                ack: fetched.ack,
+                nack: fetched.nack,
+            })),
+            Err(e) => {
+                if let Some(on_error) = &self.decode_error_handler {
+                    on_error(&fetched.message, &e);
+                }
+                fetched.nack(false).await?;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Posts `mail` as a request and waits up to `timeout` for its reply.
+    ///
+    /// Replies are received on an ephemeral address under `mail.from`
+    /// (`<from>/reply/<correlation-id>`), which is stamped on the request as
+    /// the `reply-to` header along with `correlation-id`; the subscription
+    /// is removed and, where the provider supports it, the address purged
+    /// once the call returns. Handlers
+    /// answer with `reply`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn call(&self, mut mail: OutgoingMail, timeout: Duration) -> Result<MailMessage> {
+        let correlation_id = Uuid::new_v4().to_string();
+        let mut reply_to = mail.from.clone();
+        reply_to.set_path(&format!("{}/reply/{}", mail.from.path().trim_end_matches('/'), correlation_id));
+        reply_to.set_query(None);
+        reply_to.set_fragment(None);
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        let expected = correlation_id.clone();
+        let mut subscription = self.subscribe(reply_to.clone(), Box::new(move |msg: MailMessage| {
+            if msg.headers.get(CORRELATION_ID_HEADER) == Some(&expected) {
+                if let Some(tx) = tx.lock().unwrap().take() {
+                    let _ = tx.send(msg);
+                }
+            }
+            Box::pin(async {})
+        })).await?;
+
+        mail.headers.insert(REPLY_TO_HEADER.to_string(), reply_to.to_string());
+        mail.headers.insert(CORRELATION_ID_HEADER.to_string(), correlation_id);
+        let result = match self.post(mail).await {
+            Ok(_) => match tokio::time::timeout(timeout, rx).await {
+                Ok(Ok(reply)) => Ok(reply),
+                Ok(Err(_)) => Err(MailboxError::ProviderError("reply subscription closed".to_string())),
+                Err(_) => Err(MailboxError::Timeout(timeout)),
+            },
+            Err(e) => Err(e),
+        };
+
+        // The reply address is never used again. Failing to clean it up does
+        // not undo the reply, so errors here are ignored.
+        let _ = subscription.unsubscribe().await;
+        if let Ok(provider) = self.get_provider(reply_to.scheme()) {
+            let _ = provider.purge(reply_to).await;
+        }
+        result
+    }
+
+    /// Answers `incoming` with `body`, sent from `incoming.to` to its
+    /// `reply-to` header (or `incoming.from` without one) and carrying its
+    /// `correlation-id` (or its id).
+    pub async fn reply(&self, incoming: &MailMessage, body: Value) -> Result<MailMessage> {
+        let to = match incoming.headers.get(REPLY_TO_HEADER) {
+            Some(reply_to) => reply_to.parse()?,
+            None => incoming.from.clone(),
+        };
+        let correlation_id = incoming.headers.get(CORRELATION_ID_HEADER)
+            .cloned()
+            .unwrap_or_else(|| incoming.id.clone());
+
+        self.post(OutgoingMail {
+            id: None,
+            from: incoming.to.clone(),
+            to,
+            body,
+            headers: HashMap::from([(CORRELATION_ID_HEADER.to_string(), correlation_id)]),
+            meta: HashMap::new(),
+        }).await
+    }
 }
 
 impl Default for Mailbox {
@@ -75,3 +245,194 @@ impl Default for Mailbox {
         Self::new()
     }
 }
+
+/// Messages pushed to an address, returned by `Mailbox::subscribe_stream`.
+pub struct MailboxStream {
+    rx: mpsc::Receiver<MailMessage>,
+    _subscription: SubscriptionGuard,
+}
+
+impl Stream for MailboxStream {
+    type Item = MailMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<MailMessage>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::Subscription;
+    use crate::providers::memory::{MemoryBus, MemoryProvider};
+    use serde_json::json;
+    use std::time::Duration;
+    use std::sync::Mutex;
+
+    fn mailbox() -> Mailbox {
+        let mut mailbox = Mailbox::new();
+        mailbox.register_provider(Box::new(MemoryProvider::new()));
+        mailbox
+    }
+
+    fn request(to: &str, body: Value) -> Result<OutgoingMail> {
+        Ok(OutgoingMail {
+            id: None,
+            from: "mem:client/user1".parse()?,
+            to: to.parse()?,
+            body,
+            headers: HashMap::new(),
+            meta: HashMap::new(),
+        })
+    }
+
+    // `call` is native-only.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[tokio::test]
+    async fn test_call_and_reply() -> Result<()> {
+        let mailbox = mailbox();
+        let service = mailbox.clone();
+        let _sub = mailbox.subscribe("mem:service/calculator".parse()?, Box::new(move |msg: MailMessage| {
+            let service = service.clone();
+            Box::pin(async move {
+                let sum = msg.body["a"].as_i64().unwrap_or(0) + msg.body["b"].as_i64().unwrap_or(0);
+                service.reply(&msg, json!({ "result": sum })).await.unwrap();
+            })
+        })).await?;
+
+        let reply = mailbox.call(
+            request("mem:service/calculator", json!({ "a": 10, "b": 20 }))?,
+            Duration::from_secs(1),
+        ).await?;
+        assert_eq!(reply.body["result"], 30);
+        assert_eq!(reply.from.as_str(), "mem:service/calculator");
+        assert!(reply.to.as_str().starts_with("mem:client/user1/reply/"));
+
+        // Nothing is left behind at the reply address.
+        let status = mailbox.status(reply.to.clone()).await?;
+        assert_eq!(status.unread_count, Some(0));
+        assert!(status.last_activity_time.is_none());
+
+        // Every call gets its own reply address.
+        let second = mailbox.call(request("mem:service/calculator", json!({ "a": 1, "b": 2 }))?, Duration::from_secs(1)).await?;
+        assert_eq!(second.body["result"], 3);
+        assert_ne!(second.to, reply.to);
+        Ok(())
+    }
+
+    #[derive(Debug, Serialize, serde::Deserialize, PartialEq)]
+    #[serde(tag = "op", content = "args", rename_all = "lowercase")]
+    enum Op {
+        Add(i64, i64),
+        Neg(i64),
+    }
+
+    #[tokio::test]
+    async fn test_typed_messages() -> Result<()> {
+        let mut mailbox = mailbox();
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let recorded = failures.clone();
+        mailbox.on_decode_error(move |msg, _| recorded.lock().unwrap().push(msg.id.clone()));
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let _sub = mailbox.subscribe_typed::<Op>("mem:service/ops".parse()?, Box::new(move |msg| {
+            let _ = tx.send(msg.body);
+            Box::pin(async {})
+        })).await?;
+
+        let from: Url = "mem:client/user1".parse()?;
+        let to: Url = "mem:service/ops".parse()?;
+        mailbox.post_typed(TypedMessage::new(from.clone(), to.clone(), Op::Add(1, 2))).await?;
+        let bad = mailbox.post(request("mem:service/ops", json!({ "op": "mul" }))?).await?;
+        mailbox.post_typed(TypedMessage::new(from, to.clone(), Op::Neg(5))).await?;
+
+        let mut received = vec![rx.recv().await.unwrap(), rx.recv().await.unwrap()];
+        received.sort_by_key(|op| matches!(op, Op::Neg(_)));
+        assert_eq!(received, vec![Op::Add(1, 2), Op::Neg(5)]);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(*failures.lock().unwrap(), vec![bad.id.clone()]);
+
+        // Fetching drops the undecodable message and reports it again.
+        let options = FetchOptions { manual_ack: true, ack_timeout: None, ..Default::default() };
+        let first = mailbox.fetch_typed::<Op>(to.clone(), options.clone()).await?.unwrap();
+        assert_eq!(first.message.body, Op::Add(1, 2));
+        first.ack().await?;
+        assert!(matches!(
+            mailbox.fetch_typed::<Op>(to.clone(), options.clone()).await,
+            Err(MailboxError::SerializationError(_))
+        ));
+        assert_eq!(failures.lock().unwrap().len(), 2);
+        let last = mailbox.fetch_typed::<Op>(to.clone(), options).await?.unwrap();
+        assert_eq!(last.message.body, Op::Neg(5));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_stream() -> Result<()> {
+        use futures::StreamExt;
+
+        let bus = Arc::new(MemoryBus::new());
+        let mut mailbox = Mailbox::new();
+        mailbox.register_provider(Box::new(MemoryProvider::with_bus(bus.clone())));
+
+        let mut stream = mailbox.subscribe_stream("mem:service/events".parse()?).await?;
+        assert_eq!(bus.subscriber_count("mem:service/events"), 1);
+        for _ in 0..3 {
+            mailbox.post(request("mem:service/events", json!("tick"))?).await?;
+        }
+        let received: Vec<MailMessage> = (&mut stream).take(3).collect().await;
+        assert!(received.iter().all(|msg| msg.body == "tick"));
+
+        drop(stream);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(bus.subscriber_count("mem:service/events"), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_subscription_guard() -> Result<()> {
+        let bus = Arc::new(MemoryBus::new());
+        let mut mailbox = Mailbox::new();
+        mailbox.register_provider(Box::new(MemoryProvider::with_bus(bus.clone())));
+        let address: Url = "mem:service/guarded".parse()?;
+        let noop = || -> Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync> {
+            Box::new(|_| Box::pin(async {}))
+        };
+
+        drop(mailbox.subscribe(address.clone(), noop()).await?);
+        let mut explicit = mailbox.subscribe(address.clone(), noop()).await?;
+        explicit.unsubscribe().await?;
+        let detached = mailbox.subscribe(address.clone(), noop()).await?.detach();
+        drop(detached);
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(bus.subscriber_count("mem:service/guarded"), 1);
+        Ok(())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn test_subscription_guard_drop_outside_runtime() -> Result<()> {
+        let bus = Arc::new(MemoryBus::new());
+        let mut mailbox = Mailbox::new();
+        mailbox.register_provider(Box::new(MemoryProvider::with_bus(bus.clone())));
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        let guard = runtime.block_on(mailbox.subscribe("mem:service/guarded".parse()?, Box::new(|_| Box::pin(async {}))))?;
+        drop(runtime);
+        assert_eq!(bus.subscriber_count("mem:service/guarded"), 1);
+
+        drop(guard);
+        assert_eq!(bus.subscriber_count("mem:service/guarded"), 0);
+        Ok(())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[tokio::test]
+    async fn test_call_timeout() -> Result<()> {
+        let mailbox = mailbox();
+        let err = mailbox.call(request("mem:service/silent", json!(null))?, Duration::from_millis(50)).await;
+        assert!(matches!(err, Err(MailboxError::Timeout(_))));
+        Ok(())
+    }
+}
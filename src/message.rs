@@ -1,7 +1,16 @@
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use url::Url;
 use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+
+/// Header holding the RFC 3339 time before which a message is not delivered.
+pub const DELIVER_AT_HEADER: &str = "deliver-at";
+/// Header holding the RFC 3339 time after which a message is discarded unread.
+pub const EXPIRES_AT_HEADER: &str = "expires-at";
+/// Header holding a message's priority, an integer; higher is dequeued first.
+pub const PRIORITY_HEADER: &str = "priority";
 
 // We need to import Identifiable trait if we want to implement it here?
 // Or we can just implement it in providers/queue.rs if we import MailMessage there?
@@ -20,6 +29,29 @@ pub trait Identifiable {
     fn id(&self) -> &str;
 }
 
+pub trait Expirable {
+    fn expires_at(&self) -> Option<DateTime<Utc>>;
+
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at().is_some_and(|at| at <= now)
+    }
+}
+
+pub trait Prioritized {
+    fn priority(&self) -> i32;
+}
+
+fn header_time(headers: &HashMap<String, String>, name: &str) -> Option<DateTime<Utc>> {
+    let at = headers.get(name)?;
+    DateTime::parse_from_rfc3339(at).ok().map(|at| at.with_timezone(&Utc))
+}
+
+fn after(delay: std::time::Duration) -> DateTime<Utc> {
+    chrono::Duration::from_std(delay).ok()
+        .and_then(|delay| Utc::now().checked_add_signed(delay))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OutgoingMail {
     pub id: Option<String>,
@@ -44,12 +76,66 @@ pub struct MailMessage {
     pub meta: HashMap<String, Value>,
 }
 
+impl OutgoingMail {
+    /// Holds the message back from subscribers and `fetch` until `at`.
+    pub fn deliver_at(mut self, at: DateTime<Utc>) -> Self {
+        self.headers.insert(DELIVER_AT_HEADER.to_string(), at.to_rfc3339());
+        self
+    }
+
+    /// Holds the message back for `delay` from now, like Erlang's `send_after`.
+    pub fn deliver_after(self, delay: std::time::Duration) -> Self {
+        self.deliver_at(after(delay))
+    }
+
+    /// Discards the message if it is still unread at `at`.
+    pub fn expires_at(mut self, at: DateTime<Utc>) -> Self {
+        self.headers.insert(EXPIRES_AT_HEADER.to_string(), at.to_rfc3339());
+        self
+    }
+
+    /// Discards the message if it is still unread `ttl` from now.
+    pub fn expires_after(self, ttl: std::time::Duration) -> Self {
+        self.expires_at(after(ttl))
+    }
+
+    /// Lets the message overtake queued messages of a lower priority; the
+    /// default is 0.
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.headers.insert(PRIORITY_HEADER.to_string(), priority.to_string());
+        self
+    }
+}
+
+impl MailMessage {
+    /// When the message becomes visible, from its `deliver-at` header.
+    pub fn deliver_at(&self) -> Option<DateTime<Utc>> {
+        header_time(&self.headers, DELIVER_AT_HEADER)
+    }
+}
+
 impl Identifiable for MailMessage {
     fn id(&self) -> &str {
         &self.id
     }
 }
 
+impl Prioritized for MailMessage {
+    /// From the `priority` header; 0 when it is missing or not an integer.
+    fn priority(&self) -> i32 {
+        self.headers.get(PRIORITY_HEADER)
+            .and_then(|p| p.trim().parse().ok())
+            .unwrap_or(0)
+    }
+}
+
+impl Expirable for MailMessage {
+    /// From the `expires-at` header.
+    fn expires_at(&self) -> Option<DateTime<Utc>> {
+        header_time(&self.headers, EXPIRES_AT_HEADER)
+    }
+}
+
 impl From<OutgoingMail> for MailMessage {
     fn from(mail: OutgoingMail) -> Self {
         MailMessage {
@@ -63,6 +149,75 @@ impl From<OutgoingMail> for MailMessage {
     }
 }
 
+/// The mail provider tests send: `id` to `to` from `mem:test/sender`, with
+/// a `{"n": id}` body and a `kind: test` header for round-trip checks.
+/// Tests that need something else override fields with `..test_mail(..)`.
+#[cfg(test)]
+pub(crate) fn test_mail(id: &str, to: &Url) -> OutgoingMail {
+    OutgoingMail {
+        id: Some(id.to_string()),
+        from: "mem:test/sender".parse().unwrap(),
+        to: to.clone(),
+        body: serde_json::json!({"n": id}),
+        headers: HashMap::from([("kind".to_string(), "test".to_string())]),
+        meta: HashMap::new(),
+    }
+}
+
+/// A message whose body is decoded into `T`.
+#[derive(Debug, Clone)]
+pub struct TypedMessage<T> {
+    /// Empty until the message has been posted.
+    pub id: String,
+    pub from: Url,
+    pub to: Url,
+    pub body: T,
+    pub headers: HashMap<String, String>,
+    pub meta: HashMap<String, Value>,
+}
+
+impl<T> TypedMessage<T> {
+    /// A message to post, with no headers or meta.
+    pub fn new(from: Url, to: Url, body: T) -> Self {
+        Self {
+            id: String::new(),
+            from,
+            to,
+            body,
+            headers: HashMap::new(),
+            meta: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Serialize> TypedMessage<T> {
+    pub fn into_outgoing(self) -> Result<OutgoingMail, serde_json::Error> {
+        Ok(OutgoingMail {
+            id: (!self.id.is_empty()).then_some(self.id),
+            from: self.from,
+            to: self.to,
+            body: serde_json::to_value(self.body)?,
+            headers: self.headers,
+            meta: self.meta,
+        })
+    }
+}
+
+impl<T: DeserializeOwned> TryFrom<MailMessage> for TypedMessage<T> {
+    type Error = serde_json::Error;
+
+    fn try_from(message: MailMessage) -> Result<Self, Self::Error> {
+        Ok(Self {
+            body: serde_json::from_value(message.body)?,
+            id: message.id,
+            from: message.from,
+            to: message.to,
+            headers: message.headers,
+            meta: message.meta,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MailboxStatus {
     pub state: String,
@@ -74,17 +229,43 @@ pub struct MailboxStatus {
     pub extra: HashMap<String, Value>,
 }
 
-#[derive(Debug, Clone)]
+/// How `fetch` takes a message. `FetchOptions::default()` auto-acks; set
+/// the fields directly or through the builder methods.
+#[derive(Debug, Clone, Default)]
 pub struct FetchOptions {
     pub manual_ack: bool,
     pub ack_timeout: Option<u64>,
+    /// With `manual_ack`, how many deliveries a message gets before a nack
+    /// with requeue or an ack timeout moves it to `dead_letter_address`
+    /// instead of back onto the queue. Enforced by the memory, file and
+    /// sqlite providers and those serving a `MemoryBus`; the others fail
+    /// the fetch when it is set.
+    pub max_deliveries: Option<u32>,
+    /// Where messages go after `max_deliveries`; defaults to `<address>/dlq`.
+    pub dead_letter_address: Option<Url>,
 }
 
-impl Default for FetchOptions {
-    fn default() -> Self {
-        Self {
-            manual_ack: false,
-            ack_timeout: None,
-        }
+impl FetchOptions {
+    /// Leases the message until it is acked or nacked instead of taking it.
+    pub fn manual_ack(mut self) -> Self {
+        self.manual_ack = true;
+        self
+    }
+
+    /// Makes a manual-ack lease run out after `ms` milliseconds, after
+    /// which the message can be fetched again.
+    pub fn ack_timeout(mut self, ms: u64) -> Self {
+        self.ack_timeout = Some(ms);
+        self
+    }
+
+    pub fn max_deliveries(mut self, max_deliveries: u32) -> Self {
+        self.max_deliveries = Some(max_deliveries);
+        self
+    }
+
+    pub fn dead_letter_address(mut self, address: Url) -> Self {
+        self.dead_letter_address = Some(address);
+        self
     }
 }
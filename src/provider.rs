@@ -1,12 +1,83 @@
 use async_trait::async_trait;
 use url::Url;
 use crate::error::Result;
-use crate::message::{MailMessage, MailboxStatus, FetchOptions};
+use crate::message::{MailMessage, MailboxStatus, FetchOptions, TypedMessage};
 use futures::future::BoxFuture;
 
 #[async_trait]
 pub trait Subscription: Send + Sync {
     async fn unsubscribe(&mut self) -> Result<()>;
+
+    /// Ends the subscription without awaiting anything, for when
+    /// `SubscriptionGuard` is dropped outside a tokio runtime. Returns
+    /// `false` if it can only be ended by `unsubscribe`, which is the default.
+    fn unsubscribe_now(&mut self) -> bool {
+        false
+    }
+}
+
+/// Owns a subscription and unsubscribes when dropped; returned by
+/// `Mailbox::subscribe`.
+///
+/// The drop-time unsubscribe runs as a background task, so on native
+/// targets it needs a tokio runtime. Outside one it falls back to
+/// `Subscription::unsubscribe_now`; a subscription that does not support
+/// that (such as an AMQP consumer) is left in place, which debug builds
+/// report with a panic. Call `unsubscribe` to wait for it, or `detach` to
+/// keep the subscription alive for good.
+pub struct SubscriptionGuard {
+    inner: Option<Box<dyn Subscription>>,
+}
+
+impl SubscriptionGuard {
+    pub fn new(subscription: Box<dyn Subscription>) -> Self {
+        Self {
+            inner: Some(subscription),
+        }
+    }
+
+    /// Releases the subscription without unsubscribing. Dropping the
+    /// returned handle leaves the subscription active.
+    pub fn detach(mut self) -> Box<dyn Subscription> {
+        self.inner.take().expect("subscription guard is empty")
+    }
+}
+
+#[async_trait]
+impl Subscription for SubscriptionGuard {
+    async fn unsubscribe(&mut self) -> Result<()> {
+        match self.inner.take() {
+            Some(mut subscription) => subscription.unsubscribe().await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for SubscriptionGuard {
+    fn drop(&mut self) {
+        let Some(mut subscription) = self.inner.take() else { return };
+
+        #[cfg(not(target_arch = "wasm32"))]
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(async move {
+                    let _ = subscription.unsubscribe().await;
+                });
+            }
+            Err(_) => {
+                let ended = subscription.unsubscribe_now();
+                debug_assert!(
+                    ended || std::thread::panicking(),
+                    "subscription dropped outside a tokio runtime was left in place"
+                );
+            }
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        wasm_bindgen_futures::spawn_local(async move {
+            let _ = subscription.unsubscribe().await;
+        });
+    }
 }
 
 pub struct AckableMessage {
@@ -25,6 +96,23 @@ impl AckableMessage {
     }
 }
 
+/// An `AckableMessage` whose body has been decoded into `T`.
+pub struct TypedAckableMessage<T> {
+    pub message: TypedMessage<T>,
+    pub ack: Box<dyn FnOnce() -> BoxFuture<'static, Result<()>> + Send + Sync>,
+    pub nack: Box<dyn FnOnce(bool) -> BoxFuture<'static, Result<()>> + Send + Sync>,
+}
+
+impl<T> TypedAckableMessage<T> {
+    pub async fn ack(self) -> Result<()> {
+        (self.ack)().await
+    }
+
+    pub async fn nack(self, requeue: bool) -> Result<()> {
+        (self.nack)(requeue).await
+    }
+}
+
 #[async_trait]
 pub trait MailboxProvider: Send + Sync {
     fn protocol(&self) -> &str;
@@ -41,5 +129,14 @@ pub trait MailboxProvider: Send + Sync {
 
     async fn status(&self, address: Url) -> Result<MailboxStatus>;
 
+    /// Discards everything queued at `address`, for mailboxes that are done
+    /// with, such as the one-off reply addresses of `Mailbox::call`. The
+    /// default does nothing: draining with `fetch` would set up broker-side
+    /// state for an address that is never used again, so only providers that
+    /// can drop local state cheaply override it.
+    async fn purge(&self, _address: Url) -> Result<()> {
+        Ok(())
+    }
+
     fn generate_id(&self) -> String;
 }
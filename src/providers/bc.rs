@@ -0,0 +1,208 @@
+use async_trait::async_trait;
+use url::Url;
+use std::sync::Arc;
+use uuid::Uuid;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::JsCast;
+use web_sys::{BroadcastChannel, MessageEvent};
+
+use crate::error::{MailboxError, Result};
+use crate::message::{MailMessage, MailboxStatus, FetchOptions};
+use crate::provider::{MailboxProvider, Subscription, AckableMessage};
+use crate::providers::memory::{MemoryBus, MemoryProvider};
+use crate::utils::get_canonical_mailbox_address_identifier;
+
+/// What travels over the channel: the message and the mailbox it is for.
+#[derive(Serialize, Deserialize)]
+struct Frame {
+    topic: String,
+    message: MailMessage,
+}
+
+/// Provider for `bc:` addresses shared between same-origin tabs and workers
+/// through the browser's BroadcastChannel API (wasm32 only).
+///
+/// Every provider opened with the same channel name, in any tab, frame or
+/// worker of the origin, receives what the others `send`: it is delivered
+/// to local subscribers and queued locally for `fetch`. Each context keeps
+/// its own queue, so a message fetched in one tab stays queued in the
+/// others. Messages sent before a context opened its provider are not seen.
+pub struct BroadcastChannelProvider {
+    protocol: String,
+    outgoing: mpsc::UnboundedSender<String>,
+    inbox: Arc<MemoryBus>,
+    local: MemoryProvider,
+}
+
+impl BroadcastChannelProvider {
+    /// Joins the BroadcastChannel `name`; the channel is closed when the
+    /// provider is dropped.
+    pub fn new(name: &str) -> Result<Self> {
+        let channel = BroadcastChannel::new(name)
+            .map_err(|e| MailboxError::ProviderError(format!("broadcast channel: {:?}", e)))?;
+        let inbox = Arc::new(MemoryBus::new());
+        let (outgoing, mut frames) = mpsc::unbounded_channel::<String>();
+
+        let receiving = inbox.clone();
+        let onmessage = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+            let Some(text) = event.data().as_string() else { return };
+            if let Ok(frame) = serde_json::from_str::<Frame>(&text) {
+                // There is no one to report a full mailbox to.
+                let _ = receiving.publish(frame.topic, frame.message);
+            }
+        });
+        channel.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+
+        // The channel and its callback are not `Send`, so they live in a
+        // local task that the provider feeds through a channel.
+        wasm_bindgen_futures::spawn_local(async move {
+            while let Some(frame) = frames.recv().await {
+                let _ = channel.post_message(&frame.into());
+            }
+            channel.set_onmessage(None);
+            channel.close();
+            drop(onmessage);
+        });
+
+        Ok(Self {
+            protocol: "bc".to_string(),
+            outgoing,
+            local: MemoryProvider::with_bus(inbox.clone()),
+            inbox,
+        })
+    }
+}
+
+#[async_trait]
+impl MailboxProvider for BroadcastChannelProvider {
+    fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    async fn send(&self, message: MailMessage) -> Result<MailMessage> {
+        let topic = get_canonical_mailbox_address_identifier(&message.to);
+        let frame = serde_json::to_string(&Frame {
+            topic: topic.clone(),
+            message: message.clone(),
+        })?;
+
+        // A BroadcastChannel does not deliver to itself. Publishing here
+        // first means a full local inbox fails the send before other tabs
+        // get a copy that a retry would duplicate.
+        self.inbox.publish(topic, message.clone())?;
+        self.outgoing.send(frame)
+            .map_err(|_| MailboxError::ProviderError("broadcast channel is closed".to_string()))?;
+        Ok(message)
+    }
+
+    async fn subscribe(
+        &self,
+        address: Url,
+        callback: Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>,
+    ) -> Result<Box<dyn Subscription>> {
+        self.local.subscribe(address, callback).await
+    }
+
+    async fn fetch(&self, address: Url, options: FetchOptions) -> Result<Option<AckableMessage>> {
+        self.local.fetch(address, options).await
+    }
+
+    async fn status(&self, address: Url) -> Result<MailboxStatus> {
+        self.local.status(address).await
+    }
+
+    async fn purge(&self, address: Url) -> Result<()> {
+        self.local.purge(address).await
+    }
+
+    fn generate_id(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::test_mail;
+    use crate::providers::memory::OverflowPolicy;
+    use std::sync::Mutex;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    /// `setTimeout` exists on both windows and workers.
+    async fn sleep(ms: i32) {
+        let tick = js_sys::Promise::new(&mut |resolve, _| {
+            let set_timeout: js_sys::Function = js_sys::Reflect::get(&js_sys::global(), &"setTimeout".into())
+                .unwrap()
+                .unchecked_into();
+            let _ = set_timeout.call2(&wasm_bindgen::JsValue::NULL, &resolve, &ms.into());
+        });
+        wasm_bindgen_futures::JsFuture::from(tick).await.unwrap();
+    }
+
+    /// Two providers on one channel stand in for two tabs.
+    fn pair() -> Result<(BroadcastChannelProvider, BroadcastChannelProvider)> {
+        let name = format!("mailbox-test-{}", Uuid::new_v4());
+        Ok((BroadcastChannelProvider::new(&name)?, BroadcastChannelProvider::new(&name)?))
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_subscribe_across_contexts() -> Result<()> {
+        let (first, second) = pair()?;
+        let address: Url = "bc:test/inbox".parse()?;
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        let _sub = second.subscribe(address.clone(), Box::new(move |msg| {
+            let tx = tx.clone();
+            Box::pin(async move {
+                if let Some(tx) = tx.lock().unwrap().take() {
+                    tx.send(msg).unwrap();
+                }
+            })
+        })).await?;
+
+        first.send(test_mail("msg1", &address).into()).await?;
+        assert_eq!(rx.await.unwrap().id, "msg1");
+        Ok(())
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_fetch_from_local_queue() -> Result<()> {
+        let (first, second) = pair()?;
+        let address: Url = "bc:test/jobs".parse()?;
+
+        first.send(test_mail("msg1", &address).into()).await?;
+        assert_eq!(first.fetch(address.clone(), FetchOptions::default()).await?.unwrap().message.id, "msg1");
+
+        // Delivery to the other context is asynchronous.
+        for _ in 0..50 {
+            if second.status(address.clone()).await?.unread_count == Some(1) {
+                let fetched = second.fetch(address, FetchOptions::default()).await?;
+                assert_eq!(fetched.unwrap().message.id, "msg1");
+                return Ok(());
+            }
+            sleep(10).await;
+        }
+        panic!("message did not reach the other context");
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_rejected_send_is_not_broadcast() -> Result<()> {
+        let (first, second) = pair()?;
+        let address: Url = "bc:test/full".parse()?;
+        first.inbox.set_capacity(&address, 1, OverflowPolicy::Reject);
+
+        first.send(test_mail("msg1", &address).into()).await?;
+        assert!(first.send(test_mail("msg2", &address).into()).await.is_err());
+
+        sleep(100).await;
+        assert_eq!(second.status(address.clone()).await?.unread_count, Some(1));
+        assert_eq!(second.fetch(address, FetchOptions::default()).await?.unwrap().message.id, "msg1");
+        Ok(())
+    }
+}
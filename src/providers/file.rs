@@ -0,0 +1,1006 @@
+use async_trait::async_trait;
+use url::Url;
+use std::sync::{Arc, RwLock};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+use futures::future::BoxFuture;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+
+use crate::error::{MailboxError, Result};
+use crate::message::{MailMessage, MailboxStatus, FetchOptions, Expirable};
+use crate::provider::{MailboxProvider, Subscription, AckableMessage};
+use crate::utils::{default_dead_letter_address, get_canonical_mailbox_address_identifier};
+use crate::providers::queue::{DeliveryLimit, MailMessageQueue};
+
+type Listener = Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// The log is compacted once it holds at least this many records and more
+/// than half of them are dead.
+const COMPACT_MIN_RECORDS: usize = 1024;
+
+/// One line of the write-ahead log. Every mutation of the queue is appended
+/// before it is applied, so replaying the log rebuilds the exact queue state.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum LogRecord {
+    Enqueue {
+        topic: String,
+        message: Box<MailMessage>,
+        // Deliveries so far, kept by compaction for `max_deliveries`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        deliveries: Option<u32>,
+    },
+    Dequeue { topic: String, id: String },
+    /// `id` was fetched for manual ack under the token `lease`.
+    Lease {
+        topic: String,
+        id: String,
+        lease: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<DeliveryLimit>,
+    },
+    Ack { id: String, lease: String },
+    Nack { id: String, lease: String, requeue: bool },
+    /// `lease` on `id` ran out before it was acked.
+    Timeout { id: String, lease: String },
+    Release { id: String },
+    /// Everything queued or scheduled on `topic` was discarded.
+    Purge { topic: String },
+    /// `id` was dropped from `topic` for expiring.
+    Expire { topic: String, id: String },
+    /// `count` messages had expired on `topic` when the log was compacted.
+    Expired { topic: String, count: usize },
+}
+
+struct FileState {
+    path: PathBuf,
+    log: File,
+    // Records in the log since it was last rewritten
+    records: usize,
+    // Set when a failed append could not be cut back off; the log may end
+    // in a partial record, so it is rewritten before the next append
+    poisoned: bool,
+    topics: HashMap<String, Vec<Arc<Listener>>>,
+    queue: MailMessageQueue<MailMessage>,
+    last_activity: HashMap<String, String>,
+    // When the provider next wakes up to release what is due and drop what
+    // has expired, and the task sleeping until then
+    timer: Option<(DateTime<Utc>, tokio::task::JoinHandle<()>)>,
+}
+
+impl FileState {
+    /// Appends `record` and syncs it. A write that fails partway is cut
+    /// back off the log, so the next record does not land on a fragment
+    /// that would make the log unreadable on `open`; if even that fails, the
+    /// log is rewritten from the queue before anything else is appended.
+    fn append(&mut self, record: &LogRecord) -> Result<()> {
+        if self.poisoned {
+            let (log, records) = FileProvider::compact(&self.path, &self.queue)?;
+            self.log = log;
+            self.records = records;
+            self.poisoned = false;
+        }
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let len = self.log.metadata()?.len();
+        let written = self.log.write_all(&line).and_then(|_| self.log.sync_data());
+        if let Err(e) = written {
+            if self.log.set_len(len).and_then(|_| self.log.sync_data()).is_err() {
+                self.poisoned = true;
+            }
+            return Err(e.into());
+        }
+        self.records += 1;
+        Ok(())
+    }
+
+    fn apply(&mut self, record: LogRecord) {
+        for (topic, message) in apply(&mut self.queue, record) {
+            self.last_activity.insert(topic.clone(), Utc::now().to_rfc3339());
+            self.notify(&topic, &message);
+        }
+    }
+
+    /// Rewrites the log once most of its records no longer describe a live
+    /// message. A failed rewrite leaves the old log in place, to be retried
+    /// on a later write.
+    fn compact_if_needed(&mut self) {
+        if self.records < COMPACT_MIN_RECORDS || self.queue.message_count() * 2 >= self.records {
+            return;
+        }
+        if let Ok((log, records)) = FileProvider::compact(&self.path, &self.queue) {
+            self.log = log;
+            self.records = records;
+            self.poisoned = false;
+        }
+    }
+
+    /// Pushes `message` to the subscribers of `topic`.
+    fn notify(&self, topic: &str, message: &MailMessage) {
+        if let Some(listeners) = self.topics.get(topic) {
+            for listener in listeners {
+                let msg = message.clone();
+                let listener = listener.clone();
+
+                tokio::spawn(async move {
+                    (listener)(msg).await;
+                });
+            }
+        }
+    }
+
+    /// Drops the expired messages of `topic`.
+    fn sweep(&mut self, topic: &str) -> Result<()> {
+        self.queue.expire_topic(topic, Utc::now());
+        self.log_expired()
+    }
+
+    /// Logs the messages the queue has dropped for expiring, so that replay
+    /// drops them too and `expired_count` survives a restart.
+    fn log_expired(&mut self) -> Result<()> {
+        for (topic, message) in self.queue.take_expired() {
+            self.append(&LogRecord::Expire { topic, id: message.id })?;
+        }
+        Ok(())
+    }
+
+    /// Moves the scheduled messages whose time has come onto their queues.
+    fn release_due(&mut self) -> Result<()> {
+        for id in self.queue.due(Utc::now()) {
+            self.append(&LogRecord::Release { id: id.clone() })?;
+            if let Some((topic, message)) = self.queue.release(&id) {
+                self.last_activity.insert(topic.clone(), Utc::now().to_rfc3339());
+                self.notify(&topic, &message);
+            }
+        }
+        // Those that expired while scheduled
+        self.log_expired()
+    }
+
+    /// Takes the next message on `topic` for `fetch`, leasing it under
+    /// `lease` for manual ack.
+    fn take(&mut self, address: &Url, topic: &str, options: &FetchOptions, lease: String) -> Result<Option<MailMessage>> {
+        self.release_due()?;
+        self.sweep(topic)?;
+        self.last_activity.insert(topic.to_string(), Utc::now().to_rfc3339());
+
+        if !options.manual_ack {
+            let Some(id) = self.queue.peek(topic).map(|m| m.id.clone()) else {
+                return Ok(None);
+            };
+            self.append(&LogRecord::Dequeue { topic: topic.to_string(), id: id.clone() })?;
+            let msg = self.queue.remove(topic, &id);
+            self.compact_if_needed();
+            return Ok(msg);
+        }
+
+        if let Some(timeout) = options.ack_timeout.map(Duration::from_millis) {
+            // Time out stale leases through the log so replay stays in step.
+            for (id, stale) in self.queue.stale(topic, timeout) {
+                let record = LogRecord::Timeout { id, lease: stale };
+                self.append(&record)?;
+                self.apply(record);
+            }
+            // Some of them may have expired while they were out.
+            self.sweep(topic)?;
+        }
+
+        let Some(id) = self.queue.peek(topic).map(|m| m.id.clone()) else {
+            return Ok(None);
+        };
+        let limit = options.max_deliveries.map(|max_deliveries| {
+            let dead_letter_address = options.dead_letter_address.clone()
+                .unwrap_or_else(|| default_dead_letter_address(address));
+            DeliveryLimit {
+                max_deliveries,
+                dead_letter_topic: get_canonical_mailbox_address_identifier(&dead_letter_address),
+            }
+        });
+        self.append(&LogRecord::Lease {
+            topic: topic.to_string(),
+            id: id.clone(),
+            lease: lease.clone(),
+            limit: limit.clone(),
+        })?;
+
+        let Some(mut msg) = self.queue.lease(topic, &id, limit, lease) else {
+            return Err(MailboxError::ProviderError(format!("queue for {} changed during fetch", topic)));
+        };
+        msg.meta.insert("delivery_count".to_string(), self.queue.delivery_count(&id).into());
+        self.compact_if_needed();
+        Ok(Some(msg))
+    }
+
+    /// Releases due messages and drops expired ones on every topic.
+    fn maintain(&mut self) -> Result<()> {
+        self.release_due()?;
+        self.queue.sweep(Utc::now());
+        self.log_expired()
+    }
+
+    /// Makes sure the provider wakes up by the time the next scheduled
+    /// message is due or the next queued one expires. One timer serves every
+    /// topic and is only replaced when something comes due sooner. Outside a
+    /// runtime this happens on the next `fetch` or `status` instead.
+    fn arm(&mut self, owner: &Arc<RwLock<FileState>>) {
+        let Some(at) = [self.queue.next_due(), self.queue.next_expiry()].into_iter().flatten().min() else {
+            return;
+        };
+        if self.timer.as_ref().is_some_and(|(armed, _)| *armed <= at) {
+            return;
+        }
+        if tokio::runtime::Handle::try_current().is_err() {
+            return;
+        }
+        if let Some((_, task)) = self.timer.take() {
+            task.abort();
+        }
+
+        let state = Arc::downgrade(owner);
+        let delay = (at - Utc::now()).to_std().unwrap_or_default();
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let Some(state) = state.upgrade() else { return };
+            let owner = state.clone();
+            let _ = with_state(state, move |state| {
+                if state.timer.as_ref().is_some_and(|(armed, _)| *armed == at) {
+                    state.timer = None;
+                }
+                let maintained = state.maintain();
+                state.arm(&owner);
+                maintained
+            }).await;
+        });
+        self.timer = Some((at, task));
+    }
+}
+
+/// Runs `f` on the locked state from a blocking thread: every change is
+/// written and synced to the log before it returns.
+async fn with_state<R: Send + 'static>(
+    state: Arc<RwLock<FileState>>,
+    f: impl FnOnce(&mut FileState) -> Result<R> + Send + 'static,
+) -> Result<R> {
+    tokio::task::spawn_blocking(move || f(&mut state.write().unwrap()))
+        .await
+        .map_err(|e| MailboxError::ProviderError(format!("file: {}", e)))?
+}
+
+/// Applies `record` to `queue`, returning the messages it moved to a
+/// dead-letter topic. Which messages run out of deliveries follows from the
+/// records before, so dead-lettering is not logged separately.
+fn apply(queue: &mut MailMessageQueue<MailMessage>, record: LogRecord) -> Vec<(String, MailMessage)> {
+    match record {
+        // Scheduled messages wait for their `Release`, even when replayed late.
+        LogRecord::Enqueue { topic, message, deliveries } => {
+            queue.set_delivery_count(&message.id, deliveries.unwrap_or(0));
+            match message.deliver_at() {
+                Some(due) => queue.schedule(topic, *message, due),
+                None => queue.enqueue(topic, *message),
+            }
+        }
+        // By id, as messages that have expired since are swept on open.
+        LogRecord::Dequeue { topic, id } => {
+            queue.remove(&topic, &id);
+        }
+        LogRecord::Lease { topic, id, lease, limit } => {
+            queue.lease(&topic, &id, limit, lease);
+        }
+        LogRecord::Ack { id, lease } => queue.ack(&id, &lease),
+        LogRecord::Nack { id, lease, requeue } => queue.nack(&id, &lease, requeue),
+        LogRecord::Timeout { id, lease } => {
+            queue.time_out(&id, &lease);
+        }
+        LogRecord::Release { id } => {
+            queue.release(&id);
+        }
+        LogRecord::Purge { topic } => queue.purge(&topic),
+        LogRecord::Expire { topic, id } => {
+            queue.expire_id(&topic, &id);
+        }
+        LogRecord::Expired { topic, count } => queue.count_expired(&topic, count),
+    }
+    move_dead_letters(queue)
+}
+
+/// Queues the messages that ran out of deliveries on their dead-letter topic.
+fn move_dead_letters(queue: &mut MailMessageQueue<MailMessage>) -> Vec<(String, MailMessage)> {
+    queue.take_dead_letters()
+        .into_iter()
+        .map(|dead| {
+            let (topic, message) = dead.into_message();
+            queue.enqueue(topic.clone(), message.clone());
+            (topic, message)
+        })
+        .collect()
+}
+
+/// Durable provider for the `file:` scheme.
+///
+/// Messages and in-flight state are kept in memory and mirrored to an
+/// append-only log at `path`. On `open` the log is replayed, messages that
+/// were fetched for manual ack but never acked are put back at the head of
+/// their queue, and the log is compacted to the surviving messages; it is
+/// compacted again whenever most of its records have gone stale. Log writes
+/// run on blocking threads. Each manual-ack fetch takes a fresh lease token,
+/// and ack/nack only apply while the message is still held under it, so a
+/// holder whose ack timeout ran out cannot settle the redelivered message.
+/// Messages with a future `deliver-at` are logged when sent and again when
+/// they are released, so they stay scheduled across restarts. Delivery
+/// counts survive restarts too, so `max_deliveries` holds across them: a
+/// lease found un-acked on `open` counts as nacked with requeue. Expired
+/// messages are always dropped, never dead-lettered, either when their
+/// mailbox is next fetched from or checked or by a timer at their expiry,
+/// so they stop counting as unread on time. Drops are logged, so
+/// `expired_count` survives restarts.
+pub struct FileProvider {
+    protocol: String,
+    path: PathBuf,
+    state: Arc<RwLock<FileState>>,
+}
+
+impl FileProvider {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut queue = MailMessageQueue::new();
+        let mut leased: Vec<(String, String)> = Vec::new();
+
+        if path.exists() {
+            let lines: Vec<String> = BufReader::new(File::open(&path)?)
+                .lines()
+                .collect::<std::io::Result<_>>()?;
+            let last = lines.len().saturating_sub(1);
+
+            for (index, line) in lines.iter().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let record: LogRecord = match serde_json::from_str(line) {
+                    Ok(record) => record,
+                    // A torn final record means we crashed mid-append; the
+                    // operation never completed, so it is safe to drop.
+                    Err(_) if index == last => break,
+                    Err(e) => return Err(e.into()),
+                };
+                if let LogRecord::Lease { id, lease, .. } = &record {
+                    leased.push((id.clone(), lease.clone()));
+                }
+                apply(&mut queue, record);
+            }
+        }
+
+        // Un-acked deliveries go back to the head of their queue, oldest lease first.
+        leased.retain(|(id, lease)| queue.holds(id, lease));
+        for (id, lease) in leased.iter().rev() {
+            queue.nack(id, lease, true);
+        }
+        move_dead_letters(&mut queue);
+        // Messages that expired while the provider was closed; compaction
+        // keeps their count.
+        queue.sweep(Utc::now());
+        queue.take_expired();
+
+        let (log, records) = Self::compact(&path, &queue)?;
+
+        let state = Arc::new(RwLock::new(FileState {
+            path: path.clone(),
+            log,
+            records,
+            poisoned: false,
+            topics: HashMap::new(),
+            queue,
+            last_activity: HashMap::new(),
+            timer: None,
+        }));
+        // Without a runtime, restored schedules are released by the next
+        // `fetch` or `status` once due.
+        state.write().unwrap().arm(&state);
+
+        Ok(Self {
+            protocol: "file".to_string(),
+            path,
+            state,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Rewrites the log so it only describes the pending, scheduled and
+    /// in-flight messages, and returns it opened for appending along with
+    /// the number of records written.
+    fn compact(path: &Path, queue: &MailMessageQueue<MailMessage>) -> Result<(File, usize)> {
+        let mut records = Vec::new();
+        for (topic, count) in queue.expired_counts() {
+            records.push(LogRecord::Expired { topic: topic.to_string(), count });
+        }
+        for (topic, message) in queue.scheduled() {
+            records.push(enqueue_record(queue, topic, message, 0));
+        }
+        for (topic, message) in queue.pending() {
+            records.push(enqueue_record(queue, topic, message, 0));
+            // Already released: replaying `Enqueue` alone would schedule it again.
+            if message.deliver_at().is_some() {
+                records.push(LogRecord::Release { id: message.id.clone() });
+            }
+        }
+        for (topic, message, lease, limit) in queue.in_flight() {
+            // Replaying the lease counts the current delivery again.
+            records.push(enqueue_record(queue, topic, message, 1));
+            if message.deliver_at().is_some() {
+                records.push(LogRecord::Release { id: message.id.clone() });
+            }
+            records.push(LogRecord::Lease {
+                topic: topic.to_string(),
+                id: message.id.clone(),
+                lease: lease.to_string(),
+                limit: limit.cloned(),
+            });
+        }
+
+        let tmp = path.with_extension("compact");
+        {
+            let mut file = File::create(&tmp)?;
+            for record in &records {
+                let mut line = serde_json::to_vec(record)?;
+                line.push(b'\n');
+                file.write_all(&line)?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+
+        Ok((OpenOptions::new().append(true).open(path)?, records.len()))
+    }
+}
+
+/// The record that queues `message` on `topic` again, keeping its delivery
+/// count less the `replayed` deliveries that later records count again.
+fn enqueue_record(queue: &MailMessageQueue<MailMessage>, topic: &str, message: &MailMessage, replayed: u32) -> LogRecord {
+    let deliveries = queue.delivery_count(&message.id).saturating_sub(replayed);
+    LogRecord::Enqueue {
+        topic: topic.to_string(),
+        message: Box::new(message.clone()),
+        deliveries: (deliveries > 0).then_some(deliveries),
+    }
+}
+
+struct FileSubscription {
+    state: Arc<RwLock<FileState>>,
+    topic: String,
+    listener: Arc<Listener>,
+}
+
+#[async_trait]
+impl Subscription for FileSubscription {
+    async fn unsubscribe(&mut self) -> Result<()> {
+        let topic = self.topic.clone();
+        let listener = self.listener.clone();
+        with_state(self.state.clone(), move |state| {
+            if let Some(listeners) = state.topics.get_mut(&topic) {
+                listeners.retain(|l| !Arc::ptr_eq(l, &listener));
+            }
+            Ok(())
+        }).await
+    }
+
+    fn unsubscribe_now(&mut self) -> bool {
+        let mut state = self.state.write().unwrap();
+        if let Some(listeners) = state.topics.get_mut(&self.topic) {
+            listeners.retain(|l| !Arc::ptr_eq(l, &self.listener));
+        }
+        true
+    }
+}
+
+#[async_trait]
+impl MailboxProvider for FileProvider {
+    fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    async fn send(&self, message: MailMessage) -> Result<MailMessage> {
+        let shared = self.state.clone();
+        with_state(self.state.clone(), move |state| {
+            let topic = get_canonical_mailbox_address_identifier(&message.to);
+            state.last_activity.insert(topic.clone(), Utc::now().to_rfc3339());
+
+            let record = LogRecord::Enqueue {
+                topic: topic.clone(),
+                message: Box::new(message.clone()),
+                deliveries: None,
+            };
+            state.append(&record)?;
+            state.apply(record);
+
+            // Dropped right away, and never pushed
+            if message.is_expired(Utc::now()) {
+                state.sweep(&topic)?;
+                return Ok(message);
+            }
+
+            match message.deliver_at() {
+                Some(due) if due > Utc::now() => {}
+                // Already due: released and pushed right away
+                Some(_) => state.release_due()?,
+                None => state.notify(&topic, &message),
+            }
+            state.arm(&shared);
+            state.compact_if_needed();
+
+            Ok(message)
+        }).await
+    }
+
+    async fn subscribe(
+        &self,
+        address: Url,
+        callback: Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>,
+    ) -> Result<Box<dyn Subscription>> {
+        let topic = get_canonical_mailbox_address_identifier(&address);
+        let listener = Arc::new(callback);
+
+        let (key, added) = (topic.clone(), listener.clone());
+        with_state(self.state.clone(), move |state| {
+            state.topics
+                .entry(key.clone())
+                .or_default()
+                .push(added);
+            state.last_activity.insert(key, Utc::now().to_rfc3339());
+            Ok(())
+        }).await?;
+
+        Ok(Box::new(FileSubscription {
+            state: self.state.clone(),
+            topic,
+            listener,
+        }))
+    }
+
+    async fn fetch(&self, address: Url, options: FetchOptions) -> Result<Option<AckableMessage>> {
+        let topic = get_canonical_mailbox_address_identifier(&address);
+        let manual_ack = options.manual_ack;
+        let lease = Uuid::new_v4().to_string();
+
+        let leased = lease.clone();
+        let shared = self.state.clone();
+        let fetched = with_state(self.state.clone(), move |state| {
+            let fetched = state.take(&address, &topic, &options, leased);
+            // Stale leases may have been requeued with an expiry.
+            state.arm(&shared);
+            fetched
+        }).await?;
+
+        let Some(msg) = fetched else {
+            return Ok(None);
+        };
+        if !manual_ack {
+            return Ok(Some(AckableMessage {
+                message: msg,
+                ack: Box::new(|| Box::pin(async { Ok(()) })),
+                nack: Box::new(|_| Box::pin(async { Ok(()) })),
+            }));
+        }
+
+        let msg_id = msg.id.clone();
+        let msg_id_nack = msg.id.clone();
+        let lease_nack = lease.clone();
+        let ack_state = self.state.clone();
+        let nack_state = self.state.clone();
+        let nack_owner = self.state.clone();
+
+        // Settles are only logged while this delivery still holds the lease.
+        Ok(Some(AckableMessage {
+            message: msg,
+            ack: Box::new(move || Box::pin(with_state(ack_state, move |state| {
+                if state.queue.holds(&msg_id, &lease) {
+                    let record = LogRecord::Ack { id: msg_id, lease };
+                    state.append(&record)?;
+                    state.apply(record);
+                    state.compact_if_needed();
+                }
+                Ok(())
+            }))),
+            nack: Box::new(move |requeue| Box::pin(with_state(nack_state, move |state| {
+                if state.queue.holds(&msg_id_nack, &lease_nack) {
+                    let record = LogRecord::Nack { id: msg_id_nack, lease: lease_nack, requeue };
+                    state.append(&record)?;
+                    state.apply(record);
+                    state.arm(&nack_owner);
+                    state.compact_if_needed();
+                }
+                Ok(())
+            }))),
+        }))
+    }
+
+    async fn status(&self, address: Url) -> Result<MailboxStatus> {
+        let topic = get_canonical_mailbox_address_identifier(&address);
+        let shared = self.state.clone();
+        with_state(self.state.clone(), move |state| {
+            state.release_due()?;
+            state.sweep(&topic)?;
+            state.arm(&shared);
+            let unread_count = state.queue.get_status(&topic);
+            let last_activity_time = state.last_activity.get(&topic).cloned();
+
+            let mut extra = HashMap::new();
+            extra.insert("scheduled_count".to_string(), state.queue.scheduled_count(&topic).into());
+            extra.insert("expired_count".to_string(), state.queue.expired_count(&topic).into());
+
+            Ok(MailboxStatus {
+                state: "online".to_string(),
+                unread_count: Some(unread_count),
+                last_activity_time,
+                extra,
+            })
+        }).await
+    }
+
+    async fn purge(&self, address: Url) -> Result<()> {
+        let topic = get_canonical_mailbox_address_identifier(&address);
+        with_state(self.state.clone(), move |state| {
+            let record = LogRecord::Purge { topic: topic.clone() };
+            state.append(&record)?;
+            state.apply(record);
+            state.last_activity.remove(&topic);
+            state.compact_if_needed();
+            Ok(())
+        }).await
+    }
+
+    fn generate_id(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::test_mail;
+    use serde_json::json;
+
+    fn temp_log() -> PathBuf {
+        std::env::temp_dir().join(format!("mailbox-{}.log", Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn test_messages_survive_reopen() -> Result<()> {
+        let path = temp_log();
+        let address: Url = "file:///test/inbox".parse()?;
+
+        {
+            let provider = FileProvider::open(&path)?;
+            provider.send(test_mail("msg1", &address).into()).await?;
+            provider.send(test_mail("msg2", &address).into()).await?;
+            provider.send(test_mail("msg3", &address).into()).await?;
+            let fetched = provider.fetch(address.clone(), FetchOptions::default()).await?;
+            assert_eq!(fetched.unwrap().message.id, "msg1");
+        }
+
+        let provider = FileProvider::open(&path)?;
+        assert_eq!(provider.status(address.clone()).await?.unread_count, Some(2));
+        let fetched = provider.fetch(address, FetchOptions::default()).await?;
+        assert_eq!(fetched.unwrap().message.id, "msg2");
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_purge_survives_reopen() -> Result<()> {
+        let path = temp_log();
+        let address: Url = "file:///test/replies".parse()?;
+
+        {
+            let provider = FileProvider::open(&path)?;
+            provider.send(test_mail("msg1", &address).into()).await?;
+            provider.send(test_mail("msg2", &address).into()).await?;
+            provider.purge(address.clone()).await?;
+            assert_eq!(provider.status(address.clone()).await?.unread_count, Some(0));
+        }
+
+        let provider = FileProvider::open(&path)?;
+        assert!(provider.fetch(address, FetchOptions::default()).await?.is_none());
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unacked_messages_are_restored() -> Result<()> {
+        let path = temp_log();
+        let address: Url = "file:///test/ack".parse()?;
+        let options = FetchOptions {
+            manual_ack: true,
+            ack_timeout: None,
+            ..Default::default()
+        };
+
+        {
+            let provider = FileProvider::open(&path)?;
+            provider.send(test_mail("msg1", &address).into()).await?;
+            provider.send(test_mail("msg2", &address).into()).await?;
+            provider.send(test_mail("msg3", &address).into()).await?;
+
+            let acked = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+            acked.ack().await?;
+            // Fetched but never acked: simulates a crash mid-processing.
+            let pending = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+            assert_eq!(pending.message.id, "msg2");
+            assert_eq!(provider.status(address.clone()).await?.unread_count, Some(1));
+        }
+
+        let provider = FileProvider::open(&path)?;
+        assert_eq!(provider.status(address.clone()).await?.unread_count, Some(2));
+        let fetched = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        assert_eq!(fetched.message.id, "msg2");
+        fetched.nack(true).await?;
+
+        let provider = FileProvider::open(&path)?;
+        let fetched = provider.fetch(address, options).await?.unwrap();
+        assert_eq!(fetched.message.id, "msg2");
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delivery_limit_survives_reopen() -> Result<()> {
+        let path = temp_log();
+        let address: Url = "file:///test/retry".parse()?;
+        let dlq: Url = "file:///test/retry/dlq".parse()?;
+        let options = FetchOptions::default().manual_ack().max_deliveries(3);
+
+        {
+            let provider = FileProvider::open(&path)?;
+            provider.send(test_mail("msg1", &address).into()).await?;
+            let msg = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+            assert_eq!(msg.message.meta["delivery_count"], json!(1));
+            msg.nack(true).await?;
+            // Never settled: counts as a nack on reopen.
+            let _msg = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        }
+
+        {
+            let provider = FileProvider::open(&path)?;
+            let msg = provider.fetch(address.clone(), options.clone().ack_timeout(0)).await?.unwrap();
+            assert_eq!(msg.message.meta["delivery_count"], json!(3));
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            // The lapsed lease was its last delivery.
+            assert!(provider.fetch(address.clone(), options.clone().ack_timeout(0)).await?.is_none());
+            assert_eq!(provider.status(dlq.clone()).await?.unread_count, Some(1));
+        }
+
+        let provider = FileProvider::open(&path)?;
+        assert_eq!(provider.status(address).await?.unread_count, Some(0));
+        let dead = provider.fetch(dlq, FetchOptions::default()).await?.unwrap();
+        assert_eq!(dead.message.id, "msg1");
+        assert_eq!(dead.message.meta["dead_letter_reason"], json!("ack_timeout"));
+        assert_eq!(dead.message.meta["delivery_count"], json!(3));
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stale_lease_cannot_settle() -> Result<()> {
+        let path = temp_log();
+        let address: Url = "file:///test/leases".parse()?;
+        let options = FetchOptions::default().manual_ack().ack_timeout(0);
+
+        {
+            let provider = FileProvider::open(&path)?;
+            provider.send(test_mail("msg1", &address).into()).await?;
+            let expired = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            let current = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+            assert_eq!(current.message.id, "msg1");
+
+            // The lapsed holder's ack is ignored and never logged.
+            expired.ack().await?;
+            assert!(provider.fetch(address.clone(), options.clone().ack_timeout(60_000)).await?.is_none());
+            drop(current);
+        }
+
+        // Still in flight under the second lease, so restored on reopen
+        let provider = FileProvider::open(&path)?;
+        assert_eq!(provider.status(address).await?.unread_count, Some(1));
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_log_is_compacted_while_running() -> Result<()> {
+        let path = temp_log();
+        let address: Url = "file:///test/compact".parse()?;
+        let options = FetchOptions::default().manual_ack();
+
+        {
+            let provider = FileProvider::open(&path)?;
+            provider.send(test_mail("kept", &address).into()).await?;
+            let held = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+            for i in 0..COMPACT_MIN_RECORDS {
+                provider.send(test_mail(&format!("msg{}", i), &address).into()).await?;
+                provider.fetch(address.clone(), FetchOptions::default()).await?.unwrap();
+            }
+            let lines = BufReader::new(File::open(&path)?).lines().count();
+            assert!(lines < COMPACT_MIN_RECORDS, "log has {} records", lines);
+            assert_eq!(held.message.id, "kept");
+        }
+
+        // The lease survived compaction and is restored as unacked.
+        let provider = FileProvider::open(&path)?;
+        let fetched = provider.fetch(address, options).await?.unwrap();
+        assert_eq!(fetched.message.id, "kept");
+        assert_eq!(fetched.message.meta["delivery_count"], json!(2));
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expired_send_is_not_pushed() -> Result<()> {
+        let path = temp_log();
+        let address: Url = "file:///test/late".parse()?;
+        let provider = FileProvider::open(&path)?;
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let _sub = provider.subscribe(address.clone(), Box::new(move |msg| {
+            let tx = tx.clone();
+            Box::pin(async move {
+                let _ = tx.send(msg);
+            })
+        })).await?;
+
+        let expired = test_mail("msg1", &address).expires_at(Utc::now() - chrono::Duration::seconds(1));
+        provider.send(expired.into()).await?;
+        provider.send(test_mail("msg2", &address).into()).await?;
+
+        assert_eq!(rx.recv().await.unwrap().id, "msg2");
+        let status = provider.status(address).await?;
+        assert_eq!(status.unread_count, Some(1));
+        assert_eq!(status.extra["expired_count"], json!(1));
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_messages_survive_reopen() -> Result<()> {
+        let path = temp_log();
+        let address: Url = "file:///test/scheduled".parse()?;
+        let scheduled = |id: &str, delay: u64| -> Result<MailMessage> {
+            Ok(test_mail(id, &address).deliver_after(Duration::from_millis(delay)).into())
+        };
+
+        {
+            let provider = FileProvider::open(&path)?;
+            provider.send(scheduled("msg1", 3_600_000)?).await?;
+            provider.send(scheduled("msg2", 50)?).await?;
+            assert!(provider.fetch(address.clone(), FetchOptions::default()).await?.is_none());
+        }
+
+        let provider = FileProvider::open(&path)?;
+        let status = provider.status(address.clone()).await?;
+        assert_eq!(status.unread_count, Some(0));
+        assert_eq!(status.extra["scheduled_count"], json!(2));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let fetched = provider.fetch(address.clone(), FetchOptions::default()).await?;
+        assert_eq!(fetched.unwrap().message.id, "msg2");
+
+        // The release was logged, so msg2 is not scheduled again
+        let provider = FileProvider::open(&path)?;
+        let status = provider.status(address.clone()).await?;
+        assert_eq!(status.unread_count, Some(0));
+        assert_eq!(status.extra["scheduled_count"], json!(1));
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expired_messages_are_not_replayed() -> Result<()> {
+        let path = temp_log();
+        let address: Url = "file:///test/expiring".parse()?;
+        let expiring: MailMessage = test_mail("msg1", &address).expires_after(Duration::from_millis(20)).into();
+
+        {
+            let provider = FileProvider::open(&path)?;
+            provider.send(expiring).await?;
+            provider.send(test_mail("msg2", &address).into()).await?;
+            provider.send(test_mail("msg3", &address).into()).await?;
+            let fetched = provider.fetch(address.clone(), FetchOptions::default()).await?;
+            assert_eq!(fetched.unwrap().message.id, "msg1");
+        }
+
+        // msg1 has expired by now, but its dequeue still replays
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let provider = FileProvider::open(&path)?;
+        let fetched = provider.fetch(address.clone(), FetchOptions::default()).await?;
+        assert_eq!(fetched.unwrap().message.id, "msg2");
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expired_messages_are_swept_by_timer() -> Result<()> {
+        let path = temp_log();
+        let address: Url = "file:///test/swept".parse()?;
+        let provider = FileProvider::open(&path)?;
+
+        let expiring: MailMessage = test_mail("msg1", &address).expires_after(Duration::from_millis(20)).into();
+        provider.send(expiring).await?;
+
+        // Dropped without anyone fetching from or checking the mailbox
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let topic = get_canonical_mailbox_address_identifier(&address);
+        {
+            let state = provider.state.read().unwrap();
+            assert_eq!(state.queue.get_status(&topic), 0);
+            assert_eq!(state.queue.expired_count(&topic), 1);
+        }
+        drop(provider);
+
+        // The count survives a restart.
+        let provider = FileProvider::open(&path)?;
+        assert_eq!(provider.status(address.clone()).await?.extra["expired_count"], json!(1));
+        drop(provider);
+        let provider = FileProvider::open(&path)?;
+        assert_eq!(provider.status(address).await?.extra["expired_count"], json!(1));
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_append_is_not_followed_by_records() -> Result<()> {
+        let path = temp_log();
+        let address: Url = "file:///test/failed".parse()?;
+
+        {
+            let provider = FileProvider::open(&path)?;
+            provider.send(test_mail("msg1", &address).into()).await?;
+            // As if a write failed partway and could not be cut back off
+            OpenOptions::new().append(true).open(&path)?.write_all(b"{\"op\":\"enq")?;
+            provider.state.write().unwrap().poisoned = true;
+            provider.send(test_mail("msg2", &address).into()).await?;
+        }
+
+        let provider = FileProvider::open(&path)?;
+        let fetched = provider.fetch(address.clone(), FetchOptions::default()).await?;
+        assert_eq!(fetched.unwrap().message.id, "msg1");
+        let fetched = provider.fetch(address, FetchOptions::default()).await?;
+        assert_eq!(fetched.unwrap().message.id, "msg2");
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_torn_final_record_is_ignored() -> Result<()> {
+        let path = temp_log();
+        let address: Url = "file:///test/torn".parse()?;
+
+        {
+            let provider = FileProvider::open(&path)?;
+            provider.send(test_mail("msg1", &address).into()).await?;
+        }
+        OpenOptions::new().append(true).open(&path)?.write_all(b"{\"op\":\"enq")?;
+
+        let provider = FileProvider::open(&path)?;
+        assert_eq!(provider.status(address).await?.unread_count, Some(1));
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,368 @@
+use async_trait::async_trait;
+use url::Url;
+use std::sync::Arc;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::convert::Infallible;
+use uuid::Uuid;
+use futures::future::BoxFuture;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+
+use crate::error::{MailboxError, Result};
+use crate::message::{MailMessage, MailboxStatus, FetchOptions};
+use crate::provider::{MailboxProvider, Subscription, AckableMessage};
+use crate::providers::memory::{MemoryBus, MemoryProvider, OverflowPolicy};
+use crate::utils::get_canonical_mailbox_address_identifier;
+
+impl From<reqwest::Error> for MailboxError {
+    fn from(e: reqwest::Error) -> Self {
+        MailboxError::ProviderError(format!("http: {}", e))
+    }
+}
+
+/// Inbound bodies larger than this are answered with 413 rather than read.
+pub(crate) const MAX_BODY_LEN: usize = 16 * 1024 * 1024;
+
+/// Where inbound POSTs to `path` on the listener bound at `endpoint` are queued.
+fn inbox_address(endpoint: &str, path: &str) -> Result<Url> {
+    Ok(format!("mem:{}{}", endpoint, path).parse()?)
+}
+
+fn endpoint(address: &Url) -> Result<String> {
+    let host = address.host_str()
+        .ok_or_else(|| MailboxError::InvalidAddress(address.to_string()))?;
+    let port = address.port_or_known_default()
+        .ok_or_else(|| MailboxError::InvalidAddress(address.to_string()))?;
+    Ok(format!("{}:{}", host, port))
+}
+
+/// Provider for `http:` (or, via `HttpProvider::https`, `https:`) addresses.
+///
+/// `send` POSTs the JSON-serialized `MailMessage` to `message.to` and fails
+/// on any non-2xx response. `subscribe` and `fetch` bind a plain HTTP
+/// listener on the address's host and port (once per endpoint) and receive
+/// the POSTs made to its path: bodies that are a serialized `MailMessage`
+/// are delivered as-is, any other payload is wrapped in a new message whose
+/// body is the payload and whose headers are the request headers. Inbound
+/// HTTPS needs TLS to be terminated in front of the listener. A POST to a
+/// full inbox (see `set_capacity`) is answered with 503.
+pub struct HttpProvider {
+    protocol: String,
+    client: reqwest::Client,
+    inbox: Arc<MemoryBus>,
+    local: MemoryProvider,
+    listeners: tokio::sync::Mutex<HashMap<String, (SocketAddr, JoinHandle<()>)>>,
+}
+
+impl HttpProvider {
+    pub fn new() -> Self {
+        Self::with_protocol("http")
+    }
+
+    pub fn https() -> Self {
+        Self::with_protocol("https")
+    }
+
+    fn with_protocol(protocol: &str) -> Self {
+        let inbox = Arc::new(MemoryBus::new());
+        Self {
+            protocol: protocol.to_string(),
+            client: reqwest::Client::new(),
+            local: MemoryProvider::with_bus(inbox.clone()),
+            inbox,
+            listeners: tokio::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Binds the inbound listener for `address`'s host and port if it is not
+    /// bound yet, returning the socket address it listens on.
+    pub async fn listen(&self, address: &Url) -> Result<SocketAddr> {
+        let endpoint = endpoint(address)?;
+        let mut listeners = self.listeners.lock().await;
+        if let Some((local_addr, _)) = listeners.get(&endpoint) {
+            return Ok(*local_addr);
+        }
+
+        let listener = TcpListener::bind(&endpoint).await?;
+        let local_addr = listener.local_addr()?;
+        let inbox = self.inbox.clone();
+        let key = endpoint.clone();
+
+        let task = tokio::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                let inbox = inbox.clone();
+                let endpoint = key.clone();
+                let service = service_fn(move |request| {
+                    let inbox = inbox.clone();
+                    let endpoint = endpoint.clone();
+                    async move {
+                        Ok::<_, Infallible>(handle_request(request, peer, &endpoint, &inbox).await)
+                    }
+                });
+                tokio::spawn(async move {
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+
+        listeners.insert(endpoint, (local_addr, task));
+        Ok(local_addr)
+    }
+
+    /// Bounds the unread inbound messages for `address` like
+    /// `MemoryBus::set_capacity`. `Block` cannot hold an HTTP client back,
+    /// so it rejects like `Reject`.
+    pub fn set_capacity(&self, address: &Url, capacity: usize, overflow: OverflowPolicy) -> Result<()> {
+        let inbox = inbox_address(&endpoint(address)?, address.path())?;
+        self.inbox.set_capacity(&inbox, capacity, overflow);
+        Ok(())
+    }
+
+    async fn inbox_for(&self, address: &Url) -> Result<Url> {
+        self.listen(address).await?;
+        inbox_address(&endpoint(address)?, address.path())
+    }
+}
+
+impl Default for HttpProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for HttpProvider {
+    fn drop(&mut self) {
+        for (_, task) in self.listeners.get_mut().values() {
+            task.abort();
+        }
+    }
+}
+
+fn respond(status: StatusCode, body: Value) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap()
+}
+
+async fn handle_request(
+    request: Request<Incoming>,
+    peer: SocketAddr,
+    endpoint: &str,
+    inbox: &Arc<MemoryBus>,
+) -> Response<Full<Bytes>> {
+    if request.method() != Method::POST {
+        return respond(StatusCode::METHOD_NOT_ALLOWED, json!({ "error": "only POST is accepted" }));
+    }
+
+    let path = request.uri().path().to_string();
+    let headers: HashMap<String, String> = request.headers()
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    let body = match Limited::new(request.into_body(), MAX_BODY_LEN).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) if e.is::<LengthLimitError>() => {
+            return respond(StatusCode::PAYLOAD_TOO_LARGE, json!({ "error": e.to_string() }));
+        }
+        Err(e) => return respond(StatusCode::BAD_REQUEST, json!({ "error": e.to_string() })),
+    };
+
+    let message = match serde_json::from_slice::<MailMessage>(&body) {
+        Ok(message) => message,
+        Err(_) => {
+            let to = format!("http://{}{}", endpoint, path).parse();
+            let from = format!("http://{}/", peer).parse();
+            let (Ok(to), Ok(from)) = (to, from) else {
+                return respond(StatusCode::BAD_REQUEST, json!({ "error": "invalid request address" }));
+            };
+            MailMessage {
+                id: Uuid::new_v4().to_string(),
+                from,
+                to,
+                body: serde_json::from_slice(&body)
+                    .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).into_owned())),
+                headers,
+                meta: HashMap::new(),
+            }
+        }
+    };
+
+    let topic = match inbox_address(endpoint, &path) {
+        Ok(address) => get_canonical_mailbox_address_identifier(&address),
+        Err(e) => return respond(StatusCode::BAD_REQUEST, json!({ "error": e.to_string() })),
+    };
+    let id = message.id.clone();
+    match inbox.publish(topic, message) {
+        Ok(()) => respond(StatusCode::ACCEPTED, json!({ "id": id })),
+        Err(e @ MailboxError::MailboxFull(_)) => {
+            respond(StatusCode::SERVICE_UNAVAILABLE, json!({ "error": e.to_string() }))
+        }
+        Err(e) => respond(StatusCode::INTERNAL_SERVER_ERROR, json!({ "error": e.to_string() })),
+    }
+}
+
+#[async_trait]
+impl MailboxProvider for HttpProvider {
+    fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    async fn send(&self, message: MailMessage) -> Result<MailMessage> {
+        let response = self.client
+            .post(message.to.clone())
+            .json(&message)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(MailboxError::ProviderError(format!(
+                "POST {} returned {}", message.to, status
+            )));
+        }
+
+        Ok(message)
+    }
+
+    async fn subscribe(
+        &self,
+        address: Url,
+        callback: Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>,
+    ) -> Result<Box<dyn Subscription>> {
+        let inbox = self.inbox_for(&address).await?;
+        self.local.subscribe(inbox, callback).await
+    }
+
+    async fn fetch(&self, address: Url, options: FetchOptions) -> Result<Option<AckableMessage>> {
+        let inbox = self.inbox_for(&address).await?;
+        self.local.fetch(inbox, options).await
+    }
+
+    async fn status(&self, address: Url) -> Result<MailboxStatus> {
+        let inbox = inbox_address(&endpoint(&address)?, address.path())?;
+        self.local.status(inbox).await
+    }
+
+    fn generate_id(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{test_mail, OutgoingMail};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    async fn free_port() -> Result<u16> {
+        Ok(TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port())
+    }
+
+    #[tokio::test]
+    async fn test_post_and_subscribe() -> Result<()> {
+        let receiver = HttpProvider::new();
+        let sender = HttpProvider::new();
+        let address: Url = format!("http://127.0.0.1:{}/hooks/orders", free_port().await?).parse()?;
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+
+        let _sub = receiver.subscribe(address.clone(), Box::new(move |msg| {
+            let tx = tx.clone();
+            Box::pin(async move {
+                if let Some(tx) = tx.lock().unwrap().take() {
+                    tx.send(msg).unwrap();
+                }
+            })
+        })).await?;
+
+        sender.send(OutgoingMail {
+            id: Some("msg1".to_string()),
+            from: "http://127.0.0.1:1/client".parse()?,
+            to: address.clone(),
+            body: json!({"order": 42}),
+            headers: HashMap::new(),
+            meta: HashMap::new(),
+        }.into()).await?;
+
+        let received = rx.await.unwrap();
+        assert_eq!(received.id, "msg1");
+        assert_eq!(received.to, address);
+        assert_eq!(received.body, json!({"order": 42}));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_plain_webhook_is_fetchable() -> Result<()> {
+        let receiver = HttpProvider::new();
+        let address: Url = format!("http://127.0.0.1:{}/hooks/github", free_port().await?).parse()?;
+
+        assert!(receiver.fetch(address.clone(), FetchOptions::default()).await?.is_none());
+
+        let response = reqwest::Client::new()
+            .post(address.clone())
+            .header("x-event", "push")
+            .json(&json!({"ref": "main"}))
+            .send()
+            .await?;
+        assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+
+        assert_eq!(receiver.status(address.clone()).await?.unread_count, Some(1));
+        let fetched = receiver.fetch(address, FetchOptions::default()).await?.unwrap();
+        assert_eq!(fetched.message.body, json!({"ref": "main"}));
+        assert_eq!(fetched.message.headers["x-event"], "push");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_full_inbox_rejects_post() -> Result<()> {
+        let receiver = HttpProvider::new();
+        let address: Url = format!("http://127.0.0.1:{}/hooks/full", free_port().await?).parse()?;
+        receiver.set_capacity(&address, 1, OverflowPolicy::Block(Duration::from_secs(5)))?;
+        receiver.listen(&address).await?;
+
+        let client = reqwest::Client::new();
+        let response = client.post(address.clone()).json(&json!(1)).send().await?;
+        assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+        let response = client.post(address.clone()).json(&json!(2)).send().await?;
+        assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+
+        assert_eq!(receiver.status(address).await?.unread_count, Some(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rejects_non_post_and_unreachable_targets() -> Result<()> {
+        let receiver = HttpProvider::new();
+        let address: Url = format!("http://127.0.0.1:{}/hook", free_port().await?).parse()?;
+        receiver.listen(&address).await?;
+
+        let response = reqwest::Client::new().get(address.clone()).send().await?;
+        assert_eq!(response.status(), reqwest::StatusCode::METHOD_NOT_ALLOWED);
+
+        let response = reqwest::Client::new()
+            .post(address)
+            .body(vec![b'x'; MAX_BODY_LEN + 1])
+            .send()
+            .await?;
+        assert_eq!(response.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+
+        let unreachable: Url = format!("http://127.0.0.1:{}/hook", free_port().await?).parse()?;
+        let result = HttpProvider::new().send(test_mail("msg2", &unreachable).into()).await;
+        assert!(result.is_err());
+        Ok(())
+    }
+}
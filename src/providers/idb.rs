@@ -0,0 +1,570 @@
+use async_trait::async_trait;
+use url::Url;
+use std::sync::{Arc, RwLock};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use uuid::Uuid;
+use futures::future::BoxFuture;
+use js_sys::{Array, Object, Reflect};
+use tokio::sync::{mpsc, oneshot};
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{IdbDatabase, IdbFactory, IdbObjectStore, IdbObjectStoreParameters, IdbOpenDbRequest, IdbRequest, IdbTransactionMode};
+
+use crate::error::{MailboxError, Result};
+use crate::message::{MailMessage, MailboxStatus, FetchOptions};
+use crate::provider::{MailboxProvider, Subscription, AckableMessage};
+use crate::utils::{get_canonical_mailbox_address_identifier, reject_deliver_at, reject_delivery_limit, reject_expires_at};
+
+type Listener = Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>;
+
+const MESSAGES: &str = "messages";
+const ACTIVITY: &str = "activity";
+
+fn js_error(e: JsValue) -> MailboxError {
+    MailboxError::ProviderError(format!("idb: {:?}", e))
+}
+
+/// Resolves once `request` succeeds, with its result.
+async fn done(request: &IdbRequest) -> Result<JsValue> {
+    let (tx, rx) = oneshot::channel::<Result<JsValue>>();
+    let tx = Rc::new(RefCell::new(Some(tx)));
+
+    let onsuccess = {
+        let tx = tx.clone();
+        let request = request.clone();
+        Closure::<dyn FnMut()>::new(move || {
+            if let Some(tx) = tx.borrow_mut().take() {
+                let _ = tx.send(request.result().map_err(js_error));
+            }
+        })
+    };
+    let onerror = {
+        let request = request.clone();
+        Closure::<dyn FnMut()>::new(move || {
+            if let Some(tx) = tx.borrow_mut().take() {
+                let error = match request.error() {
+                    Ok(Some(e)) => e.message(),
+                    _ => "request failed".to_string(),
+                };
+                let _ = tx.send(Err(MailboxError::ProviderError(format!("idb: {}", error))));
+            }
+        })
+    };
+    request.set_onsuccess(Some(onsuccess.as_ref().unchecked_ref()));
+    request.set_onerror(Some(onerror.as_ref().unchecked_ref()));
+
+    let result = rx.await
+        .unwrap_or_else(|_| Err(MailboxError::ProviderError("idb: request was dropped".to_string())));
+    request.set_onsuccess(None);
+    request.set_onerror(None);
+    result
+}
+
+async fn open_database(name: &str) -> Result<IdbDatabase> {
+    // `indexedDB` exists on both windows and workers.
+    let factory: IdbFactory = Reflect::get(&js_sys::global(), &"indexedDB".into())
+        .map_err(js_error)?
+        .dyn_into()
+        .map_err(|_| MailboxError::ProviderError("idb: IndexedDB is not available".to_string()))?;
+    let request: IdbOpenDbRequest = factory.open_with_u32(name, 1).map_err(js_error)?;
+
+    let onupgradeneeded = {
+        let request = request.clone();
+        Closure::<dyn FnMut()>::new(move || {
+            let Ok(db) = request.result().map(|db| db.unchecked_into::<IdbDatabase>()) else { return };
+            let params = IdbObjectStoreParameters::new();
+            params.set_key_path(&"seq".into());
+            params.set_auto_increment(true);
+            if let Ok(store) = db.create_object_store_with_optional_parameters(MESSAGES, &params) {
+                let _ = store.create_index_with_str("topic", "topic");
+            }
+            let _ = db.create_object_store(ACTIVITY);
+        })
+    };
+    request.set_onupgradeneeded(Some(onupgradeneeded.as_ref().unchecked_ref()));
+
+    let db = done(&request).await?;
+    request.set_onupgradeneeded(None);
+    Ok(db.unchecked_into())
+}
+
+fn field(record: &JsValue, name: &str) -> JsValue {
+    Reflect::get(record, &name.into()).unwrap_or(JsValue::UNDEFINED)
+}
+
+fn set_field(record: &JsValue, name: &str, value: JsValue) -> Result<()> {
+    Reflect::set(record, &name.into(), &value).map_err(js_error)?;
+    Ok(())
+}
+
+/// A leased record is available again once its deadline has passed.
+fn is_available(record: &JsValue, now: f64) -> bool {
+    match field(record, "state").as_string().as_deref() {
+        Some("pending") => true,
+        _ => field(record, "visible_at").as_f64().is_some_and(|deadline| deadline <= now),
+    }
+}
+
+/// A manual-ack lease: the token settles must present, and when it runs
+/// out (never for `None`).
+struct Lease {
+    token: String,
+    deadline: Option<f64>,
+}
+
+enum Command {
+    Enqueue {
+        topic: String,
+        message: Box<MailMessage>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Takes the next available message; `lease` is `None` to delete it and
+    /// `Some` to keep it in flight until acked or the lease runs out.
+    Fetch {
+        topic: String,
+        lease: Option<Lease>,
+        reply: oneshot::Sender<Result<Option<(f64, MailMessage)>>>,
+    },
+    /// Acks or nacks the record `seq` if it is still leased under `token`.
+    Settle {
+        seq: f64,
+        token: String,
+        requeue: bool,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Status {
+        topic: String,
+        reply: oneshot::Sender<Result<(usize, usize, Option<String>)>>,
+    },
+}
+
+struct Store {
+    db: IdbDatabase,
+}
+
+impl Store {
+    fn stores(&self, mode: IdbTransactionMode) -> Result<(IdbObjectStore, IdbObjectStore)> {
+        let names = Array::of2(&MESSAGES.into(), &ACTIVITY.into());
+        let tx = self.db.transaction_with_str_sequence_and_mode(&names, mode).map_err(js_error)?;
+        Ok((
+            tx.object_store(MESSAGES).map_err(js_error)?,
+            tx.object_store(ACTIVITY).map_err(js_error)?,
+        ))
+    }
+
+    async fn records(messages: &IdbObjectStore, topic: &str) -> Result<Array> {
+        let index = messages.index("topic").map_err(js_error)?;
+        let request = index.get_all_with_key(&topic.into()).map_err(js_error)?;
+        Ok(done(&request).await?.unchecked_into())
+    }
+
+    fn touch(activity: &IdbObjectStore, topic: &str) -> Result<()> {
+        activity.put_with_key(&chrono::Utc::now().to_rfc3339().into(), &topic.into()).map_err(js_error)?;
+        Ok(())
+    }
+
+    async fn enqueue(&self, topic: String, message: &MailMessage) -> Result<()> {
+        let (messages, activity) = self.stores(IdbTransactionMode::Readwrite)?;
+        let record: JsValue = Object::new().into();
+        set_field(&record, "topic", topic.as_str().into())?;
+        set_field(&record, "state", "pending".into())?;
+        set_field(&record, "visible_at", JsValue::NULL)?;
+        set_field(&record, "message", serde_json::to_string(message)?.into())?;
+
+        let request = messages.add(&record).map_err(js_error)?;
+        Self::touch(&activity, &topic)?;
+        done(&request).await?;
+        Ok(())
+    }
+
+    async fn fetch(&self, topic: String, lease: Option<Lease>) -> Result<Option<(f64, MailMessage)>> {
+        let (messages, activity) = self.stores(IdbTransactionMode::Readwrite)?;
+        Self::touch(&activity, &topic)?;
+
+        let now = js_sys::Date::now();
+        let Some(record) = Self::records(&messages, &topic).await?
+            .iter()
+            .find(|record| is_available(record, now)) else {
+            return Ok(None);
+        };
+
+        let seq = field(&record, "seq").as_f64()
+            .ok_or_else(|| MailboxError::ProviderError("idb: record has no key".to_string()))?;
+        let message: MailMessage = serde_json::from_str(&field(&record, "message").as_string().unwrap_or_default())?;
+
+        let request = match lease {
+            None => messages.delete(&seq.into()).map_err(js_error)?,
+            Some(lease) => {
+                set_field(&record, "state", "in_flight".into())?;
+                set_field(&record, "visible_at", lease.deadline.map(JsValue::from).unwrap_or(JsValue::NULL))?;
+                set_field(&record, "lease", lease.token.into())?;
+                messages.put(&record).map_err(js_error)?
+            }
+        };
+        done(&request).await?;
+        Ok(Some((seq, message)))
+    }
+
+    async fn settle(&self, seq: f64, token: String, requeue: bool) -> Result<()> {
+        let (messages, _) = self.stores(IdbTransactionMode::Readwrite)?;
+
+        // Another tab may have leased the record again since this lease ran
+        // out; only the current holder may settle it.
+        let record = done(&messages.get(&seq.into()).map_err(js_error)?).await?;
+        if record.is_undefined()
+            || field(&record, "state").as_string().as_deref() != Some("in_flight")
+            || field(&record, "lease").as_string() != Some(token) {
+            return Ok(());
+        }
+        if !requeue {
+            done(&messages.delete(&seq.into()).map_err(js_error)?).await?;
+            return Ok(());
+        }
+
+        // Keeping the key puts the message back at the head of its queue.
+        set_field(&record, "state", "pending".into())?;
+        set_field(&record, "visible_at", JsValue::NULL)?;
+        set_field(&record, "lease", JsValue::NULL)?;
+        done(&messages.put(&record).map_err(js_error)?).await?;
+        Ok(())
+    }
+
+    async fn status(&self, topic: String) -> Result<(usize, usize, Option<String>)> {
+        let (messages, activity) = self.stores(IdbTransactionMode::Readonly)?;
+        let now = js_sys::Date::now();
+        let records = Self::records(&messages, &topic).await?;
+        let unread = records.iter().filter(|record| is_available(record, now)).count();
+        let in_flight = records.length() as usize - unread;
+
+        let last_activity = done(&activity.get(&topic.as_str().into()).map_err(js_error)?).await?;
+        Ok((unread, in_flight, last_activity.as_string()))
+    }
+
+    async fn handle(&self, command: Command) {
+        match command {
+            Command::Enqueue { topic, message, reply } => {
+                let _ = reply.send(self.enqueue(topic, &message).await);
+            }
+            Command::Fetch { topic, lease, reply } => {
+                let _ = reply.send(self.fetch(topic, lease).await);
+            }
+            Command::Settle { seq, token, requeue, reply } => {
+                let _ = reply.send(self.settle(seq, token, requeue).await);
+            }
+            Command::Status { topic, reply } => {
+                let _ = reply.send(self.status(topic).await);
+            }
+        }
+    }
+}
+
+async fn call<T>(
+    commands: &mpsc::UnboundedSender<Command>,
+    command: impl FnOnce(oneshot::Sender<Result<T>>) -> Command,
+) -> Result<T> {
+    let (tx, rx) = oneshot::channel();
+    commands.send(command(tx))
+        .map_err(|_| MailboxError::ProviderError("idb: database is closed".to_string()))?;
+    rx.await.unwrap_or_else(|_| Err(MailboxError::ProviderError("idb: database is closed".to_string())))
+}
+
+/// Provider for `idb:` addresses persisted in the browser's IndexedDB
+/// (wasm32 only).
+///
+/// Queued and in-flight messages live in the database opened by
+/// `IdbProvider::open` and survive page reloads; a message leased by a
+/// manual-ack `fetch` is stored with its `ack_timeout` deadline (or none)
+/// and becomes fetchable again once that passes, so leases left behind by a
+/// closed tab expire like any other. Each lease stores a fresh token on the
+/// record and ack/nack only apply while it is still there, so a tab whose
+/// lease ran out cannot settle a message another tab has leased since.
+/// `nack(true)` returns a message to the head of its queue. Subscriptions are per page and are not persisted.
+pub struct IdbProvider {
+    protocol: String,
+    commands: mpsc::UnboundedSender<Command>,
+    topics: Arc<RwLock<HashMap<String, Vec<Arc<Listener>>>>>,
+}
+
+impl IdbProvider {
+    /// Opens (creating if needed) the IndexedDB database `name`.
+    pub async fn open(name: &str) -> Result<Self> {
+        let (commands, mut rx) = mpsc::unbounded_channel::<Command>();
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let name = name.to_string();
+
+        // The database handle is not `Send`, so it lives in a local task and
+        // every operation is a command handled in order.
+        wasm_bindgen_futures::spawn_local(async move {
+            let store = match open_database(&name).await {
+                Ok(db) => Store { db },
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+            let _ = ready_tx.send(Ok(()));
+            while let Some(command) = rx.recv().await {
+                store.handle(command).await;
+            }
+            store.db.close();
+        });
+
+        ready_rx.await
+            .unwrap_or_else(|_| Err(MailboxError::ProviderError("idb: open was cancelled".to_string())))?;
+
+        Ok(Self {
+            protocol: "idb".to_string(),
+            commands,
+            topics: Arc::new(RwLock::new(HashMap::new())),
+        })
+    }
+}
+
+struct IdbSubscription {
+    topics: Arc<RwLock<HashMap<String, Vec<Arc<Listener>>>>>,
+    topic: String,
+    listener: Arc<Listener>,
+}
+
+#[async_trait]
+impl Subscription for IdbSubscription {
+    async fn unsubscribe(&mut self) -> Result<()> {
+        self.unsubscribe_now();
+        Ok(())
+    }
+
+    fn unsubscribe_now(&mut self) -> bool {
+        let mut topics = self.topics.write().unwrap();
+        if let Some(listeners) = topics.get_mut(&self.topic) {
+            listeners.retain(|l| !Arc::ptr_eq(l, &self.listener));
+        }
+        true
+    }
+}
+
+#[async_trait]
+impl MailboxProvider for IdbProvider {
+    fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    async fn send(&self, message: MailMessage) -> Result<MailMessage> {
+        reject_deliver_at(&self.protocol, &message)?;
+        reject_expires_at(&self.protocol, &message)?;
+        let topic = get_canonical_mailbox_address_identifier(&message.to);
+        call(&self.commands, |reply| Command::Enqueue {
+            topic: topic.clone(),
+            message: Box::new(message.clone()),
+            reply,
+        }).await?;
+
+        let listeners = self.topics.read().unwrap().get(&topic).cloned().unwrap_or_default();
+        for listener in listeners {
+            let msg = message.clone();
+            wasm_bindgen_futures::spawn_local(async move {
+                (listener)(msg).await;
+            });
+        }
+
+        Ok(message)
+    }
+
+    async fn subscribe(
+        &self,
+        address: Url,
+        callback: Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>,
+    ) -> Result<Box<dyn Subscription>> {
+        let topic = get_canonical_mailbox_address_identifier(&address);
+        let listener = Arc::new(callback);
+        self.topics.write().unwrap()
+            .entry(topic.clone())
+            .or_default()
+            .push(listener.clone());
+
+        Ok(Box::new(IdbSubscription {
+            topics: self.topics.clone(),
+            topic,
+            listener,
+        }))
+    }
+
+    async fn fetch(&self, address: Url, options: FetchOptions) -> Result<Option<AckableMessage>> {
+        reject_delivery_limit(&self.protocol, &options)?;
+        let topic = get_canonical_mailbox_address_identifier(&address);
+        let token = Uuid::new_v4().to_string();
+        let lease = options.manual_ack.then(|| Lease {
+            token: token.clone(),
+            deadline: options.ack_timeout.map(|timeout| js_sys::Date::now() + timeout as f64),
+        });
+
+        let Some((seq, message)) = call(&self.commands, |reply| Command::Fetch { topic, lease, reply }).await? else {
+            return Ok(None);
+        };
+
+        if !options.manual_ack {
+            return Ok(Some(AckableMessage {
+                message,
+                ack: Box::new(|| Box::pin(async { Ok(()) })),
+                nack: Box::new(|_| Box::pin(async { Ok(()) })),
+            }));
+        }
+
+        let ack_commands = self.commands.clone();
+        let nack_commands = self.commands.clone();
+        let ack_token = token.clone();
+        Ok(Some(AckableMessage {
+            message,
+            ack: Box::new(move || Box::pin(async move {
+                call(&ack_commands, |reply| Command::Settle { seq, token: ack_token, requeue: false, reply }).await
+            })),
+            nack: Box::new(move |requeue| Box::pin(async move {
+                call(&nack_commands, |reply| Command::Settle { seq, token, requeue, reply }).await
+            })),
+        }))
+    }
+
+    async fn status(&self, address: Url) -> Result<MailboxStatus> {
+        let topic = get_canonical_mailbox_address_identifier(&address);
+        let (unread_count, in_flight_count, last_activity_time) =
+            call(&self.commands, |reply| Command::Status { topic, reply }).await?;
+
+        let mut extra = HashMap::new();
+        extra.insert("in_flight_count".to_string(), in_flight_count.into());
+
+        Ok(MailboxStatus {
+            state: "online".to_string(),
+            unread_count: Some(unread_count),
+            last_activity_time,
+            extra,
+        })
+    }
+
+    fn generate_id(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::test_mail;
+    use serde_json::json;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    /// `setTimeout` exists on both windows and workers.
+    async fn sleep(ms: i32) {
+        let tick = js_sys::Promise::new(&mut |resolve, _| {
+            let set_timeout: js_sys::Function = Reflect::get(&js_sys::global(), &"setTimeout".into())
+                .unwrap()
+                .unchecked_into();
+            let _ = set_timeout.call2(&JsValue::NULL, &resolve, &ms.into());
+        });
+        wasm_bindgen_futures::JsFuture::from(tick).await.unwrap();
+    }
+
+    fn database() -> String {
+        format!("mailbox-test-{}", Uuid::new_v4())
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_messages_survive_reopen() -> Result<()> {
+        let name = database();
+        let address: Url = "idb:test/inbox".parse()?;
+
+        {
+            let provider = IdbProvider::open(&name).await?;
+            provider.send(test_mail("msg1", &address).into()).await?;
+            provider.send(test_mail("msg2", &address).into()).await?;
+        }
+
+        let provider = IdbProvider::open(&name).await?;
+        let status = provider.status(address.clone()).await?;
+        assert_eq!(status.unread_count, Some(2));
+        assert!(status.last_activity_time.is_some());
+
+        let msg = provider.fetch(address.clone(), FetchOptions::default()).await?.unwrap();
+        assert_eq!(msg.message.id, "msg1");
+        assert_eq!(msg.message.body, json!({"n": "msg1"}));
+        assert_eq!(msg.message.headers["kind"], "test");
+        let msg = provider.fetch(address.clone(), FetchOptions::default()).await?.unwrap();
+        assert_eq!(msg.message.id, "msg2");
+        assert!(provider.fetch(address, FetchOptions::default()).await?.is_none());
+        Ok(())
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_in_flight_recovered_after_reopen() -> Result<()> {
+        let name = database();
+        let address: Url = "idb:test/jobs".parse()?;
+        let expiring = FetchOptions {
+            manual_ack: true,
+            ack_timeout: Some(50),
+            ..Default::default()
+        };
+        let held = FetchOptions {
+            manual_ack: true,
+            ack_timeout: None,
+            ..Default::default()
+        };
+
+        // Leased and never settled, as by a tab that was closed.
+        {
+            let provider = IdbProvider::open(&name).await?;
+            provider.send(test_mail("msg1", &address).into()).await?;
+            provider.send(test_mail("msg2", &address).into()).await?;
+            let _msg1 = provider.fetch(address.clone(), expiring.clone()).await?.unwrap();
+            let _msg2 = provider.fetch(address.clone(), held.clone()).await?.unwrap();
+        }
+
+        let provider = IdbProvider::open(&name).await?;
+        let status = provider.status(address.clone()).await?;
+        assert_eq!(status.extra["in_flight_count"], json!(2));
+
+        sleep(60).await;
+        let status = provider.status(address.clone()).await?;
+        assert_eq!(status.unread_count, Some(1));
+        assert_eq!(status.extra["in_flight_count"], json!(1));
+
+        let msg = provider.fetch(address.clone(), held.clone()).await?.unwrap();
+        assert_eq!(msg.message.id, "msg1");
+        msg.nack(true).await?;
+        let msg = provider.fetch(address.clone(), held).await?.unwrap();
+        assert_eq!(msg.message.id, "msg1");
+        msg.ack().await?;
+
+        // msg2 was leased without a timeout and stays in flight.
+        assert!(provider.fetch(address.clone(), FetchOptions::default()).await?.is_none());
+        let status = provider.status(address).await?;
+        assert_eq!(status.unread_count, Some(0));
+        assert_eq!(status.extra["in_flight_count"], json!(1));
+        Ok(())
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_stale_lease_cannot_settle() -> Result<()> {
+        let address: Url = "idb:test/leases".parse()?;
+        let provider = IdbProvider::open(&database()).await?;
+        let expiring = FetchOptions::default().manual_ack().ack_timeout(20);
+
+        provider.send(test_mail("msg1", &address).into()).await?;
+        let lapsed = provider.fetch(address.clone(), expiring.clone()).await?.unwrap();
+        sleep(40).await;
+        let current = provider.fetch(address.clone(), FetchOptions::default().manual_ack()).await?.unwrap();
+        assert_eq!(current.message.id, "msg1");
+
+        // Neither deletes nor requeues the record leased since.
+        lapsed.nack(true).await?;
+        let status = provider.status(address.clone()).await?;
+        assert_eq!(status.unread_count, Some(0));
+        assert_eq!(status.extra["in_flight_count"], json!(1));
+
+        current.ack().await?;
+        let status = provider.status(address).await?;
+        assert_eq!(status.extra["in_flight_count"], json!(0));
+        Ok(())
+    }
+}
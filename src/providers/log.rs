@@ -0,0 +1,699 @@
+use async_trait::async_trait;
+use url::Url;
+use std::sync::{Arc, RwLock};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use futures::future::BoxFuture;
+use serde_json::json;
+use tokio::sync::mpsc;
+
+use crate::error::{MailboxError, Result};
+use crate::message::{MailMessage, MailboxStatus, FetchOptions};
+use crate::provider::{MailboxProvider, Subscription, AckableMessage};
+use crate::utils::{get_canonical_mailbox_address_identifier, reject_deliver_at, reject_delivery_limit, reject_expires_at};
+
+/// Header whose value picks the partition a message is appended to.
+pub const PARTITION_KEY_HEADER: &str = "partition-key";
+
+/// Where a consumer group or subscription starts reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekPosition {
+    /// The oldest retained record of every partition.
+    Beginning,
+    /// Past the newest record of every partition.
+    End,
+    /// A specific offset within one partition.
+    Offset { partition: usize, offset: u64 },
+}
+
+struct Partition {
+    base: u64,
+    records: VecDeque<MailMessage>,
+}
+
+impl Partition {
+    fn end(&self) -> u64 {
+        self.base + self.records.len() as u64
+    }
+
+    fn get(&self, offset: u64) -> Option<&MailMessage> {
+        offset.checked_sub(self.base).and_then(|index| self.records.get(index as usize))
+    }
+}
+
+/// One `manual_ack` delivery of a record and when it is due back.
+struct Lease {
+    id: u64,
+    deadline: Option<Instant>,
+}
+
+impl Lease {
+    fn expired(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| deadline <= now)
+    }
+}
+
+/// Read positions of one consumer group.
+struct Group {
+    positions: Vec<u64>,
+    in_flight: HashMap<(usize, u64), Lease>,
+    redeliver: BTreeSet<(usize, u64)>,
+    cursor: usize,
+    next_lease: u64,
+}
+
+impl Group {
+    /// Whether lease `id` still holds `key`: it has not been settled,
+    /// replaced or reached its deadline, even if not yet reclaimed.
+    fn holds(&self, key: &(usize, u64), id: u64) -> bool {
+        self.in_flight.get(key).is_some_and(|held| held.id == id && !held.expired(Instant::now()))
+    }
+
+    fn committed(&self, partition: usize) -> u64 {
+        self.in_flight.keys()
+            .chain(self.redeliver.iter())
+            .filter(|(p, _)| *p == partition)
+            .map(|(_, offset)| *offset)
+            .fold(self.positions[partition], u64::min)
+    }
+}
+
+struct Log {
+    partitions: Vec<Partition>,
+    groups: HashMap<String, Group>,
+    subscribers: Vec<(Uuid, mpsc::UnboundedSender<MailMessage>)>,
+    next_partition: usize,
+    last_activity: Option<String>,
+}
+
+impl Log {
+    fn new(partitions: usize) -> Self {
+        Self {
+            partitions: (0..partitions).map(|_| Partition { base: 0, records: VecDeque::new() }).collect(),
+            groups: HashMap::new(),
+            subscribers: Vec::new(),
+            next_partition: 0,
+            last_activity: None,
+        }
+    }
+
+    fn positions(&self, position: SeekPosition) -> Vec<u64> {
+        self.partitions.iter()
+            .map(|partition| match position {
+                SeekPosition::End => partition.end(),
+                _ => partition.base,
+            })
+            .collect()
+    }
+
+    fn group(&mut self, name: &str, start: SeekPosition) -> &mut Group {
+        if !self.groups.contains_key(name) {
+            let positions = self.positions(start);
+            self.groups.insert(name.to_string(), Group {
+                positions,
+                in_flight: HashMap::new(),
+                redeliver: BTreeSet::new(),
+                cursor: 0,
+                next_lease: 0,
+            });
+        }
+        self.groups.get_mut(name).unwrap()
+    }
+
+    /// The record at `offset` tagged with where it lives in the log.
+    fn record(&self, partition: usize, offset: u64) -> Option<MailMessage> {
+        let mut message = self.partitions[partition].get(offset)?.clone();
+        message.meta.insert("partition".to_string(), json!(partition));
+        message.meta.insert("offset".to_string(), json!(offset));
+        Some(message)
+    }
+
+    /// Advances `group` to its next record: redeliveries first, then the
+    /// partitions in turn.
+    fn next_for(&mut self, group: &str) -> Option<(usize, u64)> {
+        let bases: Vec<u64> = self.partitions.iter().map(|p| p.base).collect();
+        let ends: Vec<u64> = self.partitions.iter().map(|p| p.end()).collect();
+        let group = self.groups.get_mut(group)?;
+
+        let now = Instant::now();
+        let expired: Vec<(usize, u64)> = group.in_flight.iter()
+            .filter(|(_, lease)| lease.expired(now))
+            .map(|(key, _)| *key)
+            .collect();
+        for key in expired {
+            group.in_flight.remove(&key);
+            group.redeliver.insert(key);
+        }
+
+        // Records trimmed by retention can no longer be redelivered.
+        group.redeliver.retain(|(partition, offset)| *offset >= bases[*partition]);
+        if let Some(next) = group.redeliver.pop_first() {
+            return Some(next);
+        }
+
+        let count = ends.len();
+        for step in 0..count {
+            let partition = (group.cursor + step) % count;
+            let position = group.positions[partition].max(bases[partition]);
+            if position < ends[partition] {
+                group.positions[partition] = position + 1;
+                group.cursor = (partition + 1) % count;
+                return Some((partition, position));
+            }
+        }
+        None
+    }
+}
+
+fn query_param(address: &Url, name: &str) -> Option<String> {
+    address.query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// The consumer group named by `?group=`, or `default`.
+fn group_for(address: &Url) -> String {
+    query_param(address, "group").unwrap_or_else(|| "default".to_string())
+}
+
+/// Where `?from=beginning|end` says a new reader starts, defaulting to the beginning.
+fn start_for(address: &Url) -> Result<SeekPosition> {
+    match query_param(address, "from").as_deref() {
+        None | Some("beginning") => Ok(SeekPosition::Beginning),
+        Some("end") => Ok(SeekPosition::End),
+        Some(_) => Err(MailboxError::InvalidAddress(format!("{} has an invalid from", address))),
+    }
+}
+
+type Logs = Arc<RwLock<HashMap<String, Log>>>;
+
+/// Provider for `log:` addresses backed by in-memory, partitioned,
+/// append-only logs.
+///
+/// `send` appends to one partition of the address's log: the partition
+/// chosen by hashing the `partition-key` header, or round-robin without one.
+/// Records are retained (up to `with_retention` per partition) rather than
+/// removed when read. `fetch` reads on behalf of the consumer group named by
+/// `?group=` (default `default`), which keeps its own offset per partition:
+/// every group sees every record, a new group starts at the oldest retained
+/// record unless `?from=end` is given, and `seek` rewinds or skips ahead.
+/// With `manual_ack`, `nack(true)` and an expired `ack_timeout` make the
+/// group read the record again; an ack or nack that arrives after its lease
+/// expired is ignored. `subscribe` delivers records in order,
+/// replaying the retained history first unless `?from=end` is given.
+/// Delivered messages carry their `partition` and `offset` in `meta`.
+pub struct LogProvider {
+    protocol: String,
+    partitions: usize,
+    retention: Option<usize>,
+    logs: Logs,
+}
+
+impl LogProvider {
+    /// Creates a provider whose logs have one partition and unlimited retention.
+    pub fn new() -> Self {
+        Self {
+            protocol: "log".to_string(),
+            partitions: 1,
+            retention: None,
+            logs: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Splits every log created from now on into `partitions` partitions.
+    pub fn with_partitions(mut self, partitions: usize) -> Self {
+        self.partitions = partitions.max(1);
+        self
+    }
+
+    /// Keeps at most `records` records per partition, trimming the oldest.
+    pub fn with_retention(mut self, records: usize) -> Self {
+        self.retention = Some(records);
+        self
+    }
+
+    /// Moves the read position of `address`'s consumer group. In-flight and
+    /// pending redeliveries of the affected partitions are discarded.
+    pub fn seek(&self, address: &Url, position: SeekPosition) -> Result<()> {
+        let topic = get_canonical_mailbox_address_identifier(address);
+        let group = group_for(address);
+        let mut logs = self.logs.write().unwrap();
+        let log = logs.entry(topic).or_insert_with(|| Log::new(self.partitions));
+
+        let positions = log.positions(position);
+        let partitions = log.partitions.len();
+        let state = log.group(&group, position);
+        match position {
+            SeekPosition::Offset { partition, offset } => {
+                if partition >= partitions {
+                    return Err(MailboxError::InvalidAddress(format!(
+                        "{} has no partition {}", address, partition
+                    )));
+                }
+                state.positions[partition] = offset;
+                state.in_flight.retain(|(p, _), _| *p != partition);
+                state.redeliver.retain(|(p, _)| *p != partition);
+            }
+            _ => {
+                state.positions = positions;
+                state.in_flight.clear();
+                state.redeliver.clear();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for LogProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct LogSubscription {
+    logs: Logs,
+    topic: String,
+    id: Uuid,
+}
+
+#[async_trait]
+impl Subscription for LogSubscription {
+    async fn unsubscribe(&mut self) -> Result<()> {
+        self.unsubscribe_now();
+        Ok(())
+    }
+
+    fn unsubscribe_now(&mut self) -> bool {
+        let mut logs = self.logs.write().unwrap();
+        if let Some(log) = logs.get_mut(&self.topic) {
+            log.subscribers.retain(|(id, _)| *id != self.id);
+        }
+        true
+    }
+}
+
+#[async_trait]
+impl MailboxProvider for LogProvider {
+    fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    async fn send(&self, message: MailMessage) -> Result<MailMessage> {
+        reject_deliver_at(&self.protocol, &message)?;
+        reject_expires_at(&self.protocol, &message)?;
+        let topic = get_canonical_mailbox_address_identifier(&message.to);
+        let mut logs = self.logs.write().unwrap();
+        let log = logs.entry(topic).or_insert_with(|| Log::new(self.partitions));
+
+        let count = log.partitions.len();
+        let partition = match message.headers.get(PARTITION_KEY_HEADER) {
+            Some(key) => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                (hasher.finish() % count as u64) as usize
+            }
+            None => {
+                let partition = log.next_partition;
+                log.next_partition = (partition + 1) % count;
+                partition
+            }
+        };
+
+        let records = &mut log.partitions[partition];
+        records.records.push_back(message.clone());
+        if let Some(retention) = self.retention {
+            while records.records.len() > retention {
+                records.records.pop_front();
+                records.base += 1;
+            }
+        }
+
+        let offset = log.partitions[partition].end() - 1;
+        if let Some(record) = log.record(partition, offset) {
+            log.subscribers.retain(|(_, tx)| tx.send(record.clone()).is_ok());
+        }
+        log.last_activity = Some(chrono::Utc::now().to_rfc3339());
+
+        Ok(message)
+    }
+
+    async fn subscribe(
+        &self,
+        address: Url,
+        callback: Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>,
+    ) -> Result<Box<dyn Subscription>> {
+        let topic = get_canonical_mailbox_address_identifier(&address);
+        let start = start_for(&address)?;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let id = Uuid::new_v4();
+
+        {
+            let mut logs = self.logs.write().unwrap();
+            let log = logs.entry(topic.clone()).or_insert_with(|| Log::new(self.partitions));
+            if start == SeekPosition::Beginning {
+                for (partition, records) in log.partitions.iter().enumerate() {
+                    for offset in records.base..records.end() {
+                        if let Some(record) = log.record(partition, offset) {
+                            let _ = tx.send(record);
+                        }
+                    }
+                }
+            }
+            log.subscribers.push((id, tx));
+        }
+
+        // One task per subscriber keeps its deliveries in log order.
+        let deliver = async move {
+            while let Some(message) = rx.recv().await {
+                callback(message).await;
+            }
+        };
+        #[cfg(not(target_arch = "wasm32"))]
+        tokio::spawn(deliver);
+        #[cfg(target_arch = "wasm32")]
+        wasm_bindgen_futures::spawn_local(deliver);
+
+        Ok(Box::new(LogSubscription {
+            logs: self.logs.clone(),
+            topic,
+            id,
+        }))
+    }
+
+    async fn fetch(&self, address: Url, options: FetchOptions) -> Result<Option<AckableMessage>> {
+        reject_delivery_limit(&self.protocol, &options)?;
+        let topic = get_canonical_mailbox_address_identifier(&address);
+        let group = group_for(&address);
+        let start = start_for(&address)?;
+        let mut logs = self.logs.write().unwrap();
+        let log = logs.entry(topic.clone()).or_insert_with(|| Log::new(self.partitions));
+
+        log.last_activity = Some(chrono::Utc::now().to_rfc3339());
+        log.group(&group, start);
+        let Some((partition, offset)) = log.next_for(&group) else {
+            return Ok(None);
+        };
+        let Some(message) = log.record(partition, offset) else {
+            return Ok(None);
+        };
+
+        if !options.manual_ack {
+            return Ok(Some(AckableMessage {
+                message,
+                ack: Box::new(|| Box::pin(async { Ok(()) })),
+                nack: Box::new(|_| Box::pin(async { Ok(()) })),
+            }));
+        }
+
+        let deadline = options.ack_timeout.map(|timeout| Instant::now() + Duration::from_millis(timeout));
+        let state = log.group(&group, start);
+        let lease = state.next_lease;
+        state.next_lease += 1;
+        state.in_flight.insert((partition, offset), Lease { id: lease, deadline });
+
+        let key = (partition, offset);
+        let ack_logs = self.logs.clone();
+        let nack_logs = self.logs.clone();
+        let ack_topic = topic.clone();
+        let ack_group = group.clone();
+
+        Ok(Some(AckableMessage {
+            message,
+            ack: Box::new(move || Box::pin(async move {
+                let mut logs = ack_logs.write().unwrap();
+                if let Some(group) = logs.get_mut(&ack_topic).and_then(|log| log.groups.get_mut(&ack_group)) {
+                    if group.holds(&key, lease) {
+                        group.in_flight.remove(&key);
+                    }
+                }
+                Ok(())
+            })),
+            nack: Box::new(move |requeue| Box::pin(async move {
+                let mut logs = nack_logs.write().unwrap();
+                if let Some(group) = logs.get_mut(&topic).and_then(|log| log.groups.get_mut(&group)) {
+                    if group.holds(&key, lease) {
+                        group.in_flight.remove(&key);
+                        if requeue {
+                            group.redeliver.insert(key);
+                        }
+                    }
+                }
+                Ok(())
+            })),
+        }))
+    }
+
+    async fn status(&self, address: Url) -> Result<MailboxStatus> {
+        let topic = get_canonical_mailbox_address_identifier(&address);
+        let group = group_for(&address);
+        let start = start_for(&address)?;
+        let logs = self.logs.read().unwrap();
+
+        let Some(log) = logs.get(&topic) else {
+            return Ok(MailboxStatus {
+                state: "online".to_string(),
+                unread_count: Some(0),
+                last_activity_time: None,
+                extra: HashMap::new(),
+            });
+        };
+
+        let ends: Vec<u64> = log.partitions.iter().map(|p| p.end()).collect();
+        let (unread_count, lag, in_flight) = match log.groups.get(&group) {
+            Some(state) => {
+                let unread = log.partitions.iter().enumerate()
+                    .map(|(p, partition)| partition.end() - state.positions[p].clamp(partition.base, partition.end()))
+                    .sum::<u64>() + state.redeliver.len() as u64;
+                // Leases past their deadline are due back, not in flight.
+                let now = Instant::now();
+                let expired = state.in_flight.values().filter(|lease| lease.expired(now)).count();
+                let lag = log.partitions.iter().enumerate()
+                    .map(|(p, partition)| partition.end() - state.committed(p).clamp(partition.base, partition.end()))
+                    .sum::<u64>();
+                (unread + expired as u64, lag, state.in_flight.len() - expired)
+            }
+            None => {
+                let unread = log.positions(start).iter().zip(&ends).map(|(start, end)| end - start).sum::<u64>();
+                (unread, unread, 0)
+            }
+        };
+
+        let mut extra = HashMap::new();
+        extra.insert("partitions".to_string(), log.partitions.len().into());
+        extra.insert("end_offsets".to_string(), json!(ends));
+        extra.insert("retained".to_string(), json!(log.partitions.iter().map(|p| p.records.len()).sum::<usize>()));
+        extra.insert("lag".to_string(), json!(lag));
+        extra.insert("in_flight_count".to_string(), in_flight.into());
+
+        Ok(MailboxStatus {
+            state: "online".to_string(),
+            unread_count: Some(unread_count as usize),
+            last_activity_time: log.last_activity.clone(),
+            extra,
+        })
+    }
+
+    fn generate_id(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::test_mail;
+    use std::sync::Mutex;
+
+    fn mail(id: &str, to: &str, key: Option<&str>) -> Result<MailMessage> {
+        let mut mail = test_mail(id, &to.parse()?);
+        if let Some(key) = key {
+            mail.headers.insert(PARTITION_KEY_HEADER.to_string(), key.to_string());
+        }
+        Ok(mail.into())
+    }
+
+    async fn fetch_ids(provider: &LogProvider, address: &str, options: FetchOptions) -> Result<Vec<String>> {
+        let mut ids = Vec::new();
+        while let Some(msg) = provider.fetch(address.parse()?, options.clone()).await? {
+            ids.push(msg.message.id.clone());
+            msg.ack().await?;
+        }
+        Ok(ids)
+    }
+
+    #[tokio::test]
+    async fn test_groups_replay_independently() -> Result<()> {
+        let provider = LogProvider::new();
+        for id in ["msg1", "msg2", "msg3"] {
+            provider.send(mail(id, "log:orders", None)?).await?;
+        }
+
+        assert_eq!(fetch_ids(&provider, "log:orders?group=billing", FetchOptions::default()).await?, ["msg1", "msg2", "msg3"]);
+        assert_eq!(fetch_ids(&provider, "log:orders?group=billing", FetchOptions::default()).await?, Vec::<String>::new());
+
+        // A new group replays the history; one starting at the end only sees new records.
+        assert_eq!(fetch_ids(&provider, "log:orders?group=projection", FetchOptions::default()).await?, ["msg1", "msg2", "msg3"]);
+        assert!(provider.fetch("log:orders?group=live&from=end".parse()?, FetchOptions::default()).await?.is_none());
+        provider.send(mail("msg4", "log:orders", None)?).await?;
+        assert_eq!(fetch_ids(&provider, "log:orders?group=live", FetchOptions::default()).await?, ["msg4"]);
+
+        provider.seek(&"log:orders?group=billing".parse()?, SeekPosition::Offset { partition: 0, offset: 2 })?;
+        assert_eq!(fetch_ids(&provider, "log:orders?group=billing", FetchOptions::default()).await?, ["msg3", "msg4"]);
+
+        let status = provider.status("log:orders?group=billing".parse()?).await?;
+        assert_eq!(status.unread_count, Some(0));
+        assert_eq!(status.extra["retained"], json!(4));
+        assert_eq!(provider.status("log:orders?group=fresh".parse()?).await?.extra["lag"], json!(4));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_manual_ack_and_retention() -> Result<()> {
+        let provider = LogProvider::new().with_partitions(4).with_retention(2);
+        for id in ["a1", "a2", "a3"] {
+            provider.send(mail(id, "log:events", Some("device-a"))?).await?;
+        }
+
+        let options = FetchOptions {
+            manual_ack: true,
+            ack_timeout: None,
+            ..Default::default()
+        };
+        let address: Url = "log:events?group=workers".parse()?;
+
+        // Retention trimmed a1; records with one key share a partition and stay ordered.
+        let a2 = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        let a3 = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        assert_eq!((a2.message.id.as_str(), a3.message.id.as_str()), ("a2", "a3"));
+        assert_eq!(a2.message.meta["partition"], a3.message.meta["partition"]);
+        assert!(provider.fetch(address.clone(), options.clone()).await?.is_none());
+        assert_eq!(provider.status(address.clone()).await?.extra["in_flight_count"], json!(2));
+
+        a2.nack(true).await?;
+        a3.ack().await?;
+        assert_eq!(provider.status(address.clone()).await?.extra["lag"], json!(2));
+
+        let again = provider.fetch(address.clone(), options).await?.unwrap();
+        assert_eq!(again.message.id, "a2");
+        again.ack().await?;
+        assert_eq!(provider.status(address).await?.extra["lag"], json!(0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delivery_limit_is_rejected() -> Result<()> {
+        let provider = LogProvider::new();
+        provider.send(mail("msg1", "log:jobs", None)?).await?;
+
+        let options = FetchOptions::default().manual_ack().max_deliveries(3);
+        assert!(provider.fetch("log:jobs".parse()?, options).await.is_err());
+        assert_eq!(provider.status("log:jobs".parse()?).await?.unread_count, Some(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_and_expiring_sends_are_rejected() -> Result<()> {
+        let provider = LogProvider::new();
+        let mut scheduled = mail("msg1", "log:jobs", None)?;
+        scheduled.headers.insert(crate::message::DELIVER_AT_HEADER.to_string(), chrono::Utc::now().to_rfc3339());
+        assert!(provider.send(scheduled).await.is_err());
+        let mut expiring = mail("msg2", "log:jobs", None)?;
+        expiring.headers.insert(crate::message::EXPIRES_AT_HEADER.to_string(), chrono::Utc::now().to_rfc3339());
+        assert!(provider.send(expiring).await.is_err());
+        assert_eq!(provider.status("log:jobs".parse()?).await?.unread_count, Some(0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_late_ack_ignores_new_lease() -> Result<()> {
+        let provider = LogProvider::new();
+        provider.send(mail("msg1", "log:jobs", None)?).await?;
+        let address: Url = "log:jobs".parse()?;
+
+        let expiring = FetchOptions {
+            manual_ack: true,
+            ack_timeout: Some(0),
+            ..Default::default()
+        };
+        let stale = provider.fetch(address.clone(), expiring).await?.unwrap();
+        let options = FetchOptions {
+            manual_ack: true,
+            ack_timeout: None,
+            ..Default::default()
+        };
+        let current = provider.fetch(address.clone(), options).await?.unwrap();
+        assert_eq!(current.message.id, "msg1");
+
+        stale.ack().await?;
+        assert_eq!(provider.status(address.clone()).await?.extra["in_flight_count"], json!(1));
+        current.ack().await?;
+        let status = provider.status(address).await?;
+        assert_eq!(status.extra["in_flight_count"], json!(0));
+        assert_eq!(status.extra["lag"], json!(0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ack_after_deadline_is_ignored() -> Result<()> {
+        let provider = LogProvider::new();
+        provider.send(mail("msg1", "log:jobs", None)?).await?;
+        let address: Url = "log:jobs".parse()?;
+
+        let expiring = FetchOptions {
+            manual_ack: true,
+            ack_timeout: Some(0),
+            ..Default::default()
+        };
+        let stale = provider.fetch(address.clone(), expiring).await?.unwrap();
+        let status = provider.status(address.clone()).await?;
+        assert_eq!(status.unread_count, Some(1));
+        assert_eq!(status.extra["in_flight_count"], json!(0));
+
+        stale.ack().await?;
+        let options = FetchOptions {
+            manual_ack: true,
+            ..Default::default()
+        };
+        let current = provider.fetch(address.clone(), options).await?.unwrap();
+        assert_eq!(current.message.id, "msg1");
+        current.ack().await?;
+        let status = provider.status(address).await?;
+        assert_eq!(status.extra["in_flight_count"], json!(0));
+        assert_eq!(status.extra["lag"], json!(0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_replays_in_order() -> Result<()> {
+        let provider = LogProvider::new();
+        provider.send(mail("msg1", "log:audit", None)?).await?;
+        provider.send(mail("msg2", "log:audit", None)?).await?;
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        let seen = received.clone();
+        let mut sub = provider.subscribe("log:audit".parse()?, Box::new(move |msg| {
+            let seen = seen.clone();
+            let tx = tx.clone();
+            Box::pin(async move {
+                let mut seen = seen.lock().unwrap();
+                seen.push(msg.id);
+                if seen.len() == 3 {
+                    if let Some(tx) = tx.lock().unwrap().take() {
+                        tx.send(()).unwrap();
+                    }
+                }
+            })
+        })).await?;
+        provider.send(mail("msg3", "log:audit", None)?).await?;
+
+        rx.await.unwrap();
+        assert_eq!(*received.lock().unwrap(), ["msg1", "msg2", "msg3"]);
+        sub.unsubscribe().await?;
+        Ok(())
+    }
+}
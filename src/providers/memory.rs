@@ -6,19 +6,73 @@ use uuid::Uuid;
 use futures::future::BoxFuture;
 use std::time::Duration;
 use once_cell::sync::Lazy;
+use dashmap::DashMap;
+use chrono::Utc;
+use tokio::sync::Notify;
 
-use crate::error::Result;
-use crate::message::{MailMessage, MailboxStatus, FetchOptions};
+use crate::error::{MailboxError, Result};
+use crate::message::{MailMessage, MailboxStatus, FetchOptions, Expirable, Prioritized, EXPIRES_AT_HEADER};
 use crate::provider::{MailboxProvider, Subscription, AckableMessage};
-use crate::utils::get_canonical_mailbox_address_identifier;
-use crate::providers::queue::MailMessageQueue;
+use crate::utils::{default_dead_letter_address, get_canonical_mailbox_address_identifier};
+use crate::providers::queue::{DeliveryLimit, MailMessageQueue};
 
 type Listener = Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>;
 
+/// What `send` does when a bounded mailbox is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Fail with `MailboxError::MailboxFull`.
+    Reject,
+    /// Discard the oldest message of the lowest priority queued to make
+    /// room, or the message being sent if it ranks below everything queued
+    /// or only scheduled messages take up the room.
+    DropOldest,
+    /// Discard the message being sent; `send` still succeeds.
+    DropNewest,
+    /// Wait up to the given time for a fetch to make room, then fail with
+    /// `MailboxError::Timeout`. On wasm32, where there are no timers, the
+    /// deadline is only checked when something wakes the sender.
+    Block(Duration),
+}
+
+impl OverflowPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OverflowPolicy::Reject => "reject",
+            OverflowPolicy::DropOldest => "drop_oldest",
+            OverflowPolicy::DropNewest => "drop_newest",
+            OverflowPolicy::Block(_) => "block",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bound {
+    capacity: usize,
+    overflow: OverflowPolicy,
+}
+
+enum Admission {
+    Accept,
+    Discard,
+    Wait(Duration),
+}
+
 struct MemoryEventBus {
     topics: HashMap<String, Vec<Arc<Listener>>>,
     queue: MailMessageQueue<MailMessage>,
     last_activity: HashMap<String, String>,
+    // Whether expired messages go to `<topic>/dlq` rather than being dropped
+    dead_letter_expired: bool,
+    // Where expired messages of a topic go instead, whatever the above says
+    expired_dead_letters: HashMap<String, String>,
+    bounds: HashMap<String, Bound>,
+    // Messages discarded by `DropOldest` and `DropNewest`, per topic
+    dropped: HashMap<String, usize>,
+    // When the bus next wakes up to deliver what is due and drop what has
+    // expired, and the task sleeping until then
+    #[cfg(not(target_arch = "wasm32"))]
+    timer: Option<(chrono::DateTime<Utc>, tokio::task::JoinHandle<()>)>,
 }
 
 impl MemoryEventBus {
@@ -27,28 +81,388 @@ impl MemoryEventBus {
             topics: HashMap::new(),
             queue: MailMessageQueue::new(),
             last_activity: HashMap::new(),
+            dead_letter_expired: false,
+            expired_dead_letters: HashMap::new(),
+            bounds: HashMap::new(),
+            dropped: HashMap::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            timer: None,
+        }
+    }
+
+    /// Decides whether one more message of `priority` fits on `topic`,
+    /// making room first under `DropOldest`. Scheduled messages count towards
+    /// the capacity, so releasing them never overfills the mailbox. A
+    /// capacity of 0 admits nothing under any policy.
+    fn admit(&mut self, topic: &str, priority: i32) -> Result<Admission> {
+        let Some(bound) = self.bounds.get(topic).copied() else {
+            return Ok(Admission::Accept);
+        };
+        if self.occupancy(topic) >= bound.capacity {
+            // Expired messages do not take up room.
+            self.refresh(topic);
+        }
+        if self.occupancy(topic) < bound.capacity {
+            return Ok(Admission::Accept);
+        }
+
+        match bound.overflow {
+            OverflowPolicy::Reject => Err(MailboxError::MailboxFull(topic.to_string())),
+            OverflowPolicy::DropOldest => {
+                while self.occupancy(topic) >= bound.capacity {
+                    *self.dropped.entry(topic.to_string()).or_default() += 1;
+                    if self.queue.evict(topic, priority).is_none() {
+                        return Ok(Admission::Discard);
+                    }
+                }
+                Ok(Admission::Accept)
+            }
+            OverflowPolicy::DropNewest => {
+                *self.dropped.entry(topic.to_string()).or_default() += 1;
+                Ok(Admission::Discard)
+            }
+            OverflowPolicy::Block(timeout) => Ok(Admission::Wait(timeout)),
+        }
+    }
+
+    /// The messages on `topic` that count towards its capacity.
+    fn occupancy(&self, topic: &str) -> usize {
+        self.queue.get_status(topic) + self.queue.scheduled_count(topic)
+    }
+
+    /// Delivers `message` on `topic`, or holds it back if it is scheduled
+    /// for later.
+    fn publish(&mut self, topic: String, message: MailMessage) {
+        self.last_activity.insert(topic.clone(), Utc::now().to_rfc3339());
+
+        if message.is_expired(Utc::now()) {
+            self.queue.expire(topic, message);
+            self.flush_dead_letters();
+            return;
+        }
+
+        if let Some(due) = message.deliver_at().filter(|due| *due > Utc::now()) {
+            self.queue.schedule(topic, message, due);
+            return;
+        }
+
+        self.notify(&topic, &message);
+
+        // Enqueue for pull consumers
+        self.queue.enqueue(topic, message);
+    }
+
+    /// Pushes `message` to the subscribers of `topic`.
+    fn notify(&self, topic: &str, message: &MailMessage) {
+        if let Some(listeners) = self.topics.get(topic) {
+            for listener in listeners {
+                let msg = message.clone();
+                let listener = listener.clone();
+
+                #[cfg(not(target_arch = "wasm32"))]
+                tokio::spawn(async move {
+                    (listener)(msg).await;
+                });
+
+                #[cfg(target_arch = "wasm32")]
+                wasm_bindgen_futures::spawn_local(async move {
+                    (listener)(msg).await;
+                });
+            }
+        }
+    }
+
+    /// Delivers the scheduled messages whose time has come.
+    fn release_due(&mut self) {
+        for id in self.queue.due(Utc::now()) {
+            if let Some((topic, message)) = self.queue.release(&id) {
+                self.last_activity.insert(topic.clone(), Utc::now().to_rfc3339());
+                self.notify(&topic, &message);
+            }
+        }
+    }
+
+    /// Releases due messages and drops expired ones.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn maintain(&mut self) {
+        self.release_due();
+        self.queue.sweep(Utc::now());
+        self.flush_dead_letters();
+    }
+
+    /// Like `maintain`, but only drops the expired messages of `topic`.
+    fn refresh(&mut self, topic: &str) {
+        self.release_due();
+        self.queue.expire_topic(topic, Utc::now());
+        self.flush_dead_letters();
+    }
+
+    /// Makes sure the bus wakes up by the time the next scheduled message is
+    /// due or the next queued one expires. One timer serves the whole bus and
+    /// is only replaced when something comes due sooner. There are no timers
+    /// on wasm32, where this happens on the next `fetch` or `status` instead.
+    fn arm(&mut self, owner: &Arc<MemoryBus>) {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let Some(at) = [self.queue.next_due(), self.queue.next_expiry()].into_iter().flatten().min() else {
+                return;
+            };
+            if self.timer.as_ref().is_some_and(|(armed, _)| *armed <= at) {
+                return;
+            }
+            if let Some((_, task)) = self.timer.take() {
+                task.abort();
+            }
+
+            let bus = Arc::downgrade(owner);
+            let delay = (at - Utc::now()).to_std().unwrap_or_default();
+            let task = tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                let Some(bus) = bus.upgrade() else { return };
+                {
+                    let mut inner = bus.inner.write().unwrap();
+                    if inner.timer.as_ref().is_some_and(|(armed, _)| *armed == at) {
+                        inner.timer = None;
+                    }
+                    inner.maintain();
+                    inner.arm(&bus);
+                }
+                bus.space.notify_waiters();
+            });
+            self.timer = Some((at, task));
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        let _ = owner;
+    }
+
+    /// Moves messages that ran out of deliveries, and expired ones if
+    /// enabled, to their dead-letter mailbox, noting why in their meta.
+    fn flush_dead_letters(&mut self) {
+        for dead in self.queue.take_dead_letters() {
+            let (topic, message) = dead.into_message();
+            self.publish(topic, message);
+        }
+
+        for (topic, mut message) in self.queue.take_expired() {
+            let dead_letter_topic = match self.expired_dead_letters.get(&topic) {
+                Some(dead_letter_topic) => dead_letter_topic.clone(),
+                None if self.dead_letter_expired => format!("{}/dlq", topic.trim_end_matches('/')),
+                None => continue,
+            };
+            // It would only expire again in the dead-letter mailbox.
+            if let Some(expires_at) = message.headers.remove(EXPIRES_AT_HEADER) {
+                message.meta.insert("expired_at".to_string(), expires_at.into());
+            }
+            message.meta.insert("dead_letter_reason".to_string(), "expired".into());
+            message.meta.insert("original_topic".to_string(), topic.clone().into());
+            message.meta.insert("dead_lettered_at".to_string(), Utc::now().to_rfc3339().into());
+            self.publish(dead_letter_topic, message);
+        }
+    }
+}
+
+/// Queues, subscribers and activity timestamps backing one or more `MemoryProvider`s.
+///
+/// Providers only see each other's messages when they hold the same bus.
+pub struct MemoryBus {
+    inner: RwLock<MemoryEventBus>,
+    // Signalled whenever messages leave a queue, for senders blocked on a full mailbox
+    space: Notify,
+}
+
+impl MemoryBus {
+    pub fn new() -> Self {
+        Self {
+            inner: RwLock::new(MemoryEventBus::new()),
+            space: Notify::new(),
+        }
+    }
+
+    /// Pushes `message` to the subscribers of `topic` and enqueues it there,
+    /// regardless of what `message.to` says. A message with a `deliver-at`
+    /// in the future is held back until then, and one past its `expires-at`
+    /// is dropped. Capacities set with `set_capacity` apply, except that a
+    /// full `Block` mailbox fails with `MailboxFull` like `Reject`: the
+    /// providers that receive messages through here cannot wait for room.
+    #[cfg(any(feature = "bc", feature = "http", feature = "mqtt"))]
+    pub(crate) fn publish(self: &Arc<Self>, topic: String, message: MailMessage) -> Result<()> {
+        if self.try_publish(&topic, &message)?.is_some() {
+            return Err(MailboxError::MailboxFull(topic));
+        }
+        Ok(())
+    }
+
+    /// Publishes `message` if `topic`'s capacity allows. If it has to wait
+    /// for room first, returns how long it may wait.
+    fn try_publish(self: &Arc<Self>, topic: &str, message: &MailMessage) -> Result<Option<Duration>> {
+        let mut bus = self.inner.write().unwrap();
+        match bus.admit(topic, message.priority())? {
+            Admission::Accept => bus.publish(topic.to_string(), message.clone()),
+            Admission::Discard => return Ok(None),
+            Admission::Wait(timeout) => return Ok(Some(timeout)),
+        }
+        bus.arm(self);
+        Ok(None)
+    }
+
+    /// Bounds the unread and scheduled messages at `address` to `capacity`, with `overflow`
+    /// deciding what `send` does once it is full.
+    pub fn set_capacity(&self, address: &Url, capacity: usize, overflow: OverflowPolicy) {
+        let topic = get_canonical_mailbox_address_identifier(address);
+        self.inner.write().unwrap().bounds.insert(topic, Bound { capacity, overflow });
+        self.space.notify_waiters();
+    }
+
+    /// Sends expired messages to `<address>/dlq` instead of dropping them.
+    pub fn set_dead_letter_expired(&self, enabled: bool) {
+        self.inner.write().unwrap().dead_letter_expired = enabled;
+    }
+
+    /// Sends the messages that expire at `address` to `dead_letter_address`
+    /// instead, whether or not `set_dead_letter_expired` is enabled.
+    pub fn set_expired_dead_letter_address(&self, address: &Url, dead_letter_address: &Url) {
+        let topic = get_canonical_mailbox_address_identifier(address);
+        let dead_letter_topic = get_canonical_mailbox_address_identifier(dead_letter_address);
+        self.inner.write().unwrap().expired_dead_letters.insert(topic, dead_letter_topic);
+    }
+
+    /// Drops what is queued at `topic` and its bookkeeping, waking blocked
+    /// senders; `MemoryProvider::purge` without the async.
+    pub(crate) fn purge(&self, topic: &str) {
+        {
+            let mut bus = self.inner.write().unwrap();
+            bus.queue.purge(topic);
+            bus.last_activity.remove(topic);
+            bus.dropped.remove(topic);
+            if bus.topics.get(topic).is_some_and(Vec::is_empty) {
+                bus.topics.remove(topic);
+            }
         }
+        self.space.notify_waiters();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn subscriber_count(&self, topic: &str) -> usize {
+        self.inner.read().unwrap().topics.get(topic).map_or(0, Vec::len)
     }
 }
 
-// Singleton instance using once_cell
-static BUS: Lazy<Arc<RwLock<MemoryEventBus>>> = Lazy::new(|| {
-    Arc::new(RwLock::new(MemoryEventBus::new()))
-});
+impl Default for MemoryBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Named buses handed out by `MemoryProvider::shared`
+static SHARED_BUSES: Lazy<DashMap<String, Arc<MemoryBus>>> = Lazy::new(DashMap::new);
 
 pub struct MemoryProvider {
     protocol: String,
+    bus: Arc<MemoryBus>,
 }
 
 impl MemoryProvider {
+    /// Creates a provider with its own isolated bus.
     pub fn new() -> Self {
+        Self::with_bus(Arc::new(MemoryBus::new()))
+    }
+
+    /// Creates a provider on the process-wide bus registered under `name`,
+    /// so every provider created with the same name sees the same mailboxes.
+    pub fn shared(name: &str) -> Self {
+        let bus = SHARED_BUSES
+            .entry(name.to_string())
+            .or_default()
+            .clone();
+        Self::with_bus(bus)
+    }
+
+    /// Creates a provider on an explicitly supplied bus.
+    pub fn with_bus(bus: Arc<MemoryBus>) -> Self {
         Self {
             protocol: "mem".to_string(),
+            bus,
+        }
+    }
+
+    pub fn bus(&self) -> Arc<MemoryBus> {
+        self.bus.clone()
+    }
+
+    /// Takes the next message on `topic` for `fetch` from the locked bus.
+    fn take(&self, bus: &mut MemoryEventBus, address: &Url, topic: &str, options: &FetchOptions) -> Option<AckableMessage> {
+        bus.release_due();
+        bus.last_activity.insert(topic.to_string(), Utc::now().to_rfc3339());
+
+        if !options.manual_ack {
+            let fetched = bus.queue.dequeue(topic);
+            bus.flush_dead_letters();
+            bus.arm(&self.bus);
+            if let Some(msg) = fetched {
+                return Some(AckableMessage {
+                    message: msg,
+                    ack: Box::new(|| Box::pin(async { Ok(()) })),
+                    nack: Box::new(|_| Box::pin(async { Ok(()) })),
+                });
+            }
+            return None;
         }
+
+        let timeout = options.ack_timeout.map(Duration::from_millis);
+        let limit = options.max_deliveries.map(|max_deliveries| {
+            let dead_letter_address = options.dead_letter_address.clone()
+                .unwrap_or_else(|| default_dead_letter_address(address));
+            DeliveryLimit {
+                max_deliveries,
+                dead_letter_topic: get_canonical_mailbox_address_identifier(&dead_letter_address),
+            }
+        });
+        let lease = Uuid::new_v4().to_string();
+        let fetched = bus.queue.dequeue_for_ack(topic, timeout, limit, lease.clone());
+        // Stale leases may just have run out of deliveries, and messages
+        // passed over may have expired.
+        bus.flush_dead_letters();
+        bus.arm(&self.bus);
+
+        if let Some(mut msg) = fetched {
+             msg.meta.insert("delivery_count".to_string(), bus.queue.delivery_count(&msg.id).into());
+             let msg_id = msg.id.clone();
+             let msg_id_nack = msg.id.clone();
+             let lease_nack = lease.clone();
+             let ack_bus = self.bus.clone();
+             let nack_bus = self.bus.clone();
+
+             // Settles only apply while this delivery still holds the lease.
+             return Some(AckableMessage {
+                 message: msg,
+                 ack: Box::new(move || Box::pin(async move {
+                     let mut bus = ack_bus.inner.write().unwrap();
+                     bus.queue.ack(&msg_id, &lease);
+                     Ok(())
+                 })),
+                 nack: Box::new(move |requeue| Box::pin(async move {
+                     let mut bus = nack_bus.inner.write().unwrap();
+                     bus.queue.nack(&msg_id_nack, &lease_nack, requeue);
+                     bus.flush_dead_letters();
+                     bus.arm(&nack_bus);
+                     Ok(())
+                 })),
+             });
+        }
+
+        None
+    }
+}
+
+impl Default for MemoryProvider {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 struct MemorySubscription {
+    bus: Arc<MemoryBus>,
     topic: String,
     listener: Arc<Listener>,
 }
@@ -56,11 +470,16 @@ struct MemorySubscription {
 #[async_trait]
 impl Subscription for MemorySubscription {
     async fn unsubscribe(&mut self) -> Result<()> {
-        let mut bus = BUS.write().unwrap();
+        self.unsubscribe_now();
+        Ok(())
+    }
+
+    fn unsubscribe_now(&mut self) -> bool {
+        let mut bus = self.bus.inner.write().unwrap();
         if let Some(listeners) = bus.topics.get_mut(&self.topic) {
             listeners.retain(|l| !Arc::ptr_eq(l, &self.listener));
         }
-        Ok(())
+        true
     }
 }
 
@@ -72,32 +491,34 @@ impl MailboxProvider for MemoryProvider {
 
     async fn send(&self, message: MailMessage) -> Result<MailMessage> {
         let topic = get_canonical_mailbox_address_identifier(&message.to);
-        let mut bus = BUS.write().unwrap();
-
-        bus.last_activity.insert(topic.clone(), chrono::Utc::now().to_rfc3339());
+        let mut deadline = None;
+        loop {
+            // Registered before checking, so room made in between is not missed.
+            let space = self.bus.space.notified();
+            futures::pin_mut!(space);
+            space.as_mut().enable();
 
-        // Push to subscribers
-        if let Some(listeners) = bus.topics.get(&topic) {
-            for listener in listeners {
-                let msg = message.clone();
-                let listener = listener.clone();
+            let Some(timeout) = self.bus.try_publish(&topic, &message)? else {
+                return Ok(message);
+            };
+            let deadline = *deadline.get_or_insert_with(|| {
+                Utc::now() + chrono::Duration::from_std(timeout).unwrap_or(chrono::Duration::MAX)
+            });
+            let Ok(remaining) = (deadline - Utc::now()).to_std() else {
+                return Err(MailboxError::Timeout(timeout));
+            };
 
-                #[cfg(not(target_arch = "wasm32"))]
-                tokio::spawn(async move {
-                    (listener)(msg).await;
-                });
+            #[cfg(not(target_arch = "wasm32"))]
+            if tokio::time::timeout(remaining, space).await.is_err() {
+                return Err(MailboxError::Timeout(timeout));
+            }
 
-                #[cfg(target_arch = "wasm32")]
-                wasm_bindgen_futures::spawn_local(async move {
-                    (listener)(msg).await;
-                });
+            #[cfg(target_arch = "wasm32")]
+            {
+                let _ = remaining;
+                space.await;
             }
         }
-
-        // Enqueue for pull consumers
-        bus.queue.enqueue(topic, message.clone());
-
-        Ok(message)
     }
 
     async fn subscribe(
@@ -106,17 +527,18 @@ impl MailboxProvider for MemoryProvider {
         callback: Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>,
     ) -> Result<Box<dyn Subscription>> {
         let topic = get_canonical_mailbox_address_identifier(&address);
-        let mut bus = BUS.write().unwrap();
+        let mut bus = self.bus.inner.write().unwrap();
 
         let listener = Arc::new(callback);
         bus.topics
             .entry(topic.clone())
-            .or_insert_with(Vec::new)
+            .or_default()
             .push(listener.clone());
 
-        bus.last_activity.insert(topic.clone(), chrono::Utc::now().to_rfc3339());
+        bus.last_activity.insert(topic.clone(), Utc::now().to_rfc3339());
 
         Ok(Box::new(MemorySubscription {
+            bus: self.bus.clone(),
             topic,
             listener,
         }))
@@ -124,56 +546,49 @@ impl MailboxProvider for MemoryProvider {
 
     async fn fetch(&self, address: Url, options: FetchOptions) -> Result<Option<AckableMessage>> {
         let topic = get_canonical_mailbox_address_identifier(&address);
-        let mut bus = BUS.write().unwrap();
-
-        bus.last_activity.insert(topic.clone(), chrono::Utc::now().to_rfc3339());
-
-        if !options.manual_ack {
-            if let Some(msg) = bus.queue.dequeue(&topic) {
-                return Ok(Some(AckableMessage {
-                    message: msg,
-                    ack: Box::new(|| Box::pin(async { Ok(()) })),
-                    nack: Box::new(|_| Box::pin(async { Ok(()) })),
-                }));
-            }
-            return Ok(None);
-        }
-
-        let timeout = options.ack_timeout.map(Duration::from_millis);
-        if let Some(msg) = bus.queue.dequeue_for_ack(&topic, timeout) {
-             let msg_id = msg.id.clone();
-             let msg_id_nack = msg.id.clone();
+        let (fetched, freed) = {
+            let mut bus = self.bus.inner.write().unwrap();
+            let occupied = bus.occupancy(&topic);
+            let fetched = self.take(&mut bus, &address, &topic, &options);
+            (fetched, bus.occupancy(&topic) < occupied)
+        };
 
-             return Ok(Some(AckableMessage {
-                 message: msg,
-                 ack: Box::new(move || Box::pin(async move {
-                     let mut bus = BUS.write().unwrap();
-                     bus.queue.ack(&msg_id);
-                     Ok(())
-                 })),
-                 nack: Box::new(move |requeue| Box::pin(async move {
-                     let mut bus = BUS.write().unwrap();
-                     bus.queue.nack(&msg_id_nack, requeue);
-                     Ok(())
-                 })),
-             }));
+        // Senders waiting on a full mailbox only retry once this fetch has
+        // made room and let go of the lock.
+        if freed {
+            self.bus.space.notify_waiters();
         }
+        Ok(fetched)
+    }
 
-        Ok(None)
+    async fn purge(&self, address: Url) -> Result<()> {
+        self.bus.purge(&get_canonical_mailbox_address_identifier(&address));
+        Ok(())
     }
 
     async fn status(&self, address: Url) -> Result<MailboxStatus> {
         let topic = get_canonical_mailbox_address_identifier(&address);
-        let bus = BUS.read().unwrap();
+        let mut bus = self.bus.inner.write().unwrap();
 
+        bus.refresh(&topic);
+        bus.arm(&self.bus);
         let unread_count = bus.queue.get_status(&topic);
         let last_activity_time = bus.last_activity.get(&topic).cloned();
 
+        let mut extra = HashMap::new();
+        extra.insert("scheduled_count".to_string(), bus.queue.scheduled_count(&topic).into());
+        extra.insert("expired_count".to_string(), bus.queue.expired_count(&topic).into());
+        if let Some(bound) = bus.bounds.get(&topic) {
+            extra.insert("capacity".to_string(), bound.capacity.into());
+            extra.insert("overflow".to_string(), bound.overflow.as_str().into());
+            extra.insert("dropped_count".to_string(), bus.dropped.get(&topic).copied().unwrap_or(0).into());
+        }
+
         Ok(MailboxStatus {
             state: "online".to_string(),
             unread_count: Some(unread_count),
             last_activity_time,
-            extra: HashMap::new(),
+            extra,
         })
     }
 
@@ -185,7 +600,7 @@ impl MailboxProvider for MemoryProvider {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::message::OutgoingMail;
+    use crate::message::{test_mail, OutgoingMail};
     use serde_json::json;
     use std::sync::Mutex;
 
@@ -227,14 +642,7 @@ mod tests {
         let provider = MemoryProvider::new();
         let address: Url = "mem:test/fetch".parse()?;
 
-        let mail = OutgoingMail {
-            id: Some("msg1".to_string()),
-            from: "mem:test/sender".parse()?,
-            to: address.clone(),
-            body: json!("content"),
-            headers: HashMap::new(),
-            meta: HashMap::new(),
-        };
+        let mail = test_mail("msg1", &address);
 
         provider.send(mail.into()).await?;
 
@@ -254,20 +662,14 @@ mod tests {
         let provider = MemoryProvider::new();
         let address: Url = "mem:test/ack".parse()?;
 
-        let mail = OutgoingMail {
-            id: Some("msg2".to_string()),
-            from: "mem:test/sender".parse()?,
-            to: address.clone(),
-            body: json!("content"),
-            headers: HashMap::new(),
-            meta: HashMap::new(),
-        };
+        let mail = test_mail("msg2", &address);
 
         provider.send(mail.into()).await?;
 
         let options = FetchOptions {
             manual_ack: true,
             ack_timeout: None,
+            ..Default::default()
         };
 
         let fetched = provider.fetch(address.clone(), options.clone()).await?;
@@ -293,20 +695,14 @@ mod tests {
         let provider = MemoryProvider::new();
         let address: Url = "mem:test/nack".parse()?;
 
-        let mail = OutgoingMail {
-            id: Some("msg3".to_string()),
-            from: "mem:test/sender".parse()?,
-            to: address.clone(),
-            body: json!("content"),
-            headers: HashMap::new(),
-            meta: HashMap::new(),
-        };
+        let mail = test_mail("msg3", &address);
 
         provider.send(mail.into()).await?;
 
         let options = FetchOptions {
             manual_ack: true,
             ack_timeout: None,
+            ..Default::default()
         };
 
         let fetched = provider.fetch(address.clone(), options.clone()).await?;
@@ -321,4 +717,391 @@ mod tests {
         assert_eq!(fetched2.unwrap().message.id, "msg3");
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_dead_letter_after_max_deliveries() -> Result<()> {
+        let provider = MemoryProvider::new();
+        let address: Url = "mem:test/jobs".parse()?;
+
+        let mail = test_mail("msg7", &address);
+
+        provider.send(mail.into()).await?;
+
+        let options = FetchOptions {
+            manual_ack: true,
+            max_deliveries: Some(2),
+            ..Default::default()
+        };
+
+        for attempt in 1..=2 {
+            let msg = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+            assert_eq!(msg.message.meta["delivery_count"], json!(attempt));
+            msg.nack(true).await?;
+        }
+
+        // Out of deliveries: moved to the default dead-letter mailbox
+        assert!(provider.fetch(address.clone(), options).await?.is_none());
+        let dead = provider.fetch("mem:test/jobs/dlq".parse()?, FetchOptions::default()).await?.unwrap();
+        assert_eq!(dead.message.id, "msg7");
+        assert_eq!(dead.message.meta["dead_letter_reason"], json!("nacked"));
+        assert_eq!(dead.message.meta["delivery_count"], json!(2));
+        assert_eq!(dead.message.meta["original_topic"], json!("mem:test/jobs"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stale_lease_cannot_settle() -> Result<()> {
+        let provider = MemoryProvider::new();
+        let address: Url = "mem:test/leases".parse()?;
+
+        let mail = test_mail("msg1", &address);
+        provider.send(mail.into()).await?;
+
+        let options = FetchOptions::default().manual_ack().ack_timeout(0).max_deliveries(2);
+        let expired = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let current = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        assert_eq!(current.message.meta["delivery_count"], json!(2));
+
+        // The first holder's lease ran out, so its nack neither requeues nor
+        // dead-letters the message the second holder is working on.
+        expired.nack(true).await?;
+        assert_eq!(provider.status(address.clone()).await?.unread_count, Some(0));
+        assert_eq!(provider.status("mem:test/leases/dlq".parse()?).await?.unread_count, Some(0));
+
+        current.nack(true).await?;
+        assert_eq!(provider.status("mem:test/leases/dlq".parse()?).await?.unread_count, Some(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_delivery() -> Result<()> {
+        let provider = MemoryProvider::new();
+        let address: Url = "mem:test/reminders".parse()?;
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        let _sub = provider.subscribe(address.clone(), Box::new(move |msg| {
+            let tx = tx.clone();
+            Box::pin(async move {
+                if let Some(tx) = tx.lock().unwrap().take() {
+                    tx.send(msg).unwrap();
+                }
+            })
+        })).await?;
+
+        let mail = test_mail("msg8", &address).deliver_after(Duration::from_millis(50));
+
+        provider.send(mail.into()).await?;
+
+        // Invisible until due
+        assert!(provider.fetch(address.clone(), FetchOptions::default()).await?.is_none());
+        let status = provider.status(address.clone()).await?;
+        assert_eq!(status.unread_count, Some(0));
+        assert_eq!(status.extra["scheduled_count"], json!(1));
+
+        let received = tokio::time::timeout(Duration::from_secs(1), rx).await.unwrap().unwrap();
+        assert_eq!(received.id, "msg8");
+        let fetched = provider.fetch(address.clone(), FetchOptions::default()).await?;
+        assert_eq!(fetched.unwrap().message.id, "msg8");
+        assert_eq!(provider.status(address).await?.extra["scheduled_count"], json!(0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expired_messages_are_dropped() -> Result<()> {
+        let provider = MemoryProvider::new();
+        let address: Url = "mem:test/commands".parse()?;
+
+        for (id, ttl) in [("msg9", 20), ("msg10", 60_000)] {
+            let mail = test_mail(id, &address).expires_after(Duration::from_millis(ttl));
+            provider.send(mail.into()).await?;
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let status = provider.status(address.clone()).await?;
+        assert_eq!(status.unread_count, Some(1));
+        assert_eq!(status.extra["expired_count"], json!(1));
+
+        let fetched = provider.fetch(address.clone(), FetchOptions::default()).await?;
+        assert_eq!(fetched.unwrap().message.id, "msg10");
+        assert!(provider.fetch(address, FetchOptions::default()).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expired_messages_can_be_dead_lettered() -> Result<()> {
+        let provider = MemoryProvider::new();
+        provider.bus().set_dead_letter_expired(true);
+        let address: Url = "mem:test/stale".parse()?;
+
+        let mail = test_mail("msg11", &address).expires_at(Utc::now() - chrono::Duration::seconds(1));
+
+        provider.send(mail.into()).await?;
+
+        assert!(provider.fetch(address.clone(), FetchOptions::default()).await?.is_none());
+        let dead = provider.fetch("mem:test/stale/dlq".parse()?, FetchOptions::default()).await?.unwrap();
+        assert_eq!(dead.message.id, "msg11");
+        assert_eq!(dead.message.meta["dead_letter_reason"], json!("expired"));
+        assert_eq!(dead.message.meta["original_topic"], json!("mem:test/stale"));
+        assert!(dead.message.expires_at().is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expired_dead_letter_address() -> Result<()> {
+        let provider = MemoryProvider::new();
+        let address: Url = "mem:test/offers".parse()?;
+        let dead_letter_address: Url = "mem:test/expired-offers".parse()?;
+        provider.bus().set_expired_dead_letter_address(&address, &dead_letter_address);
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        let _sub = provider.subscribe(dead_letter_address, Box::new(move |msg| {
+            let tx = tx.clone();
+            Box::pin(async move {
+                if let Some(tx) = tx.lock().unwrap().take() {
+                    tx.send(msg).unwrap();
+                }
+            })
+        })).await?;
+
+        let mail = test_mail("msg24", &address).expires_after(Duration::from_millis(20));
+        provider.send(mail.into()).await?;
+
+        // Nothing fetches from the mailbox; the bus's timer expires the message.
+        let dead = tokio::time::timeout(Duration::from_secs(1), rx).await.unwrap().unwrap();
+        assert_eq!(dead.id, "msg24");
+        assert_eq!(dead.meta["dead_letter_reason"], json!("expired"));
+        assert_eq!(dead.meta["original_topic"], json!("mem:test/offers"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_priorities() -> Result<()> {
+        let provider = MemoryProvider::new();
+        let address: Url = "mem:test/actor".parse()?;
+        let send = |id: &str, priority: i32| {
+            let mail = test_mail(id, &address).priority(priority);
+            provider.send(mail.into())
+        };
+        let options = FetchOptions {
+            manual_ack: true,
+            ..Default::default()
+        };
+
+        for (id, priority) in [("bulk1", 0), ("bulk2", 0), ("shutdown", 10), ("bulk3", 0), ("reconfigure", 10)] {
+            send(id, priority).await?;
+        }
+
+        // Higher priority first, FIFO within a priority
+        for id in ["shutdown", "reconfigure"] {
+            let msg = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+            assert_eq!(msg.message.id, id);
+            msg.ack().await?;
+        }
+
+        // A requeued message goes back ahead of its own priority only
+        let bulk1 = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        assert_eq!(bulk1.message.id, "bulk1");
+        send("ping", 5).await?;
+        bulk1.nack(true).await?;
+
+        for id in ["ping", "bulk1", "bulk2", "bulk3"] {
+            let msg = provider.fetch(address.clone(), FetchOptions::default()).await?.unwrap();
+            assert_eq!(msg.message.id, id);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bounded_mailboxes() -> Result<()> {
+        let provider = MemoryProvider::new();
+        let mail = |id: &str, to: &str| -> Result<MailMessage> {
+            Ok(test_mail(id, &to.parse()?).into())
+        };
+
+        for (address, overflow) in [
+            ("mem:test/reject", OverflowPolicy::Reject),
+            ("mem:test/oldest", OverflowPolicy::DropOldest),
+            ("mem:test/newest", OverflowPolicy::DropNewest),
+        ] {
+            provider.bus().set_capacity(&address.parse()?, 2, overflow);
+            provider.send(mail("msg1", address)?).await?;
+            provider.send(mail("msg2", address)?).await?;
+        }
+
+        assert!(matches!(
+            provider.send(mail("msg3", "mem:test/reject")?).await,
+            Err(MailboxError::MailboxFull(_))
+        ));
+        provider.send(mail("msg3", "mem:test/oldest")?).await?;
+        provider.send(mail("msg3", "mem:test/newest")?).await?;
+
+        for (address, first) in [("mem:test/reject", "msg1"), ("mem:test/oldest", "msg2"), ("mem:test/newest", "msg1")] {
+            let address: Url = address.parse()?;
+            let status = provider.status(address.clone()).await?;
+            assert_eq!(status.unread_count, Some(2));
+            assert_eq!(status.extra["capacity"], json!(2));
+            let fetched = provider.fetch(address, FetchOptions::default()).await?;
+            assert_eq!(fetched.unwrap().message.id, first);
+        }
+        let status = provider.status("mem:test/oldest".parse()?).await?;
+        assert_eq!(status.extra["overflow"], json!("drop_oldest"));
+        assert_eq!(status.extra["dropped_count"], json!(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bounded_mailbox_blocks_until_fetched() -> Result<()> {
+        let provider = Arc::new(MemoryProvider::new());
+        let address: Url = "mem:test/block".parse()?;
+        provider.bus().set_capacity(&address, 1, OverflowPolicy::Block(Duration::from_secs(5)));
+
+        let mail = |id: &str| -> MailMessage { test_mail(id, &address).into() };
+
+        provider.send(mail("msg1")).await?;
+        let blocked = tokio::spawn({
+            let provider = provider.clone();
+            let msg = mail("msg2");
+            async move { provider.send(msg).await }
+        });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!blocked.is_finished());
+
+        let fetched = provider.fetch(address.clone(), FetchOptions::default()).await?;
+        assert_eq!(fetched.unwrap().message.id, "msg1");
+        tokio::time::timeout(Duration::from_secs(1), blocked).await.unwrap().unwrap()?;
+        let fetched = provider.fetch(address, FetchOptions::default()).await?;
+        assert_eq!(fetched.unwrap().message.id, "msg2");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_keeps_higher_priorities() -> Result<()> {
+        let provider = MemoryProvider::new();
+        let address: Url = "mem:test/ranked".parse()?;
+        provider.bus().set_capacity(&address, 3, OverflowPolicy::DropOldest);
+        let send = |id: &str, priority: i32| {
+            let mail = test_mail(id, &address).priority(priority);
+            provider.send(mail.into())
+        };
+
+        send("urgent", 10).await?;
+        send("bulk1", 0).await?;
+        send("bulk2", 0).await?;
+        send("bulk3", 0).await?;
+        send("urgent2", 10).await?;
+
+        for id in ["urgent", "urgent2", "bulk3"] {
+            let msg = provider.fetch(address.clone(), FetchOptions::default()).await?.unwrap();
+            assert_eq!(msg.message.id, id);
+        }
+        let status = provider.status(address).await?;
+        assert_eq!(status.extra["dropped_count"], json!(2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_discards_lower_priority_sends() -> Result<()> {
+        let provider = MemoryProvider::new();
+        let address: Url = "mem:test/outranked".parse()?;
+        provider.bus().set_capacity(&address, 1, OverflowPolicy::DropOldest);
+        let send = |id: &str, priority: i32| {
+            let mail = test_mail(id, &address).priority(priority);
+            provider.send(mail.into())
+        };
+
+        send("urgent", 10).await?;
+        send("bulk", 0).await?;
+
+        let msg = provider.fetch(address.clone(), FetchOptions::default()).await?.unwrap();
+        assert_eq!(msg.message.id, "urgent");
+        assert!(provider.fetch(address.clone(), FetchOptions::default()).await?.is_none());
+        assert_eq!(provider.status(address).await?.extra["dropped_count"], json!(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_zero_capacity_admits_nothing() -> Result<()> {
+        let provider = MemoryProvider::new();
+
+        for overflow in [OverflowPolicy::Reject, OverflowPolicy::DropOldest, OverflowPolicy::DropNewest] {
+            let address: Url = format!("mem:test/closed/{}", overflow.as_str()).parse()?;
+            provider.bus().set_capacity(&address, 0, overflow);
+            let _ = provider.send(test_mail("msg1", &address).into()).await;
+            assert_eq!(provider.status(address).await?.unread_count, Some(0));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_messages_count_towards_capacity() -> Result<()> {
+        let provider = MemoryProvider::new();
+        let address: Url = "mem:test/later".parse()?;
+        provider.bus().set_capacity(&address, 1, OverflowPolicy::Reject);
+        let mail = |id: &str| test_mail(id, &address);
+
+        provider.send(mail("msg25").deliver_after(Duration::from_millis(20)).into()).await?;
+        assert!(matches!(
+            provider.send(mail("msg26").into()).await,
+            Err(MailboxError::MailboxFull(_))
+        ));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let status = provider.status(address).await?;
+        assert_eq!(status.unread_count, Some(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blocked_send_times_out() -> Result<()> {
+        let provider = MemoryProvider::new();
+        let address: Url = "mem:test/stuck".parse()?;
+        provider.bus().set_capacity(&address, 1, OverflowPolicy::Block(Duration::from_millis(20)));
+        let mail = |id: &str| -> MailMessage {
+            test_mail(id, &address).into()
+        };
+
+        provider.send(mail("msg27")).await?;
+        let sent = tokio::time::timeout(Duration::from_secs(1), provider.send(mail("msg28"))).await.unwrap();
+        assert!(matches!(sent, Err(MailboxError::Timeout(_))));
+        assert_eq!(provider.status(address).await?.unread_count, Some(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_providers_are_isolated_by_default() -> Result<()> {
+        let first = MemoryProvider::new();
+        let second = MemoryProvider::new();
+        let address: Url = "mem:test/isolated".parse()?;
+
+        let mail = test_mail("msg4", &address);
+
+        first.send(mail.into()).await?;
+
+        assert!(second.fetch(address.clone(), FetchOptions::default()).await?.is_none());
+        assert!(first.fetch(address, FetchOptions::default()).await?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shared_and_explicit_buses() -> Result<()> {
+        let first = MemoryProvider::shared("test_shared_and_explicit_buses");
+        let second = MemoryProvider::shared("test_shared_and_explicit_buses");
+        let third = MemoryProvider::with_bus(first.bus());
+        let address: Url = "mem:test/shared".parse()?;
+
+        for id in ["msg5", "msg6"] {
+            let mail = test_mail(id, &address);
+            first.send(mail.into()).await?;
+        }
+
+        let fetched = second.fetch(address.clone(), FetchOptions::default()).await?;
+        assert_eq!(fetched.unwrap().message.id, "msg5");
+        let fetched = third.fetch(address, FetchOptions::default()).await?;
+        assert_eq!(fetched.unwrap().message.id, "msg6");
+        Ok(())
+    }
 }
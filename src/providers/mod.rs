@@ -1,2 +1,29 @@
 pub mod memory;
 pub mod queue;
+pub mod log;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod file;
+#[cfg(all(feature = "sqlite", not(target_arch = "wasm32")))]
+pub mod sqlite;
+#[cfg(any(not(target_arch = "wasm32"), feature = "ws"))]
+pub(crate) mod remote;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tcp;
+#[cfg(all(feature = "http", not(target_arch = "wasm32")))]
+pub mod http;
+#[cfg(feature = "ws")]
+pub mod ws;
+#[cfg(unix)]
+pub mod unix;
+#[cfg(all(feature = "redis", not(target_arch = "wasm32")))]
+pub mod redis;
+#[cfg(all(feature = "mqtt", not(target_arch = "wasm32")))]
+pub mod mqtt;
+#[cfg(all(feature = "amqp", not(target_arch = "wasm32")))]
+pub mod amqp;
+#[cfg(all(feature = "nats", not(target_arch = "wasm32")))]
+pub mod nats;
+#[cfg(all(feature = "idb", target_arch = "wasm32"))]
+pub mod idb;
+#[cfg(all(feature = "bc", target_arch = "wasm32"))]
+pub mod bc;
@@ -0,0 +1,687 @@
+use async_trait::async_trait;
+use url::Url;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::HashMap;
+use std::time::Duration;
+use uuid::Uuid;
+use futures::future::BoxFuture;
+use percent_encoding::percent_decode_str;
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
+use serde_json::Value;
+use tokio::task::JoinHandle;
+
+use crate::error::{MailboxError, Result};
+use crate::message::{MailMessage, MailboxStatus, FetchOptions};
+use crate::provider::{MailboxProvider, Subscription, AckableMessage};
+use crate::providers::memory::{MemoryBus, MemoryProvider};
+use crate::utils::get_canonical_mailbox_address_identifier;
+
+impl From<rumqttc::ClientError> for MailboxError {
+    fn from(e: rumqttc::ClientError) -> Self {
+        MailboxError::ProviderError(format!("mqtt: {}", e))
+    }
+}
+
+impl From<rumqttc::ConnectionError> for MailboxError {
+    fn from(e: rumqttc::ConnectionError) -> Self {
+        MailboxError::ProviderError(format!("mqtt: {}", e))
+    }
+}
+
+impl From<rumqttc::v5::ClientError> for MailboxError {
+    fn from(e: rumqttc::v5::ClientError) -> Self {
+        MailboxError::ProviderError(format!("mqtt: {}", e))
+    }
+}
+
+impl From<rumqttc::v5::ConnectionError> for MailboxError {
+    fn from(e: rumqttc::v5::ConnectionError) -> Self {
+        MailboxError::ProviderError(format!("mqtt: {}", e))
+    }
+}
+
+/// Protocol version spoken to the broker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MqttVersion {
+    V311,
+    V5,
+}
+
+/// The MQTT topic or filter named by a `mqtt:` address.
+///
+/// The path is percent-decoded with any leading `/` removed, so
+/// `mqtt:devices/42/inbox` is the topic `devices/42/inbox`. A `#` wildcard
+/// can be written as `%23` or, at the end, as a bare `#`
+/// (`mqtt:devices/#`), which URL parsing would otherwise treat as a fragment.
+pub fn topic_for(address: &Url) -> Result<String> {
+    let path = address.path().trim_start_matches('/');
+    let mut topic = percent_decode_str(path)
+        .decode_utf8()
+        .map_err(|_| MailboxError::InvalidAddress(address.to_string()))?
+        .into_owned();
+    if address.fragment() == Some("") {
+        topic.push('#');
+    }
+
+    if topic.is_empty() {
+        return Err(MailboxError::InvalidAddress(address.to_string()));
+    }
+    Ok(topic)
+}
+
+/// Whether `topic` is matched by the subscription `filter`, honouring the
+/// `+` (one level) and `#` (remaining levels) wildcards.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    // Filters starting with a wildcard never match `$SYS`-style topics.
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+
+    let mut levels = topic.split('/');
+    for part in filter.split('/') {
+        match (part, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (part, Some(level)) if part == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+fn query_param(address: &Url, name: &str) -> Option<String> {
+    address.query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// The QoS requested with `?qos=0|1|2`, defaulting to 1.
+fn qos_for(address: &Url) -> Result<u8> {
+    match query_param(address, "qos").as_deref() {
+        None => Ok(1),
+        Some(value @ ("0" | "1" | "2")) => Ok(value.parse().unwrap()),
+        Some(_) => Err(MailboxError::InvalidAddress(format!("{} has an invalid qos", address))),
+    }
+}
+
+/// The `scheme:` address whose path is `topic`, percent-encoding `#`.
+fn topic_address(scheme: &str, topic: &str) -> Result<Url> {
+    let mut address: Url = format!("{}:", scheme).parse()?;
+    address.set_path(topic);
+    Ok(address)
+}
+
+fn inbox_address(filter: &str) -> Result<Url> {
+    topic_address("mem", filter)
+}
+
+enum Client {
+    V311(rumqttc::AsyncClient),
+    V5(rumqttc::v5::AsyncClient),
+}
+
+impl Client {
+    async fn publish(
+        &self,
+        topic: String,
+        qos: u8,
+        retain: bool,
+        payload: Vec<u8>,
+        message: &MailMessage,
+    ) -> Result<()> {
+        match self {
+            Client::V311(client) => {
+                client.publish(topic, v4_qos(qos), retain, payload).await?;
+            }
+            Client::V5(client) => {
+                let properties = PublishProperties {
+                    content_type: Some("application/json".to_string()),
+                    response_topic: topic_for(&message.from).ok()
+                        .filter(|_| message.from.scheme() == "mqtt"),
+                    user_properties: message.headers.clone().into_iter().collect(),
+                    ..Default::default()
+                };
+                client.publish_with_properties(topic, v5_qos(qos), retain, payload, properties).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&self, filter: &str, qos: u8) -> Result<()> {
+        match self {
+            Client::V311(client) => client.subscribe(filter, v4_qos(qos)).await?,
+            Client::V5(client) => client.subscribe(filter, v5_qos(qos)).await?,
+        }
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, filter: &str) -> Result<()> {
+        match self {
+            Client::V311(client) => client.unsubscribe(filter).await?,
+            Client::V5(client) => client.unsubscribe(filter).await?,
+        }
+        Ok(())
+    }
+
+    fn try_unsubscribe(&self, filter: &str) -> bool {
+        match self {
+            Client::V311(client) => client.try_unsubscribe(filter).is_ok(),
+            Client::V5(client) => client.try_unsubscribe(filter).is_ok(),
+        }
+    }
+
+    fn resubscribe(&self, filters: &HashMap<String, Filter>) {
+        for (filter, state) in filters {
+            // A full request queue only happens while offline; the next
+            // ConnAck resubscribes again.
+            match self {
+                Client::V311(client) => { let _ = client.try_subscribe(filter, v4_qos(state.qos)); }
+                Client::V5(client) => { let _ = client.try_subscribe(filter, v5_qos(state.qos)); }
+            }
+        }
+    }
+
+    fn clone_handle(&self) -> Self {
+        match self {
+            Client::V311(client) => Client::V311(client.clone()),
+            Client::V5(client) => Client::V5(client.clone()),
+        }
+    }
+}
+
+fn v4_qos(qos: u8) -> rumqttc::QoS {
+    match qos {
+        0 => rumqttc::QoS::AtMostOnce,
+        1 => rumqttc::QoS::AtLeastOnce,
+        _ => rumqttc::QoS::ExactlyOnce,
+    }
+}
+
+fn v5_qos(qos: u8) -> rumqttc::v5::mqttbytes::QoS {
+    match qos {
+        0 => rumqttc::v5::mqttbytes::QoS::AtMostOnce,
+        1 => rumqttc::v5::mqttbytes::QoS::AtLeastOnce,
+        _ => rumqttc::v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+/// A filter subscribed on the broker and the inbox topic it delivers to.
+///
+/// It stays subscribed while it has live subscriptions or is captured for
+/// `fetch`, and is unsubscribed on the broker once neither holds.
+struct Filter {
+    qos: u8,
+    inbox: String,
+    subscriptions: usize,
+    captured: bool,
+}
+
+impl Filter {
+    fn in_use(&self) -> bool {
+        self.subscriptions > 0 || self.captured
+    }
+}
+
+/// State shared between the provider and its event loop task.
+struct Shared {
+    inbox: Arc<MemoryBus>,
+    filters: RwLock<HashMap<String, Filter>>,
+    /// Held while a filter is added or removed, so its SUBSCRIBE and
+    /// UNSUBSCRIBE reach the broker in the same order as the changes.
+    changes: tokio::sync::Mutex<()>,
+    online: AtomicBool,
+}
+
+impl Shared {
+    /// Drops a subscription's or the capture's hold on `filter`. Once nothing
+    /// holds it, it is forgotten and its inbox emptied, returning `true` so
+    /// the caller unsubscribes it on the broker.
+    fn forget(&self, filter: &str, subscription: bool) -> bool {
+        let removed = {
+            let mut filters = self.filters.write().unwrap();
+            let Some(state) = filters.get_mut(filter) else { return false };
+            if subscription {
+                state.subscriptions = state.subscriptions.saturating_sub(1);
+            } else {
+                state.captured = false;
+            }
+            if state.in_use() {
+                return false;
+            }
+            filters.remove(filter)
+        };
+        if let Some(removed) = removed {
+            self.inbox.purge(&removed.inbox);
+        }
+        true
+    }
+
+    /// `forget`, then unsubscribing `filter` on the broker if it went away.
+    async fn release(&self, client: &Client, filter: &str, subscription: bool) -> Result<()> {
+        let _changes = self.changes.lock().await;
+        if self.forget(filter, subscription) {
+            client.unsubscribe(filter).await?;
+        }
+        Ok(())
+    }
+
+    /// Delivers a publish on `topic` to the inbox of every matching filter.
+    fn dispatch(&self, topic: &str, payload: &[u8], properties: Option<PublishProperties>) {
+        let (headers, response_topic) = match properties {
+            Some(properties) => (properties.user_properties, properties.response_topic),
+            None => (Vec::new(), None),
+        };
+
+        let message = match serde_json::from_slice::<MailMessage>(payload) {
+            Ok(mut message) => {
+                for (name, value) in headers {
+                    message.headers.entry(name).or_insert(value);
+                }
+                message
+            }
+            Err(_) => {
+                let Ok(to) = topic_address("mqtt", topic) else { return };
+                let from = response_topic
+                    .and_then(|reply| topic_address("mqtt", &reply).ok())
+                    .unwrap_or_else(|| to.clone());
+                MailMessage {
+                    id: Uuid::new_v4().to_string(),
+                    from,
+                    to,
+                    body: serde_json::from_slice(payload)
+                        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(payload).into_owned())),
+                    headers: headers.into_iter().collect(),
+                    meta: HashMap::new(),
+                }
+            }
+        };
+
+        let filters = self.filters.read().unwrap();
+        for (filter, state) in filters.iter() {
+            if topic_matches(filter, topic) {
+                // The broker has already handed it over, so a full inbox drops it.
+                let _ = self.inbox.publish(state.inbox.clone(), message.clone());
+            }
+        }
+    }
+}
+
+/// Provider for `mqtt:` addresses on an MQTT 3.1.1 or 5 broker.
+///
+/// The address path is the MQTT topic (see `topic_for`); `?qos=` selects the
+/// QoS of publishes and subscriptions (default 1) and `?retain=true` makes a
+/// publish retained. `send` publishes the JSON-serialized `MailMessage`;
+/// over MQTT 5 its headers also travel as user properties and an `mqtt:`
+/// sender as the response topic. `subscribe` and `fetch` subscribe the
+/// filter on the broker and receive what is published to it from then on:
+/// serialized `MailMessage`s as-is, any other payload wrapped in a new
+/// message whose headers are the user properties. Subscriptions are
+/// restored after a reconnect.
+///
+/// A filter stays subscribed on the broker while any `subscribe` for it is
+/// live or it is captured for `fetch`, and is unsubscribed once neither
+/// holds. The first `fetch` of a filter captures it, so anything published
+/// before that is missed; call `capture` to start queueing for a fetch-only
+/// mailbox up front. `purge` ends the capture and drops what it queued.
+pub struct MqttProvider {
+    protocol: String,
+    client: Client,
+    shared: Arc<Shared>,
+    local: MemoryProvider,
+    task: JoinHandle<()>,
+}
+
+impl MqttProvider {
+    /// Connects to the broker at `host:port`, returning once the broker has
+    /// accepted the session.
+    pub async fn connect(host: &str, port: u16, version: MqttVersion) -> Result<Self> {
+        let client_id = format!("mailbox-{}", Uuid::new_v4().simple());
+        let inbox = Arc::new(MemoryBus::new());
+        let shared = Arc::new(Shared {
+            inbox: inbox.clone(),
+            filters: RwLock::new(HashMap::new()),
+            changes: tokio::sync::Mutex::new(()),
+            online: AtomicBool::new(true),
+        });
+
+        let (client, task) = match version {
+            MqttVersion::V311 => {
+                use rumqttc::{Event, Packet};
+
+                let mut options = rumqttc::MqttOptions::new(client_id, host, port);
+                options.set_keep_alive(Duration::from_secs(30));
+                let (client, mut eventloop) = rumqttc::AsyncClient::new(options, 64);
+                loop {
+                    if let Event::Incoming(Packet::ConnAck(_)) = eventloop.poll().await? {
+                        break;
+                    }
+                }
+
+                let client = Client::V311(client);
+                let handle = client.clone_handle();
+                let shared = shared.clone();
+                let task = tokio::spawn(async move {
+                    loop {
+                        match eventloop.poll().await {
+                            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                                shared.online.store(true, Ordering::SeqCst);
+                                handle.resubscribe(&shared.filters.read().unwrap());
+                            }
+                            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                                shared.dispatch(&publish.topic, &publish.payload, None);
+                            }
+                            Ok(_) => {}
+                            Err(_) => {
+                                shared.online.store(false, Ordering::SeqCst);
+                                tokio::time::sleep(Duration::from_secs(1)).await;
+                            }
+                        }
+                    }
+                });
+                (client, task)
+            }
+            MqttVersion::V5 => {
+                use rumqttc::v5::Event;
+                use rumqttc::v5::mqttbytes::v5::Packet;
+
+                let mut options = rumqttc::v5::MqttOptions::new(client_id, host, port);
+                options.set_keep_alive(Duration::from_secs(30));
+                let (client, mut eventloop) = rumqttc::v5::AsyncClient::new(options, 64);
+                loop {
+                    if let Event::Incoming(Packet::ConnAck(_)) = eventloop.poll().await? {
+                        break;
+                    }
+                }
+
+                let client = Client::V5(client);
+                let handle = client.clone_handle();
+                let shared = shared.clone();
+                let task = tokio::spawn(async move {
+                    loop {
+                        match eventloop.poll().await {
+                            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                                shared.online.store(true, Ordering::SeqCst);
+                                handle.resubscribe(&shared.filters.read().unwrap());
+                            }
+                            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                                let topic = String::from_utf8_lossy(&publish.topic);
+                                shared.dispatch(&topic, &publish.payload, publish.properties);
+                            }
+                            Ok(_) => {}
+                            Err(_) => {
+                                shared.online.store(false, Ordering::SeqCst);
+                                tokio::time::sleep(Duration::from_secs(1)).await;
+                            }
+                        }
+                    }
+                });
+                (client, task)
+            }
+        };
+
+        Ok(Self {
+            protocol: "mqtt".to_string(),
+            client,
+            shared,
+            local: MemoryProvider::with_bus(inbox),
+            task,
+        })
+    }
+
+    /// Starts queueing what is published to `address`'s filter for `fetch`,
+    /// subscribing it on the broker if needed. The capture lasts until
+    /// `purge`; `fetch` captures on first use, which misses anything
+    /// published before it.
+    pub async fn capture(&self, address: &Url) -> Result<()> {
+        self.acquire(address, false).await?;
+        Ok(())
+    }
+
+    /// Takes a subscription's (`subscription`) or the capture's hold on
+    /// `address`'s filter, subscribing it on the broker if it is not
+    /// subscribed yet. Returns the filter and the local inbox its messages
+    /// are delivered to.
+    async fn acquire(&self, address: &Url, subscription: bool) -> Result<(String, Url)> {
+        let filter = topic_for(address)?;
+        let qos = qos_for(address)?;
+        let inbox = inbox_address(&filter)?;
+
+        let _changes = self.shared.changes.lock().await;
+        let added = {
+            let mut filters = self.shared.filters.write().unwrap();
+            let added = !filters.contains_key(&filter);
+            let state = filters.entry(filter.clone()).or_insert_with(|| Filter {
+                qos,
+                inbox: get_canonical_mailbox_address_identifier(&inbox),
+                subscriptions: 0,
+                captured: false,
+            });
+            if subscription {
+                state.subscriptions += 1;
+            } else {
+                state.captured = true;
+            }
+            added
+        };
+        if added {
+            if let Err(e) = self.client.subscribe(&filter, qos).await {
+                self.shared.filters.write().unwrap().remove(&filter);
+                return Err(e);
+            }
+        }
+
+        Ok((filter, inbox))
+    }
+}
+
+/// A local inbox subscription that also holds its filter on the broker.
+struct MqttSubscription {
+    local: Box<dyn Subscription>,
+    shared: Arc<Shared>,
+    client: Client,
+    filter: String,
+    active: bool,
+}
+
+#[async_trait]
+impl Subscription for MqttSubscription {
+    async fn unsubscribe(&mut self) -> Result<()> {
+        self.local.unsubscribe().await?;
+        if std::mem::take(&mut self.active) {
+            self.shared.release(&self.client, &self.filter, true).await?;
+        }
+        Ok(())
+    }
+
+    fn unsubscribe_now(&mut self) -> bool {
+        // Taking `changes` keeps the UNSUBSCRIBE ordered after a SUBSCRIBE
+        // in progress; if one is, this has to wait for `unsubscribe`.
+        let Ok(_changes) = self.shared.changes.try_lock() else { return false };
+        if !self.local.unsubscribe_now() {
+            return false;
+        }
+        if std::mem::take(&mut self.active) && self.shared.forget(&self.filter, true) {
+            return self.client.try_unsubscribe(&self.filter);
+        }
+        true
+    }
+}
+
+impl Drop for MqttProvider {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[async_trait]
+impl MailboxProvider for MqttProvider {
+    fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    async fn send(&self, message: MailMessage) -> Result<MailMessage> {
+        let topic = topic_for(&message.to)?;
+        if topic.contains(['+', '#']) {
+            return Err(MailboxError::InvalidAddress(format!(
+                "cannot publish to the wildcard topic {}", topic
+            )));
+        }
+        let qos = qos_for(&message.to)?;
+        let retain = query_param(&message.to, "retain").as_deref() == Some("true");
+        let payload = serde_json::to_vec(&message)?;
+
+        self.client.publish(topic, qos, retain, payload, &message).await?;
+        Ok(message)
+    }
+
+    async fn subscribe(
+        &self,
+        address: Url,
+        callback: Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>,
+    ) -> Result<Box<dyn Subscription>> {
+        let (filter, inbox) = self.acquire(&address, true).await?;
+        let local = match self.local.subscribe(inbox, callback).await {
+            Ok(local) => local,
+            Err(e) => {
+                self.shared.release(&self.client, &filter, true).await?;
+                return Err(e);
+            }
+        };
+        Ok(Box::new(MqttSubscription {
+            local,
+            shared: self.shared.clone(),
+            client: self.client.clone_handle(),
+            filter,
+            active: true,
+        }))
+    }
+
+    async fn fetch(&self, address: Url, options: FetchOptions) -> Result<Option<AckableMessage>> {
+        let (_, inbox) = self.acquire(&address, false).await?;
+        self.local.fetch(inbox, options).await
+    }
+
+    async fn purge(&self, address: Url) -> Result<()> {
+        let filter = topic_for(&address)?;
+        self.shared.release(&self.client, &filter, false).await?;
+        self.local.purge(inbox_address(&filter)?).await
+    }
+
+    async fn status(&self, address: Url) -> Result<MailboxStatus> {
+        let inbox = inbox_address(&topic_for(&address)?)?;
+        let mut status = self.local.status(inbox).await?;
+        status.state = if self.shared.online.load(Ordering::SeqCst) { "online" } else { "offline" }.to_string();
+        Ok(status)
+    }
+
+    fn generate_id(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{test_mail, OutgoingMail};
+    use serde_json::json;
+    use std::sync::Mutex;
+
+    async fn provider(version: MqttVersion) -> Result<MqttProvider> {
+        let broker = std::env::var("MQTT_BROKER").unwrap_or_else(|_| "127.0.0.1:1883".to_string());
+        let (host, port) = broker.rsplit_once(':').expect("MQTT_BROKER is host:port");
+        MqttProvider::connect(host, port.parse().expect("MQTT_BROKER port"), version).await
+    }
+
+    #[test]
+    fn test_topic_for_and_matching() -> Result<()> {
+        assert_eq!(topic_for(&"mqtt:devices/42/inbox".parse()?)?, "devices/42/inbox");
+        assert_eq!(topic_for(&"mqtt:devices/+/telemetry?qos=0".parse()?)?, "devices/+/telemetry");
+        assert_eq!(topic_for(&"mqtt:devices/#".parse()?)?, "devices/#");
+        assert_eq!(topic_for(&"mqtt:devices/%23".parse()?)?, "devices/#");
+        assert!(topic_for(&"mqtt:".parse()?).is_err());
+        assert_eq!(qos_for(&"mqtt:a?qos=2".parse()?)?, 2);
+        assert!(qos_for(&"mqtt:a?qos=3".parse()?).is_err());
+
+        assert!(topic_matches("devices/+/telemetry", "devices/42/telemetry"));
+        assert!(!topic_matches("devices/+/telemetry", "devices/42/status"));
+        assert!(topic_matches("devices/#", "devices/42/telemetry"));
+        assert!(topic_matches("devices/#", "devices"));
+        assert!(topic_matches("#", "devices/42"));
+        assert!(!topic_matches("#", "$SYS/uptime"));
+        assert!(!topic_matches("devices/42", "devices/42/telemetry"));
+        Ok(())
+    }
+
+    async fn roundtrip(version: MqttVersion) -> Result<()> {
+        let provider = provider(version).await?;
+        let root = format!("mailbox-test/{}", Uuid::new_v4());
+        let filter: Url = format!("mqtt:{}/+/inbox?qos=1", root).parse()?;
+        let address: Url = format!("mqtt:{}/42/inbox", root).parse()?;
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        let _sub = provider.subscribe(filter.clone(), Box::new(move |msg| {
+            let tx = tx.clone();
+            Box::pin(async move {
+                if let Some(tx) = tx.lock().unwrap().take() {
+                    tx.send(msg).unwrap();
+                }
+            })
+        })).await?;
+        // Give the broker time to acknowledge the subscription.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        provider.send(OutgoingMail {
+            id: Some("msg1".to_string()),
+            from: "mqtt:test/sender".parse()?,
+            to: address.clone(),
+            body: json!({"temp": 21}),
+            headers: HashMap::from([("device".to_string(), "42".to_string())]),
+            meta: HashMap::new(),
+        }.into()).await?;
+
+        let received = tokio::time::timeout(Duration::from_secs(5), rx).await.unwrap().unwrap();
+        assert_eq!(received.id, "msg1");
+        assert_eq!(received.headers["device"], "42");
+
+        let fetched = provider.fetch(filter.clone(), FetchOptions::default()).await?.unwrap();
+        assert_eq!(fetched.message.body, json!({"temp": 21}));
+        assert_eq!(provider.status(filter).await?.state, "online");
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires an MQTT broker at MQTT_BROKER (default 127.0.0.1:1883)"]
+    async fn test_roundtrip_v311() -> Result<()> {
+        roundtrip(MqttVersion::V311).await
+    }
+
+    #[tokio::test]
+    #[ignore = "requires an MQTT broker at MQTT_BROKER (default 127.0.0.1:1883)"]
+    async fn test_roundtrip_v5() -> Result<()> {
+        roundtrip(MqttVersion::V5).await
+    }
+
+    #[tokio::test]
+    #[ignore = "requires an MQTT broker at MQTT_BROKER (default 127.0.0.1:1883)"]
+    async fn test_capture_and_filter_release() -> Result<()> {
+        let provider = provider(MqttVersion::V5).await?;
+        let address: Url = format!("mqtt:mailbox-test/{}/inbox", Uuid::new_v4()).parse()?;
+        let filter = topic_for(&address)?;
+
+        provider.capture(&address).await?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        provider.send(test_mail("early", &address).into()).await?;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let fetched = provider.fetch(address.clone(), FetchOptions::default()).await?.unwrap();
+        assert_eq!(fetched.message.id, "early");
+
+        let mut sub = provider.subscribe(address.clone(), Box::new(|_| Box::pin(async {}))).await?;
+        provider.purge(address.clone()).await?;
+        assert!(provider.shared.filters.read().unwrap().contains_key(&filter));
+        sub.unsubscribe().await?;
+        assert!(!provider.shared.filters.read().unwrap().contains_key(&filter));
+        Ok(())
+    }
+}
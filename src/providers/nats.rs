@@ -0,0 +1,476 @@
+use async_trait::async_trait;
+use url::Url;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::time::Duration;
+use uuid::Uuid;
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use async_nats::connection::State;
+use async_nats::jetstream::{self, consumer, stream, AckKind};
+use async_nats::{Client, HeaderMap};
+use serde_json::Value;
+use tokio::task::JoinHandle;
+
+use crate::error::{MailboxError, Result};
+use crate::message::{MailMessage, MailboxStatus, FetchOptions};
+use crate::provider::{MailboxProvider, Subscription, AckableMessage};
+use crate::utils::{reject_deliver_at, reject_delivery_limit, reject_expires_at};
+
+impl<K> From<async_nats::error::Error<K>> for MailboxError
+where
+    K: Clone + Debug + Display + PartialEq,
+{
+    fn from(e: async_nats::error::Error<K>) -> Self {
+        MailboxError::ProviderError(format!("nats: {}", e))
+    }
+}
+
+impl From<async_nats::SubscribeError> for MailboxError {
+    fn from(e: async_nats::SubscribeError) -> Self {
+        MailboxError::ProviderError(format!("nats: {}", e))
+    }
+}
+
+fn nats_error(e: async_nats::Error) -> MailboxError {
+    MailboxError::ProviderError(format!("nats: {}", e))
+}
+
+/// The NATS subject named by a `nats:` address: its path with `/` as the
+/// token separator, so `nats:orders/created` is the subject `orders.created`.
+pub fn subject_for(address: &Url) -> Result<String> {
+    let subject = address.path().trim_start_matches('/').replace('/', ".");
+    if subject.is_empty() {
+        return Err(MailboxError::InvalidAddress(address.to_string()));
+    }
+    Ok(subject)
+}
+
+/// The `nats:` address of `subject`, the inverse of `subject_for`.
+pub fn address_for(subject: &str) -> Result<Url> {
+    Ok(format!("nats:{}", subject.replace('.', "/")).parse()?)
+}
+
+/// Whether the subscription `filter` matches `subject`, honouring the `*`
+/// (one token) and `>` (one or more remaining tokens) wildcards.
+pub fn subject_matches(filter: &str, subject: &str) -> bool {
+    let mut tokens = subject.split('.');
+    for part in filter.split('.') {
+        match (part, tokens.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (part, Some(token)) if part == token => {}
+            _ => return false,
+        }
+    }
+    tokens.next().is_none()
+}
+
+/// Header carrying the reply subject of a message published through
+/// JetStream, whose NATS reply subject is taken by the publish ack. It is
+/// empty when the message has no reply subject.
+const REPLY_HEADER: &str = "Mailbox-Reply-Subject";
+
+/// A name usable for streams and consumers derived from `subject`.
+fn durable_name(subject: &str) -> String {
+    let name: String = subject.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    format!("MAILBOX_{}", name)
+}
+
+/// Decodes a NATS message, wrapping payloads that are not a serialized
+/// `MailMessage` in a new message carrying the NATS headers. When the
+/// message has a reply subject, `from` is that subject's address.
+fn message_from(subject: &str, reply: Option<&str>, headers: Option<&HeaderMap>, payload: &[u8]) -> Result<MailMessage> {
+    let reply = match headers.and_then(|headers| headers.get(REPLY_HEADER)) {
+        Some(header) => Some(header.as_str()).filter(|reply| !reply.is_empty()),
+        None => reply,
+    };
+    let reply = reply.map(address_for).transpose()?;
+
+    if let Ok(mut message) = serde_json::from_slice::<MailMessage>(payload) {
+        if let Some(reply) = reply {
+            message.from = reply;
+        }
+        return Ok(message);
+    }
+
+    let to = address_for(subject)?;
+    let headers = headers
+        .map(|headers| headers.iter()
+            .filter_map(|(name, values)| Some((name.to_string(), values.first()?.to_string())))
+            .collect())
+        .unwrap_or_default();
+
+    Ok(MailMessage {
+        id: Uuid::new_v4().to_string(),
+        from: reply.unwrap_or_else(|| to.clone()),
+        to,
+        body: serde_json::from_slice(payload)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(payload).into_owned())),
+        headers,
+        meta: HashMap::new(),
+    })
+}
+
+/// Provider for `nats:` addresses on a NATS server.
+///
+/// Address paths are subjects (see `subject_for`). `send` publishes the
+/// JSON-serialized `MailMessage` with its headers as NATS headers and, when
+/// the sender is a `nats:` address, its subject as the reply subject; a
+/// receiver's `from` is always the reply subject when there is one, so
+/// replying to `from` works for plain NATS requesters too. `subscribe` is a
+/// core subscription and may use the `*` and `>` wildcards. `fetch` reads
+/// through a durable JetStream pull consumer with explicit acks: a stream
+/// capturing the subject is created on first use unless one already exists
+/// (see `ensure_stream`), and only messages published after that are
+/// retained. Once this provider has set up such a stream, `send` to a
+/// subject it captures publishes through JetStream and returns only after
+/// the stream has stored the message. Without an `ack_timeout` an un-acked
+/// fetch is redelivered after the consumer's ack wait.
+pub struct NatsProvider {
+    protocol: String,
+    client: Client,
+    jetstream: jetstream::Context,
+    consumers: tokio::sync::Mutex<HashMap<String, consumer::PullConsumer>>,
+}
+
+impl NatsProvider {
+    /// Connects to the NATS server at `url`, e.g. `nats://127.0.0.1:4222`.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let client = async_nats::connect(url).await?;
+        Ok(Self {
+            protocol: "nats".to_string(),
+            jetstream: jetstream::new(client.clone()),
+            client,
+            consumers: tokio::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Makes sure a JetStream stream captures `address`'s subject, creating
+    /// one named after the subject if no existing stream does, so that
+    /// messages sent from now on can be fetched.
+    pub async fn ensure_stream(&self, address: &Url) -> Result<()> {
+        self.consumer(&subject_for(address)?).await?;
+        Ok(())
+    }
+
+    async fn consumer(&self, subject: &str) -> Result<consumer::PullConsumer> {
+        let mut consumers = self.consumers.lock().await;
+        if let Some(consumer) = consumers.get(subject) {
+            return Ok(consumer.clone());
+        }
+
+        let stream = match self.jetstream.stream_by_subject(subject).await {
+            Ok(name) => self.jetstream.get_stream(name).await?,
+            Err(_) => self.jetstream.get_or_create_stream(stream::Config {
+                name: durable_name(subject),
+                subjects: vec![subject.to_string()],
+                ..Default::default()
+            }).await?,
+        };
+        let consumer = stream.get_or_create_consumer(&durable_name(subject), consumer::pull::Config {
+            durable_name: Some(durable_name(subject)),
+            filter_subject: subject.to_string(),
+            ack_policy: consumer::AckPolicy::Explicit,
+            ..Default::default()
+        }).await?;
+
+        consumers.insert(subject.to_string(), consumer.clone());
+        Ok(consumer)
+    }
+
+    /// Whether a stream set up by `ensure_stream` or `fetch` captures `subject`.
+    async fn streamed(&self, subject: &str) -> bool {
+        self.consumers.lock().await.keys().any(|filter| subject_matches(filter, subject))
+    }
+}
+
+struct NatsSubscription {
+    task: JoinHandle<()>,
+}
+
+#[async_trait]
+impl Subscription for NatsSubscription {
+    async fn unsubscribe(&mut self) -> Result<()> {
+        self.unsubscribe_now();
+        Ok(())
+    }
+
+    fn unsubscribe_now(&mut self) -> bool {
+        // Dropping the subscriber unsubscribes on the server.
+        self.task.abort();
+        true
+    }
+}
+
+#[async_trait]
+impl MailboxProvider for NatsProvider {
+    fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    async fn send(&self, message: MailMessage) -> Result<MailMessage> {
+        reject_deliver_at(&self.protocol, &message)?;
+        reject_expires_at(&self.protocol, &message)?;
+        let subject = subject_for(&message.to)?;
+        let payload = serde_json::to_vec(&message)?;
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in &message.headers {
+            headers.insert(name.as_str(), value.as_str());
+        }
+
+        let reply = if message.from.scheme() == self.protocol {
+            Some(subject_for(&message.from)?)
+        } else {
+            None
+        };
+
+        if self.streamed(&subject).await {
+            headers.insert(REPLY_HEADER, reply.unwrap_or_default().as_str());
+            self.jetstream.publish_with_headers(subject, headers, payload.into()).await?
+                .await?;
+        } else if let Some(reply) = reply {
+            self.client.publish_with_reply_and_headers(subject, reply, headers, payload.into()).await?;
+            self.client.flush().await?;
+        } else {
+            self.client.publish_with_headers(subject, headers, payload.into()).await?;
+            self.client.flush().await?;
+        }
+
+        Ok(message)
+    }
+
+    async fn subscribe(
+        &self,
+        address: Url,
+        callback: Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>,
+    ) -> Result<Box<dyn Subscription>> {
+        let mut subscriber = self.client.subscribe(subject_for(&address)?).await?;
+        // Make sure the server has the subscription before returning.
+        self.client.flush().await?;
+
+        let task = tokio::spawn(async move {
+            while let Some(msg) = subscriber.next().await {
+                let reply = msg.reply.as_ref().map(|reply| reply.as_str());
+                if let Ok(message) = message_from(&msg.subject, reply, msg.headers.as_ref(), &msg.payload) {
+                    tokio::spawn(callback(message));
+                }
+            }
+        });
+
+        Ok(Box::new(NatsSubscription { task }))
+    }
+
+    async fn fetch(&self, address: Url, options: FetchOptions) -> Result<Option<AckableMessage>> {
+        reject_delivery_limit(&self.protocol, &options)?;
+        let consumer = self.consumer(&subject_for(&address)?).await?;
+
+        let mut batch = consumer.fetch().max_messages(1).messages().await?;
+        let Some(msg) = batch.next().await.transpose().map_err(nats_error)? else {
+            return Ok(None);
+        };
+        // The reply subject of a JetStream message is its ack subject, so
+        // `from` comes from the payload.
+        let message = message_from(&msg.subject, None, msg.headers.as_ref(), &msg.payload)?;
+
+        if !options.manual_ack {
+            msg.ack().await.map_err(nats_error)?;
+            return Ok(Some(AckableMessage {
+                message,
+                ack: Box::new(|| Box::pin(async { Ok(()) })),
+                nack: Box::new(|_| Box::pin(async { Ok(()) })),
+            }));
+        }
+
+        if let Some(timeout) = options.ack_timeout {
+            // The server ignores a nak for a message that was acked already.
+            let msg = msg.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(timeout)).await;
+                let _ = msg.ack_with(AckKind::Nak(None)).await;
+            });
+        }
+
+        let nack_msg = msg.clone();
+        Ok(Some(AckableMessage {
+            message,
+            ack: Box::new(move || Box::pin(async move {
+                msg.double_ack().await.map_err(nats_error)
+            })),
+            nack: Box::new(move |requeue| Box::pin(async move {
+                let kind = if requeue { AckKind::Nak(None) } else { AckKind::Term };
+                nack_msg.ack_with(kind).await.map_err(nats_error)
+            })),
+        }))
+    }
+
+    async fn status(&self, address: Url) -> Result<MailboxStatus> {
+        let subject = subject_for(&address)?;
+        let mut extra = HashMap::new();
+
+        let consumer = self.consumers.lock().await.get(&subject).cloned();
+        let unread_count = match consumer {
+            Some(mut consumer) => {
+                let info = consumer.info().await?;
+                extra.insert("in_flight_count".to_string(), info.num_ack_pending.into());
+                Some(info.num_pending as usize)
+            }
+            None => None,
+        };
+
+        Ok(MailboxStatus {
+            state: match self.client.connection_state() {
+                State::Connected => "online",
+                _ => "offline",
+            }.to_string(),
+            unread_count,
+            last_activity_time: None,
+            extra,
+        })
+    }
+
+    fn generate_id(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{test_mail, OutgoingMail};
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    async fn provider() -> Result<NatsProvider> {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "nats://127.0.0.1:4222".to_string());
+        NatsProvider::connect(&url).await
+    }
+
+    fn mail(id: &str, from: &str, to: &Url) -> Result<MailMessage> {
+        Ok(OutgoingMail { from: from.parse()?, ..test_mail(id, to) }.into())
+    }
+
+    #[test]
+    fn test_subject_mapping() -> Result<()> {
+        assert_eq!(subject_for(&"nats:service/calculator".parse()?)?, "service.calculator");
+        assert_eq!(subject_for(&"nats:orders/*/created".parse()?)?, "orders.*.created");
+        assert!(subject_for(&"nats:".parse()?).is_err());
+        assert_eq!(address_for("_INBOX.abc")?.as_str(), "nats:_INBOX/abc");
+        assert_eq!(subject_for(&address_for("_INBOX.abc")?)?, "_INBOX.abc");
+        assert_eq!(durable_name("orders.*.created"), "MAILBOX_orders___created");
+        assert!(subject_matches("orders.*.created", "orders.42.created"));
+        assert!(subject_matches("orders.>", "orders.42.created"));
+        assert!(!subject_matches("orders.>", "orders"));
+        assert!(!subject_matches("orders.*", "orders.42.created"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_jetstream_reply_header_overrides_ack_inbox() -> Result<()> {
+        let to: Url = "nats:orders".parse()?;
+        let payload = serde_json::to_vec(&mail("msg1", "mem:local/sender", &to)?)?;
+
+        let mut headers = HeaderMap::new();
+        headers.insert(REPLY_HEADER, "");
+        let message = message_from("orders", Some("_INBOX.ack"), Some(&headers), &payload)?;
+        assert_eq!(message.from.as_str(), "mem:local/sender");
+
+        headers.insert(REPLY_HEADER, "service.replies");
+        let message = message_from("orders", Some("_INBOX.ack"), Some(&headers), &payload)?;
+        assert_eq!(message.from.as_str(), "nats:service/replies");
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a nats-server at NATS_URL (default nats://127.0.0.1:4222)"]
+    async fn test_reply_subject_populates_from() -> Result<()> {
+        let provider = Arc::new(provider().await?);
+        let service: Url = format!("nats:test/{}/calculator", Uuid::new_v4().simple()).parse()?;
+
+        let responder = provider.clone();
+        let _sub = provider.subscribe(service.clone(), Box::new(move |msg| {
+            let provider = responder.clone();
+            Box::pin(async move {
+                let reply = OutgoingMail {
+                    id: None,
+                    from: msg.to.clone(),
+                    to: msg.from.clone(),
+                    body: json!({"result": 30}),
+                    headers: HashMap::new(),
+                    meta: HashMap::new(),
+                };
+                provider.send(reply.into()).await.unwrap();
+            })
+        })).await?;
+
+        // A plain NATS request gets its reply on the requester's inbox.
+        let response = provider.client
+            .request(subject_for(&service)?, r#"{"op":"add","args":[10,20]}"#.into())
+            .await?;
+        let reply: MailMessage = serde_json::from_slice(&response.payload)?;
+        assert_eq!(reply.body, json!({"result": 30}));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a nats-server with JetStream at NATS_URL (default nats://127.0.0.1:4222)"]
+    async fn test_jetstream_fetch_ack_nack() -> Result<()> {
+        let provider = provider().await?;
+        let address: Url = format!("nats:test/{}/jobs", Uuid::new_v4().simple()).parse()?;
+        provider.ensure_stream(&address).await?;
+
+        provider.send(mail("msg1", "nats:test/sender", &address)?).await?;
+        provider.send(mail("msg2", "nats:test/sender", &address)?).await?;
+
+        let options = FetchOptions {
+            manual_ack: true,
+            ack_timeout: None,
+            ..Default::default()
+        };
+        let msg = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        assert_eq!(msg.message.id, "msg1");
+        assert_eq!(msg.message.from.as_str(), "nats:test/sender");
+        msg.nack(true).await?;
+
+        let msg = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        assert_eq!(msg.message.id, "msg1");
+        msg.ack().await?;
+
+        let msg = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        assert_eq!(msg.message.id, "msg2");
+        msg.ack().await?;
+
+        assert!(provider.fetch(address.clone(), options).await?.is_none());
+        assert_eq!(provider.status(address).await?.unread_count, Some(0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a nats-server at NATS_URL (default nats://127.0.0.1:4222)"]
+    async fn test_wildcard_subscribe() -> Result<()> {
+        let provider = provider().await?;
+        let root = format!("test/{}", Uuid::new_v4().simple());
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        let mut sub = provider.subscribe(format!("nats:{}/*/events", root).parse()?, Box::new(move |msg| {
+            let tx = tx.clone();
+            Box::pin(async move {
+                if let Some(tx) = tx.lock().unwrap().take() {
+                    tx.send(msg).unwrap();
+                }
+            })
+        })).await?;
+
+        let address: Url = format!("nats:{}/42/events", root).parse()?;
+        provider.send(mail("msg1", "mem:local/sender", &address)?).await?;
+        let received = rx.await.unwrap();
+        assert_eq!(received.id, "msg1");
+        assert_eq!(received.from.as_str(), "mem:local/sender");
+        sub.unsubscribe().await?;
+        Ok(())
+    }
+}
@@ -1,98 +1,499 @@
-use std::collections::{HashMap, VecDeque};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
 use std::time::{Duration, Instant};
-use crate::message::Identifiable;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::message::{Expirable, Identifiable, MailMessage, Prioritized};
 
 #[derive(Debug, Clone)]
 struct InFlightMessage<T> {
     message: T,
     timestamp: Instant,
     topic: String,
+    limit: Option<DeliveryLimit>,
+    // Token of the delivery holding the message; settles must present it
+    lease: String,
+}
+
+/// How many times a message may be delivered before a nack with requeue or
+/// an ack timeout moves it to `dead_letter_topic` instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryLimit {
+    pub max_deliveries: u32,
+    pub dead_letter_topic: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadLetterReason {
+    Nacked,
+    AckTimeout,
+}
+
+impl DeadLetterReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeadLetterReason::Nacked => "nacked",
+            DeadLetterReason::AckTimeout => "ack_timeout",
+        }
+    }
+}
+
+/// A message that ran out of deliveries, waiting to be moved by the owner
+/// of the queue.
+#[derive(Debug, Clone)]
+pub struct DeadLetter<T> {
+    pub topic: String,
+    pub dead_letter_topic: String,
+    pub message: T,
+    pub deliveries: u32,
+    pub reason: DeadLetterReason,
+}
+
+impl DeadLetter<MailMessage> {
+    /// The dead-letter topic and the message to put there, with why it was
+    /// dead-lettered noted in its meta.
+    pub fn into_message(self) -> (String, MailMessage) {
+        let mut message = self.message;
+        message.meta.insert("dead_letter_reason".to_string(), self.reason.as_str().into());
+        message.meta.insert("delivery_count".to_string(), self.deliveries.into());
+        message.meta.insert("original_topic".to_string(), self.topic.into());
+        message.meta.insert("dead_lettered_at".to_string(), Utc::now().to_rfc3339().into());
+        (self.dead_letter_topic, message)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ScheduledMessage<T> {
+    message: T,
+    topic: String,
+    due: DateTime<Utc>,
 }
 
 pub struct MailMessageQueue<T> {
     queues: HashMap<String, VecDeque<T>>,
+    // Messages held back until `due`, soonest first
+    scheduled: Vec<ScheduledMessage<T>>,
+    // When the queued and scheduled messages of each topic expire, soonest
+    // first. Entries stay behind when their message leaves early, so one may
+    // turn out to have nothing left to expire.
+    expiries: HashMap<String, BinaryHeap<Reverse<DateTime<Utc>>>>,
     in_flight: HashMap<String, InFlightMessage<T>>,
+    // Manual-ack deliveries so far, per message id
+    deliveries: HashMap<String, u32>,
+    dead_letters: Vec<DeadLetter<T>>,
+    // Messages dropped for expiring, waiting to be taken by the owner
+    expired: Vec<(String, T)>,
+    expired_counts: HashMap<String, usize>,
 }
 
 impl<T> MailMessageQueue<T>
-where T: Clone + Identifiable
+where T: Clone + Identifiable + Expirable + Prioritized
 {
     pub fn new() -> Self {
         Self {
             queues: HashMap::new(),
+            scheduled: Vec::new(),
+            expiries: HashMap::new(),
             in_flight: HashMap::new(),
+            deliveries: HashMap::new(),
+            dead_letters: Vec::new(),
+            expired: Vec::new(),
+            expired_counts: HashMap::new(),
         }
     }
 
+    /// Queues `message` behind everything on `topic` of the same or higher
+    /// priority.
     pub fn enqueue(&mut self, topic: String, message: T) {
-        self.queues
-            .entry(topic)
-            .or_insert_with(VecDeque::new)
-            .push_back(message);
+        self.track_expiry(&topic, &message);
+        let queue = self.queues.entry(topic).or_default();
+        let index = queue.iter()
+            .rposition(|m| m.priority() >= message.priority())
+            .map_or(0, |i| i + 1);
+        queue.insert(index, message);
+    }
+
+    /// Holds `message` back until `release` moves it onto `topic`.
+    pub fn schedule(&mut self, topic: String, message: T, due: DateTime<Utc>) {
+        self.track_expiry(&topic, &message);
+        let index = self.scheduled.partition_point(|s| s.due <= due);
+        self.scheduled.insert(index, ScheduledMessage { message, topic, due });
+    }
+
+    /// Ids of the scheduled messages due by `now`, earliest first.
+    pub fn due(&self, now: DateTime<Utc>) -> Vec<String> {
+        self.scheduled.iter()
+            .take_while(|s| s.due <= now)
+            .map(|s| s.message.id().to_string())
+            .collect()
+    }
+
+    /// Moves a scheduled message to the back of its topic's queue, returning
+    /// the topic and a copy of the message. One that expired while it was
+    /// scheduled is dropped instead.
+    pub fn release(&mut self, message_id: &str) -> Option<(String, T)> {
+        let index = self.scheduled.iter().position(|s| s.message.id() == message_id)?;
+        let ScheduledMessage { message, topic, .. } = self.scheduled.remove(index);
+        if message.is_expired(Utc::now()) {
+            self.expire(topic, message);
+            return None;
+        }
+        self.enqueue(topic.clone(), message.clone());
+        Some((topic, message))
+    }
+
+    /// When the earliest scheduled message is due.
+    pub fn next_due(&self) -> Option<DateTime<Utc>> {
+        self.scheduled.first().map(|s| s.due)
+    }
+
+    /// The earliest time a queued or scheduled message may expire.
+    pub fn next_expiry(&self) -> Option<DateTime<Utc>> {
+        self.expiries.values().filter_map(|e| e.peek()).map(|Reverse(at)| *at).min()
     }
 
+    pub fn scheduled_count(&self, topic: &str) -> usize {
+        self.scheduled.iter().filter(|s| s.topic == topic).count()
+    }
+
+    /// Takes the next unexpired message on `topic`.
     pub fn dequeue(&mut self, topic: &str) -> Option<T> {
-        self.queues.get_mut(topic)?.pop_front()
+        self.expire_topic(topic, Utc::now());
+        let message = self.queues.get_mut(topic)?.pop_front()?;
+        self.deliveries.remove(message.id());
+        Some(message)
+    }
+
+    /// Takes the oldest of the lowest-priority messages queued on `topic`,
+    /// leaving everything that would be fetched before it in place. Nothing
+    /// is taken if every queued message ranks above `priority`.
+    pub fn evict(&mut self, topic: &str, priority: i32) -> Option<T> {
+        let queue = self.queues.get_mut(topic)?;
+        let lowest = queue.back()?.priority();
+        if lowest > priority {
+            return None;
+        }
+        let index = queue.iter().position(|m| m.priority() == lowest)?;
+        let message = queue.remove(index)?;
+        self.deliveries.remove(message.id());
+        Some(message)
     }
 
+    /// Takes the message `message_id` off `topic` wherever it is queued,
+    /// expired or not.
+    pub fn remove(&mut self, topic: &str, message_id: &str) -> Option<T> {
+        let queue = self.queues.get_mut(topic)?;
+        let index = queue.iter().position(|m| m.id() == message_id)?;
+        let message = queue.remove(index)?;
+        self.deliveries.remove(message_id);
+        Some(message)
+    }
+
+    pub fn peek(&self, topic: &str) -> Option<&T> {
+        self.queues.get(topic)?.front()
+    }
+
+    /// Takes the next unexpired message on `topic` and holds it in flight
+    /// under `lease` until it is acked or nacked. With a `limit`, a message
+    /// that has been delivered `max_deliveries` times is dead-lettered rather
+    /// than requeued.
     pub fn dequeue_for_ack(
         &mut self,
         topic: &str,
-        ack_timeout: Option<Duration>
+        ack_timeout: Option<Duration>,
+        limit: Option<DeliveryLimit>,
+        lease: String,
     ) -> Option<T> {
         if let Some(timeout) = ack_timeout {
             self.requeue_stale(topic, timeout);
         }
+        self.expire_topic(topic, Utc::now());
 
         let message = self.queues.get_mut(topic)?.pop_front()?;
-        let id = message.id().to_string();
+        self.hold(topic, message.clone(), limit, lease);
+        Some(message)
+    }
 
+    /// Like `dequeue_for_ack` for the message `message_id`, expired or not.
+    pub fn lease(
+        &mut self,
+        topic: &str,
+        message_id: &str,
+        limit: Option<DeliveryLimit>,
+        lease: String,
+    ) -> Option<T> {
+        let queue = self.queues.get_mut(topic)?;
+        let index = queue.iter().position(|m| m.id() == message_id)?;
+        let message = queue.remove(index)?;
+        self.hold(topic, message.clone(), limit, lease);
+        Some(message)
+    }
+
+    fn hold(&mut self, topic: &str, message: T, limit: Option<DeliveryLimit>, lease: String) {
+        let id = message.id().to_string();
+        *self.deliveries.entry(id.clone()).or_default() += 1;
         self.in_flight.insert(id, InFlightMessage {
-            message: message.clone(),
+            message,
             timestamp: Instant::now(),
             topic: topic.to_string(),
+            limit,
+            lease,
         });
+    }
 
-        Some(message)
+    /// How many times the message has been handed out by `dequeue_for_ack`.
+    pub fn delivery_count(&self, message_id: &str) -> u32 {
+        self.deliveries.get(message_id).copied().unwrap_or(0)
+    }
+
+    /// Restores the delivery count of a queued message, e.g. from storage.
+    pub fn set_delivery_count(&mut self, message_id: &str, deliveries: u32) {
+        if deliveries > 0 {
+            self.deliveries.insert(message_id.to_string(), deliveries);
+        }
+    }
+
+    /// Takes the message `message_id` out of flight if it is still held
+    /// under `lease`. A holder whose lease has since run out gets nothing.
+    fn settle(&mut self, message_id: &str, lease: &str) -> Option<InFlightMessage<T>> {
+        if self.in_flight.get(message_id)?.lease != lease {
+            return None;
+        }
+        self.in_flight.remove(message_id)
     }
 
-    pub fn ack(&mut self, message_id: &str) {
-        self.in_flight.remove(message_id);
+    /// Whether `message_id` is in flight under `lease`.
+    pub fn holds(&self, message_id: &str, lease: &str) -> bool {
+        self.in_flight.get(message_id).is_some_and(|flight| flight.lease == lease)
     }
 
-    pub fn nack(&mut self, message_id: &str, requeue: bool) {
-        if let Some(flight) = self.in_flight.remove(message_id) {
+    /// Acks `message_id`, unless it is no longer held under `lease`.
+    pub fn ack(&mut self, message_id: &str, lease: &str) {
+        if self.settle(message_id, lease).is_some() {
+            self.deliveries.remove(message_id);
+        }
+    }
+
+    /// Nacks `message_id`, unless it is no longer held under `lease`.
+    pub fn nack(&mut self, message_id: &str, lease: &str, requeue: bool) {
+        if let Some(flight) = self.settle(message_id, lease) {
             if requeue {
-                self.requeue_internal(flight.topic, flight.message);
+                self.redeliver(flight, DeadLetterReason::Nacked);
+            } else {
+                self.deliveries.remove(message_id);
             }
         }
     }
 
+    /// Drains the messages dead-lettered since the last call.
+    pub fn take_dead_letters(&mut self) -> Vec<DeadLetter<T>> {
+        std::mem::take(&mut self.dead_letters)
+    }
+
+    /// Records `message` as expired on `topic` without queueing it.
+    pub fn expire(&mut self, topic: String, message: T) {
+        self.deliveries.remove(message.id());
+        *self.expired_counts.entry(topic.clone()).or_default() += 1;
+        self.expired.push((topic, message));
+    }
+
+    /// Drops every queued or scheduled message that has expired by `now`.
+    /// In-flight messages are left to be acked or nacked.
+    pub fn sweep(&mut self, now: DateTime<Utc>) {
+        let topics: Vec<String> = self.expiries.iter()
+            .filter(|(_, expiries)| expiries.peek().is_some_and(|Reverse(at)| *at <= now))
+            .map(|(topic, _)| topic.clone())
+            .collect();
+        for topic in topics {
+            self.expire_topic(&topic, now);
+        }
+    }
+
+    /// Drains the messages expired since the last call, with their topic.
+    pub fn take_expired(&mut self) -> Vec<(String, T)> {
+        std::mem::take(&mut self.expired)
+    }
+
+    /// How many messages have expired on `topic`.
+    pub fn expired_count(&self, topic: &str) -> usize {
+        self.expired_counts.get(topic).copied().unwrap_or(0)
+    }
+
+    /// Every topic on which messages have expired, with how many.
+    pub fn expired_counts(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.expired_counts.iter().map(|(topic, count)| (topic.as_str(), *count))
+    }
+
+    /// Counts `count` more messages as expired on `topic`.
+    pub fn count_expired(&mut self, topic: &str, count: usize) {
+        *self.expired_counts.entry(topic.to_string()).or_default() += count;
+    }
+
+    /// Expires the message `message_id` queued or scheduled on `topic`,
+    /// whether its time has come or not. Returns whether it was there.
+    pub fn expire_id(&mut self, topic: &str, message_id: &str) -> bool {
+        if let Some(message) = self.remove(topic, message_id) {
+            self.expire(topic.to_string(), message);
+            return true;
+        }
+        let Some(index) = self.scheduled.iter().position(|s| s.topic == topic && s.message.id() == message_id) else {
+            return false;
+        };
+        let ScheduledMessage { message, topic, .. } = self.scheduled.remove(index);
+        self.expire(topic, message);
+        true
+    }
+
+    /// Like `sweep` for `topic` alone. The messages are only looked at once
+    /// one of the topic's expiry times has passed.
+    pub fn expire_topic(&mut self, topic: &str, now: DateTime<Utc>) {
+        let Some(expiries) = self.expiries.get_mut(topic) else { return };
+        let mut passed = false;
+        while expiries.peek().is_some_and(|Reverse(at)| *at <= now) {
+            expiries.pop();
+            passed = true;
+        }
+        if expiries.is_empty() {
+            self.expiries.remove(topic);
+        }
+        if !passed {
+            return;
+        }
+
+        if let Some(queue) = self.queues.get_mut(topic) {
+            let (expired, live) = std::mem::take(queue)
+                .into_iter()
+                .partition::<VecDeque<_>, _>(|m| m.is_expired(now));
+            *queue = live;
+            for message in expired {
+                self.expire(topic.to_string(), message);
+            }
+        }
+
+        let (expired, scheduled) = std::mem::take(&mut self.scheduled)
+            .into_iter()
+            .partition::<Vec<_>, _>(|s| s.topic == topic && s.message.is_expired(now));
+        self.scheduled = scheduled;
+        for s in expired {
+            self.expire(s.topic, s.message);
+        }
+    }
+
+    fn track_expiry(&mut self, topic: &str, message: &T) {
+        if let Some(at) = message.expires_at() {
+            self.expiries.entry(topic.to_string()).or_default().push(Reverse(at));
+        }
+    }
+
+    /// Forgets `topic`'s queued and scheduled messages and its counts.
+    /// Messages in flight can still be acked or nacked.
+    pub fn purge(&mut self, topic: &str) {
+        self.queues.remove(topic);
+        self.scheduled.retain(|s| s.topic != topic);
+        self.expiries.remove(topic);
+        self.expired_counts.remove(topic);
+    }
+
     pub fn get_status(&self, topic: &str) -> usize {
         self.queues.get(topic).map(|q| q.len()).unwrap_or(0)
     }
 
-    fn requeue_internal(&mut self, topic: String, message: T) {
+    /// Iterates every queued (not in-flight) message in dequeue order per topic.
+    pub fn pending(&self) -> impl Iterator<Item = (&str, &T)> {
         self.queues
-            .entry(topic)
-            .or_insert_with(VecDeque::new)
-            .push_front(message);
+            .iter()
+            .flat_map(|(topic, queue)| queue.iter().map(move |m| (topic.as_str(), m)))
     }
 
-    fn requeue_stale(&mut self, topic: &str, timeout: Duration) {
-        let now = Instant::now();
-        let mut stale_ids = Vec::new();
+    /// The in-flight messages with their topic, lease and delivery limit,
+    /// longest held first.
+    pub fn in_flight(&self) -> Vec<(&str, &T, &str, Option<&DeliveryLimit>)> {
+        let mut flights: Vec<_> = self.in_flight.values().collect();
+        flights.sort_by_key(|flight| flight.timestamp);
+        flights.into_iter()
+            .map(|flight| (flight.topic.as_str(), &flight.message, flight.lease.as_str(), flight.limit.as_ref()))
+            .collect()
+    }
 
-        for (id, flight) in &self.in_flight {
-            if flight.topic == topic && now.duration_since(flight.timestamp) > timeout {
-                stale_ids.push(id.clone());
-            }
-        }
+    /// How many messages are queued, scheduled or in flight across all topics.
+    pub fn message_count(&self) -> usize {
+        self.queues.values().map(VecDeque::len).sum::<usize>() + self.scheduled.len() + self.in_flight.len()
+    }
+
+    /// Iterates the scheduled messages with their topic, soonest first.
+    pub fn scheduled(&self) -> impl Iterator<Item = (&str, &T)> {
+        self.scheduled.iter().map(|s| (s.topic.as_str(), &s.message))
+    }
+
+    /// Puts `message` back ahead of everything on `topic` of the same or
+    /// lower priority, where it was taken from.
+    fn requeue_internal(&mut self, topic: String, message: T) {
+        self.track_expiry(&topic, &message);
+        let queue = self.queues.entry(topic).or_default();
+        let index = queue.iter()
+            .position(|m| m.priority() <= message.priority())
+            .unwrap_or(queue.len());
+        queue.insert(index, message);
+    }
 
-        for id in stale_ids {
-            if let Some(flight) = self.in_flight.remove(&id) {
+    /// Requeues an in-flight message, or dead-letters it once it has used
+    /// up its deliveries. Returns whether it was requeued.
+    fn redeliver(&mut self, flight: InFlightMessage<T>, reason: DeadLetterReason) -> bool {
+        let id = flight.message.id().to_string();
+        let deliveries = self.delivery_count(&id);
+        match flight.limit {
+            Some(limit) if deliveries >= limit.max_deliveries => {
+                self.deliveries.remove(&id);
+                self.dead_letters.push(DeadLetter {
+                    topic: flight.topic,
+                    dead_letter_topic: limit.dead_letter_topic,
+                    message: flight.message,
+                    deliveries,
+                    reason,
+                });
+                false
+            }
+            _ => {
                 self.requeue_internal(flight.topic, flight.message);
+                true
             }
         }
     }
+
+    /// Ids and leases of the in-flight messages on `topic` held for longer
+    /// than `timeout`.
+    pub fn stale(&self, topic: &str, timeout: Duration) -> Vec<(String, String)> {
+        let now = Instant::now();
+        self.in_flight.iter()
+            .filter(|(_, flight)| flight.topic == topic && now.duration_since(flight.timestamp) > timeout)
+            .map(|(id, flight)| (id.clone(), flight.lease.clone()))
+            .collect()
+    }
+
+    /// Ends `lease` on `message_id` as if its ack timeout had passed,
+    /// returning whether it was requeued rather than dead-lettered.
+    pub fn time_out(&mut self, message_id: &str, lease: &str) -> bool {
+        match self.settle(message_id, lease) {
+            Some(flight) => self.redeliver(flight, DeadLetterReason::AckTimeout),
+            None => false,
+        }
+    }
+
+    /// Requeues in-flight messages on `topic` older than `timeout`, returning
+    /// the ids of those requeued rather than dead-lettered.
+    pub fn requeue_stale(&mut self, topic: &str, timeout: Duration) -> Vec<String> {
+        self.stale(topic, timeout)
+            .into_iter()
+            .filter(|(id, lease)| self.time_out(id, lease))
+            .map(|(id, _)| id)
+            .collect()
+    }
+}
+
+impl<T> Default for MailMessageQueue<T>
+where T: Clone + Identifiable + Expirable + Prioritized
+{
+    fn default() -> Self {
+        Self::new()
+    }
 }
@@ -0,0 +1,410 @@
+use async_trait::async_trait;
+use url::Url;
+use std::collections::HashMap;
+use uuid::Uuid;
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use once_cell::sync::Lazy;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, Client, Script};
+use tokio::task::JoinHandle;
+
+use crate::error::{MailboxError, Result};
+use crate::message::{MailMessage, MailboxStatus, FetchOptions};
+use crate::provider::{MailboxProvider, Subscription, AckableMessage};
+use crate::utils::{get_canonical_mailbox_address_identifier, reject_deliver_at, reject_delivery_limit, reject_expires_at};
+
+impl From<redis::RedisError> for MailboxError {
+    fn from(e: redis::RedisError) -> Self {
+        MailboxError::ProviderError(format!("redis: {}", e))
+    }
+}
+
+// Moves expired leases back to the head of the queue, then pops the head and,
+// when leasing, records it in the in-flight set under the lease
+// `<message id>:<nonce>`, scored by its deadline.
+// KEYS: queue, in-flight set, leased payloads. ARGV: now, deadline, lease flag, nonce.
+static FETCH: Lazy<Script> = Lazy::new(|| Script::new(r"
+    local expired = redis.call('ZRANGEBYSCORE', KEYS[2], '-inf', ARGV[1])
+    for i = #expired, 1, -1 do
+        local message = redis.call('HGET', KEYS[3], expired[i])
+        redis.call('ZREM', KEYS[2], expired[i])
+        redis.call('HDEL', KEYS[3], expired[i])
+        if message then
+            redis.call('LPUSH', KEYS[1], message)
+        end
+    end
+    local message = redis.call('LPOP', KEYS[1])
+    if message and ARGV[3] == '1' then
+        local lease = cjson.decode(message).id .. ':' .. ARGV[4]
+        redis.call('ZADD', KEYS[2], ARGV[2], lease)
+        redis.call('HSET', KEYS[3], lease, message)
+    end
+    return message
+"));
+
+// Does nothing once the lease has expired, even if the message was leased again.
+// KEYS: in-flight set, leased payloads. ARGV: lease.
+static ACK: Lazy<Script> = Lazy::new(|| Script::new(r"
+    if redis.call('ZREM', KEYS[1], ARGV[1]) == 1 then
+        redis.call('HDEL', KEYS[2], ARGV[1])
+    end
+    return 0
+"));
+
+// KEYS: queue, in-flight set, leased payloads. ARGV: lease.
+static REQUEUE: Lazy<Script> = Lazy::new(|| Script::new(r"
+    if redis.call('ZREM', KEYS[2], ARGV[1]) == 1 then
+        redis.call('LPUSH', KEYS[1], redis.call('HGET', KEYS[3], ARGV[1]))
+        redis.call('HDEL', KEYS[3], ARGV[1])
+    end
+    return 0
+"));
+
+/// Redis keys and channel backing one mailbox.
+struct Keys {
+    queue: String,
+    in_flight: String,
+    leases: String,
+    channel: String,
+    last_activity: String,
+}
+
+/// Provider for `redis:` addresses stored on a Redis server.
+///
+/// Each mailbox (by `get_canonical_mailbox_address_identifier`) is a list
+/// `<prefix>:<topic>:queue` for `fetch`, a sorted set
+/// `<prefix>:<topic>:in_flight` of leases scored by the unix-millis deadline
+/// after which their message is redelivered, a hash `<prefix>:<topic>:leases`
+/// from lease to leased message, and a pub/sub channel
+/// `<prefix>:<topic>` for `subscribe`. `send` pushes to the list and
+/// publishes in one transaction, so every provider on the same server
+/// shares the mailboxes.
+pub struct RedisProvider {
+    protocol: String,
+    prefix: String,
+    client: Client,
+    conn: MultiplexedConnection,
+}
+
+impl RedisProvider {
+    /// Connects to the Redis server at `url`, e.g. `redis://127.0.0.1:6379`.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let client = Client::open(url)?;
+        let conn = client.get_multiplexed_async_connection().await?;
+        Ok(Self {
+            protocol: "redis".to_string(),
+            prefix: "mailbox".to_string(),
+            client,
+            conn,
+        })
+    }
+
+    /// Namespaces every key and channel under `prefix` instead of `mailbox`.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    fn keys(&self, address: &Url) -> Keys {
+        let topic = get_canonical_mailbox_address_identifier(address);
+        Keys {
+            queue: format!("{}:{}:queue", self.prefix, topic),
+            in_flight: format!("{}:{}:in_flight", self.prefix, topic),
+            leases: format!("{}:{}:leases", self.prefix, topic),
+            channel: format!("{}:{}", self.prefix, topic),
+            last_activity: format!("{}:{}:last_activity", self.prefix, topic),
+        }
+    }
+}
+
+struct RedisSubscription {
+    task: JoinHandle<()>,
+}
+
+#[async_trait]
+impl Subscription for RedisSubscription {
+    async fn unsubscribe(&mut self) -> Result<()> {
+        self.unsubscribe_now();
+        Ok(())
+    }
+
+    fn unsubscribe_now(&mut self) -> bool {
+        // Dropping the pub/sub connection unsubscribes on the server.
+        self.task.abort();
+        true
+    }
+}
+
+#[async_trait]
+impl MailboxProvider for RedisProvider {
+    fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    async fn send(&self, message: MailMessage) -> Result<MailMessage> {
+        reject_deliver_at(&self.protocol, &message)?;
+        reject_expires_at(&self.protocol, &message)?;
+        let keys = self.keys(&message.to);
+        let payload = serde_json::to_string(&message)?;
+
+        redis::pipe()
+            .atomic()
+            .rpush(&keys.queue, &payload).ignore()
+            .publish(&keys.channel, &payload).ignore()
+            .set(&keys.last_activity, chrono::Utc::now().to_rfc3339()).ignore()
+            .query_async::<()>(&mut self.conn.clone())
+            .await?;
+
+        Ok(message)
+    }
+
+    async fn subscribe(
+        &self,
+        address: Url,
+        callback: Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>,
+    ) -> Result<Box<dyn Subscription>> {
+        let keys = self.keys(&address);
+        let mut pubsub = self.client.get_async_pubsub().await?;
+        pubsub.subscribe(&keys.channel).await?;
+
+        let task = tokio::spawn(async move {
+            let mut messages = pubsub.into_on_message();
+            while let Some(msg) = messages.next().await {
+                let Ok(payload) = msg.get_payload::<String>() else { continue };
+                if let Ok(message) = serde_json::from_str::<MailMessage>(&payload) {
+                    tokio::spawn(callback(message));
+                }
+            }
+        });
+
+        Ok(Box::new(RedisSubscription { task }))
+    }
+
+    async fn fetch(&self, address: Url, options: FetchOptions) -> Result<Option<AckableMessage>> {
+        reject_delivery_limit(&self.protocol, &options)?;
+        let keys = self.keys(&address);
+        let mut conn = self.conn.clone();
+        let now = chrono::Utc::now().timestamp_millis();
+        let deadline = match options.ack_timeout {
+            Some(timeout) => (now + timeout as i64).to_string(),
+            None => "+inf".to_string(),
+        };
+
+        let nonce = Uuid::new_v4().to_string();
+
+        conn.set::<_, _, ()>(&keys.last_activity, chrono::Utc::now().to_rfc3339()).await?;
+        let payload: Option<String> = FETCH
+            .key(&keys.queue)
+            .key(&keys.in_flight)
+            .key(&keys.leases)
+            .arg(now)
+            .arg(deadline)
+            .arg(if options.manual_ack { "1" } else { "0" })
+            .arg(&nonce)
+            .invoke_async(&mut conn)
+            .await?;
+
+        let Some(payload) = payload else {
+            return Ok(None);
+        };
+        let message: MailMessage = serde_json::from_str(&payload)?;
+
+        if !options.manual_ack {
+            return Ok(Some(AckableMessage {
+                message,
+                ack: Box::new(|| Box::pin(async { Ok(()) })),
+                nack: Box::new(|_| Box::pin(async { Ok(()) })),
+            }));
+        }
+
+        let lease = format!("{}:{}", message.id, nonce);
+        let ack_conn = self.conn.clone();
+        let nack_conn = self.conn.clone();
+        let ack_in_flight = keys.in_flight.clone();
+        let ack_leases = keys.leases.clone();
+        let ack_lease = lease.clone();
+
+        Ok(Some(AckableMessage {
+            message,
+            ack: Box::new(move || Box::pin(async move {
+                let mut conn = ack_conn;
+                ACK.key(&ack_in_flight)
+                    .key(&ack_leases)
+                    .arg(ack_lease)
+                    .invoke_async::<()>(&mut conn)
+                    .await?;
+                Ok(())
+            })),
+            nack: Box::new(move |requeue| Box::pin(async move {
+                let mut conn = nack_conn;
+                if requeue {
+                    REQUEUE
+                        .key(&keys.queue)
+                        .key(&keys.in_flight)
+                        .key(&keys.leases)
+                        .arg(lease)
+                        .invoke_async::<()>(&mut conn)
+                        .await?;
+                } else {
+                    ACK.key(&keys.in_flight)
+                        .key(&keys.leases)
+                        .arg(lease)
+                        .invoke_async::<()>(&mut conn)
+                        .await?;
+                }
+                Ok(())
+            })),
+        }))
+    }
+
+    async fn status(&self, address: Url) -> Result<MailboxStatus> {
+        let keys = self.keys(&address);
+        let now = chrono::Utc::now().timestamp_millis();
+        // Expired leases are redelivered by the next fetch, so they count as
+        // unread rather than in flight.
+        let (queued, expired, in_flight_count, last_activity_time): (usize, usize, usize, Option<String>) =
+            redis::pipe()
+                .llen(&keys.queue)
+                .zcount(&keys.in_flight, "-inf", now)
+                .zcount(&keys.in_flight, format!("({}", now), "+inf")
+                .get(&keys.last_activity)
+                .query_async(&mut self.conn.clone())
+                .await?;
+
+        let mut extra = HashMap::new();
+        extra.insert("in_flight_count".to_string(), in_flight_count.into());
+
+        Ok(MailboxStatus {
+            state: "online".to_string(),
+            unread_count: Some(queued + expired),
+            last_activity_time,
+            extra,
+        })
+    }
+
+    fn generate_id(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::test_mail;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    async fn provider() -> Result<RedisProvider> {
+        let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+        // A fresh prefix per test keeps runs independent on a shared server.
+        Ok(RedisProvider::connect(&url).await?.with_prefix(&format!("mailbox-test-{}", Uuid::new_v4())))
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a redis-server at REDIS_URL (default redis://127.0.0.1:6379)"]
+    async fn test_subscribe() -> Result<()> {
+        let provider = provider().await?;
+        let address: Url = "redis:test/inbox".parse()?;
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        let mut sub = provider.subscribe(address.clone(), Box::new(move |msg| {
+            let tx = tx.clone();
+            Box::pin(async move {
+                if let Some(tx) = tx.lock().unwrap().take() {
+                    tx.send(msg).unwrap();
+                }
+            })
+        })).await?;
+
+        provider.send(test_mail("msg1", &address).into()).await?;
+        assert_eq!(rx.await.unwrap().id, "msg1");
+        sub.unsubscribe().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a redis-server at REDIS_URL (default redis://127.0.0.1:6379)"]
+    async fn test_fetch_ack_nack_and_visibility_timeout() -> Result<()> {
+        let provider = provider().await?;
+        let address: Url = "redis:test/jobs".parse()?;
+
+        provider.send(test_mail("msg1", &address).into()).await?;
+        provider.send(test_mail("msg2", &address).into()).await?;
+        assert_eq!(provider.status(address.clone()).await?.unread_count, Some(2));
+
+        let options = FetchOptions {
+            manual_ack: true,
+            ack_timeout: None,
+            ..Default::default()
+        };
+        let msg = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        assert_eq!(msg.message.id, "msg1");
+        assert_eq!(provider.status(address.clone()).await?.extra["in_flight_count"], json!(1));
+        msg.nack(true).await?;
+
+        let msg = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        assert_eq!(msg.message.id, "msg1");
+        msg.ack().await?;
+
+        let expiring = FetchOptions {
+            manual_ack: true,
+            ack_timeout: Some(0),
+            ..Default::default()
+        };
+        let msg = provider.fetch(address.clone(), expiring.clone()).await?.unwrap();
+        assert_eq!(msg.message.id, "msg2");
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        let status = provider.status(address.clone()).await?;
+        assert_eq!(status.unread_count, Some(1));
+        assert_eq!(status.extra["in_flight_count"], json!(0));
+        let again = provider.fetch(address.clone(), expiring).await?.unwrap();
+        assert_eq!(again.message.id, "msg2");
+        again.ack().await?;
+
+        let status = provider.status(address).await?;
+        assert_eq!(status.unread_count, Some(0));
+        assert_eq!(status.extra["in_flight_count"], json!(0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a redis-server at REDIS_URL (default redis://127.0.0.1:6379)"]
+    async fn test_stale_ack_and_identical_payloads() -> Result<()> {
+        let provider = provider().await?;
+        let address: Url = "redis:test/stale".parse()?;
+
+        // Identical payloads are leased separately.
+        provider.send(test_mail("msg1", &address).into()).await?;
+        provider.send(test_mail("msg1", &address).into()).await?;
+        let options = FetchOptions {
+            manual_ack: true,
+            ack_timeout: None,
+            ..Default::default()
+        };
+        let first = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        let second = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        assert_eq!(provider.status(address.clone()).await?.extra["in_flight_count"], json!(2));
+        first.ack().await?;
+        second.ack().await?;
+
+        // A late ack leaves the redelivered lease in flight.
+        provider.send(test_mail("msg2", &address).into()).await?;
+        let expiring = FetchOptions {
+            manual_ack: true,
+            ack_timeout: Some(0),
+            ..Default::default()
+        };
+        let stale = provider.fetch(address.clone(), expiring).await?.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        let current = provider.fetch(address.clone(), options).await?.unwrap();
+        assert_eq!(current.message.id, "msg2");
+
+        stale.ack().await?;
+        assert_eq!(provider.status(address.clone()).await?.extra["in_flight_count"], json!(1));
+        current.ack().await?;
+        assert_eq!(provider.status(address).await?.extra["in_flight_count"], json!(0));
+        Ok(())
+    }
+}
@@ -0,0 +1,612 @@
+//! Request/response protocol the socket providers use to reach a `MemoryBus`
+//! hosted by another process.
+//!
+//! Every frame is one JSON document. Byte-stream transports prefix each
+//! frame with its length as a big-endian `u32`; message-oriented transports
+//! send one frame per message. A single connection multiplexes any number of
+//! requests and subscriptions: replies carry the id of the request they
+//! answer, and pushed messages carry the id of the `subscribe` request that
+//! created the subscription.
+
+use async_trait::async_trait;
+use url::Url;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::collections::HashMap;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::sync::Notify;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::{MailboxError, Result};
+use crate::message::{MailMessage, MailboxStatus, FetchOptions};
+use crate::provider::{Subscription, AckableMessage};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::provider::MailboxProvider;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::providers::memory::MemoryProvider;
+
+type Listener = Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Frames larger than this are rejected rather than allocated.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Encoded frames waiting for the transport to write them, per connection.
+/// Requests and replies wait for room; a pushed message that finds no room
+/// means the peer has fallen behind, and the server drops its session.
+pub(crate) const FRAME_QUEUE_LEN: usize = 1024;
+
+/// Sends waiting for their turn on a server session before it stops
+/// reading further requests from the peer.
+#[cfg(not(target_arch = "wasm32"))]
+const SEND_QUEUE_LEN: usize = 64;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum Request {
+    Send { message: Box<MailMessage> },
+    Subscribe { address: Url },
+    Unsubscribe { subscription: u64 },
+    Fetch {
+        address: Url,
+        manual_ack: bool,
+        ack_timeout: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_deliveries: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dead_letter_address: Option<Url>,
+    },
+    Ack { lease: u64 },
+    Nack { lease: u64, requeue: bool },
+    Status { address: Url },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct RequestFrame {
+    pub id: u64,
+    pub request: Request,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub(crate) enum Reply {
+    Sent { message: Box<MailMessage> },
+    Fetched { message: Option<Box<MailMessage>>, lease: Option<u64> },
+    Status { status: MailboxStatus },
+    Done,
+    Error { error: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ServerFrame {
+    Reply { id: u64, reply: Reply },
+    Message { subscription: u64, message: Box<MailMessage> },
+}
+
+/// Maps a remote address onto the `mem:` mailbox it names on the serving bus,
+/// e.g. `tcp://127.0.0.1:7000/service/inbox` becomes `mem:service/inbox`.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn local_address(address: &Url) -> Result<Url> {
+    Ok(format!("mem:{}", address.path().trim_start_matches('/')).parse()?)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn read_frame<R>(reader: &mut R) -> std::io::Result<Option<Vec<u8>>>
+where R: AsyncRead + Unpin
+{
+    let len = match reader.read_u32().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if len > MAX_FRAME_LEN {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("frame of {} bytes exceeds limit", len),
+        ));
+    }
+
+    let mut frame = vec![0; len];
+    reader.read_exact(&mut frame).await?;
+    Ok(Some(frame))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn write_frame<W>(writer: &mut W, frame: &[u8]) -> std::io::Result<()>
+where W: AsyncWrite + Unpin
+{
+    writer.write_u32(frame.len() as u32).await?;
+    writer.write_all(frame).await?;
+    writer.flush().await
+}
+
+/// Maps an address received from a peer onto the local mailbox it names.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) type AddressMapper = fn(&Url) -> Result<Url>;
+
+/// Server half of one connection: applies requests to `provider` and queues
+/// encoded replies and pushed messages on `outgoing`.
+///
+/// Sends run in order on a task of their own, since a send to a full
+/// `Block` mailbox waits for room and must not hold up the peer's other
+/// requests, such as the fetch that would make that room. Both queues are
+/// bounded: once either is full the session stops reading requests, and
+/// once `outgoing` is too full for a pushed message `lagging` is signalled
+/// so that the transport drops the peer.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) struct ServerSession {
+    provider: Arc<MemoryProvider>,
+    map_address: AddressMapper,
+    outgoing: mpsc::Sender<Vec<u8>>,
+    sends: mpsc::Sender<(u64, Box<MailMessage>)>,
+    subscriptions: HashMap<u64, Box<dyn Subscription>>,
+    leases: HashMap<u64, AckableMessage>,
+    lagging: Arc<Notify>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ServerSession {
+    pub(crate) fn new(
+        provider: Arc<MemoryProvider>,
+        map_address: AddressMapper,
+        outgoing: mpsc::Sender<Vec<u8>>,
+    ) -> Self {
+        let (sends, mut queued) = mpsc::channel::<(u64, Box<MailMessage>)>(SEND_QUEUE_LEN);
+        let sender = provider.clone();
+        let replies = outgoing.clone();
+        tokio::spawn(async move {
+            while let Some((id, mut message)) = queued.recv().await {
+                let result = match map_address(&message.to) {
+                    Ok(to) => {
+                        message.to = to;
+                        sender.send(*message).await
+                    }
+                    Err(e) => Err(e),
+                };
+                let reply = match result {
+                    Ok(message) => Reply::Sent { message: Box::new(message) },
+                    Err(e) => Reply::Error { error: e.to_string() },
+                };
+                if let Ok(frame) = serde_json::to_vec(&ServerFrame::Reply { id, reply }) {
+                    let _ = replies.send(frame).await;
+                }
+            }
+        });
+
+        Self {
+            provider,
+            map_address,
+            outgoing,
+            sends,
+            subscriptions: HashMap::new(),
+            leases: HashMap::new(),
+            lagging: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Signalled once the peer has fallen too far behind on pushed messages.
+    pub(crate) fn lagging(&self) -> Arc<Notify> {
+        self.lagging.clone()
+    }
+
+    pub(crate) async fn handle_frame(&mut self, frame: &[u8]) -> Result<()> {
+        let RequestFrame { id, request } = serde_json::from_slice(frame)?;
+        if let Request::Send { message } = request {
+            let _ = self.sends.send((id, message)).await;
+            return Ok(());
+        }
+        let reply = match self.handle(id, request).await {
+            Ok(reply) => reply,
+            Err(e) => Reply::Error { error: e.to_string() },
+        };
+        let _ = self.outgoing.send(serde_json::to_vec(&ServerFrame::Reply { id, reply })?).await;
+        Ok(())
+    }
+
+    /// Feeds `frames` to the session until the peer disconnects, sends an
+    /// invalid frame or falls behind, then closes the session.
+    pub(crate) async fn run<F>(mut self, mut frames: F)
+    where F: futures::Stream<Item = Vec<u8>> + Unpin
+    {
+        use futures::StreamExt;
+
+        let lagging = self.lagging();
+        loop {
+            let next = async {
+                match frames.next().await {
+                    Some(frame) => self.handle_frame(&frame).await.is_ok(),
+                    None => false,
+                }
+            };
+            let keep_going = tokio::select! {
+                keep_going = next => keep_going,
+                _ = lagging.notified() => false,
+            };
+            if !keep_going {
+                break;
+            }
+        }
+        self.close().await;
+    }
+
+    async fn handle(&mut self, id: u64, request: Request) -> Result<Reply> {
+        match request {
+            Request::Send { .. } => unreachable!("sends are queued by handle_frame"),
+            Request::Subscribe { address } => {
+                let outgoing = self.outgoing.clone();
+                let lagging = self.lagging.clone();
+                let subscription = self.provider.subscribe(
+                    (self.map_address)(&address)?,
+                    Box::new(move |message| {
+                        let frame = serde_json::to_vec(&ServerFrame::Message {
+                            subscription: id,
+                            message: Box::new(message),
+                        });
+                        if let Ok(frame) = frame {
+                            if outgoing.try_send(frame).is_err() {
+                                lagging.notify_one();
+                            }
+                        }
+                        Box::pin(async {})
+                    }),
+                ).await?;
+                self.subscriptions.insert(id, subscription);
+                Ok(Reply::Done)
+            }
+            Request::Unsubscribe { subscription } => {
+                if let Some(mut subscription) = self.subscriptions.remove(&subscription) {
+                    subscription.unsubscribe().await?;
+                }
+                Ok(Reply::Done)
+            }
+            Request::Fetch { address, manual_ack, ack_timeout, max_deliveries, dead_letter_address } => {
+                let options = FetchOptions {
+                    manual_ack,
+                    ack_timeout,
+                    max_deliveries,
+                    dead_letter_address: dead_letter_address.as_ref().map(&self.map_address).transpose()?,
+                };
+                match self.provider.fetch((self.map_address)(&address)?, options).await? {
+                    Some(fetched) => {
+                        let message = Box::new(fetched.message.clone());
+                        let lease = if manual_ack {
+                            self.leases.insert(id, fetched);
+                            Some(id)
+                        } else {
+                            None
+                        };
+                        Ok(Reply::Fetched { message: Some(message), lease })
+                    }
+                    None => Ok(Reply::Fetched { message: None, lease: None }),
+                }
+            }
+            Request::Ack { lease } => {
+                if let Some(fetched) = self.leases.remove(&lease) {
+                    fetched.ack().await?;
+                }
+                Ok(Reply::Done)
+            }
+            Request::Nack { lease, requeue } => {
+                if let Some(fetched) = self.leases.remove(&lease) {
+                    fetched.nack(requeue).await?;
+                }
+                Ok(Reply::Done)
+            }
+            Request::Status { address } => {
+                let status = self.provider.status((self.map_address)(&address)?).await?;
+                Ok(Reply::Status { status })
+            }
+        }
+    }
+
+    /// Drops the peer's subscriptions and returns its un-acked messages to the queue.
+    pub(crate) async fn close(&mut self) {
+        for (_, mut subscription) in self.subscriptions.drain() {
+            let _ = subscription.unsubscribe().await;
+        }
+        for (_, fetched) in self.leases.drain() {
+            let _ = fetched.nack(true).await;
+        }
+    }
+}
+
+/// Serves one byte-stream connection until the peer disconnects.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn serve_stream<S>(stream: S, provider: Arc<MemoryProvider>, map_address: AddressMapper)
+where S: AsyncRead + AsyncWrite + Send + 'static
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let (outgoing, mut frames) = mpsc::channel::<Vec<u8>>(FRAME_QUEUE_LEN);
+
+    let writing = tokio::spawn(async move {
+        while let Some(frame) = frames.recv().await {
+            if write_frame(&mut writer, &frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let requests = futures::stream::unfold(reader, |mut reader| async move {
+        match read_frame(&mut reader).await {
+            Ok(Some(frame)) => Some((frame, reader)),
+            _ => None,
+        }
+    });
+    ServerSession::new(provider, map_address, outgoing).run(Box::pin(requests)).await;
+    // A peer that stopped reading would keep the writer waiting forever.
+    writing.abort();
+}
+
+/// Client half of one connection. Requests are encoded onto `outgoing`,
+/// waiting for room once `FRAME_QUEUE_LEN` frames are queued; the transport
+/// feeds every received frame to `handle_frame`.
+pub(crate) struct Connection {
+    outgoing: mpsc::Sender<Vec<u8>>,
+    pending: Mutex<HashMap<u64, oneshot::Sender<Reply>>>,
+    listeners: Mutex<HashMap<u64, Arc<Listener>>>,
+    next_id: AtomicU64,
+    closed: AtomicBool,
+}
+
+impl Connection {
+    pub(crate) fn new(outgoing: mpsc::Sender<Vec<u8>>) -> Arc<Self> {
+        Arc::new(Self {
+            outgoing,
+            pending: Mutex::new(HashMap::new()),
+            listeners: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    /// Spawns reader and writer tasks driving the connection over a byte stream.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn spawn_stream<S>(stream: S) -> Arc<Self>
+    where S: AsyncRead + AsyncWrite + Send + 'static
+    {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (outgoing, mut frames) = mpsc::channel::<Vec<u8>>(FRAME_QUEUE_LEN);
+        let connection = Self::new(outgoing);
+
+        // The writer ends once every handle to the connection is dropped and
+        // shuts the stream down, which in turn ends the reader.
+        tokio::spawn(async move {
+            while let Some(frame) = frames.recv().await {
+                if write_frame(&mut writer, &frame).await.is_err() {
+                    break;
+                }
+            }
+            let _ = writer.shutdown().await;
+        });
+
+        let reading = Arc::downgrade(&connection);
+        tokio::spawn(async move {
+            while let Ok(Some(frame)) = read_frame(&mut reader).await {
+                match reading.upgrade() {
+                    Some(connection) if connection.handle_frame(&frame).is_ok() => {}
+                    _ => return,
+                }
+            }
+            if let Some(connection) = reading.upgrade() {
+                connection.close();
+            }
+        });
+
+        connection
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Dispatches one frame from the server. A frame that cannot be decoded
+    /// means the two ends no longer agree on the protocol, so it fails every
+    /// outstanding request and closes the connection.
+    pub(crate) fn handle_frame(&self, frame: &[u8]) -> Result<()> {
+        match serde_json::from_slice(frame) {
+            Ok(ServerFrame::Reply { id, reply }) => {
+                if let Some(tx) = self.pending.lock().unwrap().remove(&id) {
+                    let _ = tx.send(reply);
+                }
+            }
+            Ok(ServerFrame::Message { subscription, message }) => {
+                let listener = self.listeners.lock().unwrap().get(&subscription).cloned();
+                if let Some(listener) = listener {
+                    #[cfg(not(target_arch = "wasm32"))]
+                    tokio::spawn(async move {
+                        (listener)(*message).await;
+                    });
+
+                    #[cfg(target_arch = "wasm32")]
+                    wasm_bindgen_futures::spawn_local(async move {
+                        (listener)(*message).await;
+                    });
+                }
+            }
+            Err(e) => {
+                let error = format!("invalid frame from server: {}", e);
+                self.fail(&error);
+                return Err(MailboxError::ProviderError(error));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails every outstanding request and ends every subscription; the
+    /// owning provider reconnects on next use.
+    pub(crate) fn close(&self) {
+        self.fail("connection closed");
+    }
+
+    /// Subscriptions are not carried over to the next connection: their
+    /// callbacks are dropped here, which ends streams fed by them, and
+    /// unsubscribing from them reports the loss.
+    fn fail(&self, error: &str) {
+        self.closed.store(true, Ordering::SeqCst);
+        for (_, tx) in self.pending.lock().unwrap().drain() {
+            let _ = tx.send(Reply::Error { error: error.to_string() });
+        }
+        self.listeners.lock().unwrap().clear();
+    }
+
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    async fn request_with_id(&self, id: u64, request: Request) -> Result<Reply> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+
+        let frame = serde_json::to_vec(&RequestFrame { id, request })?;
+        if self.is_closed() || self.outgoing.send(frame).await.is_err() {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(MailboxError::ProviderError("connection closed".to_string()));
+        }
+
+        match rx.await {
+            Ok(Reply::Error { error }) => Err(MailboxError::ProviderError(error)),
+            Ok(reply) => Ok(reply),
+            Err(_) => Err(MailboxError::ProviderError("connection closed".to_string())),
+        }
+    }
+
+    async fn request(&self, request: Request) -> Result<Reply> {
+        self.request_with_id(self.next_id(), request).await
+    }
+
+    pub(crate) async fn send(&self, message: MailMessage) -> Result<MailMessage> {
+        match self.request(Request::Send { message: Box::new(message) }).await? {
+            Reply::Sent { message } => Ok(*message),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    pub(crate) async fn subscribe(
+        self: &Arc<Self>,
+        address: Url,
+        callback: Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>,
+    ) -> Result<Box<dyn Subscription>> {
+        // Register first: the server may push before its reply is processed.
+        let id = self.next_id();
+        self.listeners.lock().unwrap().insert(id, Arc::new(callback));
+
+        if let Err(e) = self.request_with_id(id, Request::Subscribe { address }).await {
+            self.listeners.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+
+        Ok(Box::new(RemoteSubscription {
+            connection: self.clone(),
+            subscription: id,
+        }))
+    }
+
+    pub(crate) async fn fetch(self: &Arc<Self>, address: Url, options: FetchOptions) -> Result<Option<AckableMessage>> {
+        let request = Request::Fetch {
+            address,
+            manual_ack: options.manual_ack,
+            ack_timeout: options.ack_timeout,
+            max_deliveries: options.max_deliveries,
+            dead_letter_address: options.dead_letter_address,
+        };
+        let (message, lease) = match self.request(request).await? {
+            Reply::Fetched { message: Some(message), lease } => (*message, lease),
+            Reply::Fetched { message: None, .. } => return Ok(None),
+            reply => return Err(unexpected(reply)),
+        };
+
+        let Some(lease) = lease else {
+            return Ok(Some(AckableMessage {
+                message,
+                ack: Box::new(|| Box::pin(async { Ok(()) })),
+                nack: Box::new(|_| Box::pin(async { Ok(()) })),
+            }));
+        };
+
+        let ack_connection = self.clone();
+        let nack_connection = self.clone();
+
+        Ok(Some(AckableMessage {
+            message,
+            ack: Box::new(move || Box::pin(async move {
+                ack_connection.request(Request::Ack { lease }).await.map(|_| ())
+            })),
+            nack: Box::new(move |requeue| Box::pin(async move {
+                nack_connection.request(Request::Nack { lease, requeue }).await.map(|_| ())
+            })),
+        }))
+    }
+
+    pub(crate) async fn status(&self, address: Url) -> Result<MailboxStatus> {
+        match self.request(Request::Status { address }).await? {
+            Reply::Status { status } => Ok(status),
+            reply => Err(unexpected(reply)),
+        }
+    }
+}
+
+fn unexpected(reply: Reply) -> MailboxError {
+    MailboxError::ProviderError(format!("unexpected reply: {:?}", reply))
+}
+
+struct RemoteSubscription {
+    connection: Arc<Connection>,
+    subscription: u64,
+}
+
+#[async_trait]
+impl Subscription for RemoteSubscription {
+    async fn unsubscribe(&mut self) -> Result<()> {
+        self.connection.listeners.lock().unwrap().remove(&self.subscription);
+        if self.connection.is_closed() {
+            return Err(MailboxError::ProviderError(
+                "connection closed; the subscription had already ended".to_string(),
+            ));
+        }
+        self.connection
+            .request(Request::Unsubscribe { subscription: self.subscription })
+            .await
+            .map(|_| ())
+    }
+
+    fn unsubscribe_now(&mut self) -> bool {
+        self.connection.listeners.lock().unwrap().remove(&self.subscription);
+        if self.connection.is_closed() {
+            return true;
+        }
+        // Nothing waits for the reply, so the server's answer is dropped.
+        let frame = RequestFrame {
+            id: self.connection.next_id(),
+            request: Request::Unsubscribe { subscription: self.subscription },
+        };
+        serde_json::to_vec(&frame).is_ok_and(|frame| self.connection.outgoing.try_send(frame).is_ok())
+    }
+}
+
+/// Connections keyed by endpoint, replaced transparently once they close.
+#[derive(Default)]
+pub(crate) struct ConnectionPool {
+    connections: tokio::sync::Mutex<HashMap<String, Arc<Connection>>>,
+}
+
+impl ConnectionPool {
+    pub(crate) async fn get_or_connect<F>(&self, endpoint: String, connect: F) -> Result<Arc<Connection>>
+    where F: std::future::Future<Output = Result<Arc<Connection>>>
+    {
+        let mut connections = self.connections.lock().await;
+        if let Some(connection) = connections.get(&endpoint) {
+            if !connection.is_closed() {
+                return Ok(connection.clone());
+            }
+        }
+
+        let connection = connect.await?;
+        connections.insert(endpoint, connection.clone());
+        Ok(connection)
+    }
+}
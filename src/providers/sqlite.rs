@@ -0,0 +1,734 @@
+use async_trait::async_trait;
+use url::Url;
+use std::sync::{Arc, Mutex, RwLock};
+use std::collections::HashMap;
+use std::path::Path;
+use uuid::Uuid;
+use futures::future::BoxFuture;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+
+use crate::error::{MailboxError, Result};
+use crate::message::{MailMessage, MailboxStatus, FetchOptions, Expirable};
+use crate::provider::{MailboxProvider, Subscription, AckableMessage};
+use crate::utils::{default_dead_letter_address, get_canonical_mailbox_address_identifier};
+use crate::providers::queue::{DeadLetter, DeadLetterReason};
+
+type Listener = Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>;
+
+impl From<rusqlite::Error> for MailboxError {
+    fn from(e: rusqlite::Error) -> Self {
+        MailboxError::ProviderError(format!("sqlite: {}", e))
+    }
+}
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS mailbox_messages (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        id TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        from_address TEXT NOT NULL,
+        to_address TEXT NOT NULL,
+        body TEXT NOT NULL,
+        headers TEXT NOT NULL DEFAULT '{}',
+        meta TEXT NOT NULL DEFAULT '{}',
+        state TEXT NOT NULL DEFAULT 'pending',
+        visible_at INTEGER,
+        expires_at INTEGER,
+        lease TEXT,
+        deliveries INTEGER NOT NULL DEFAULT 0,
+        max_deliveries INTEGER,
+        dead_letter_topic TEXT
+    );
+    CREATE INDEX IF NOT EXISTS mailbox_messages_topic
+        ON mailbox_messages (topic, state, visible_at, seq);
+";
+
+const STATE_PENDING: &str = "pending";
+const STATE_IN_FLIGHT: &str = "in_flight";
+
+fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// Provider for the `sqlite:` scheme storing one row per message in the
+/// `mailbox_messages` table.
+///
+/// Rows move from `pending` to `in_flight` when fetched for manual ack and
+/// are deleted on ack. `visible_at` (unix millis) is when a row becomes
+/// fetchable: its `deliver-at` time for a pending row, or when the lease
+/// runs out for an in-flight one, where `NULL` means it stays leased until
+/// acked or nacked. `expires_at` (unix millis) is the message's `expires-at`
+/// time, after which the row is no longer fetched and the next fetch from
+/// its mailbox deletes it unless it is leased. Each fetch stores a fresh
+/// `lease` token on the row, and ack/nack only apply while the row still
+/// carries it, so a late ack from a holder
+/// whose lease expired leaves the redelivered message alone. A lease taken
+/// with `max_deliveries` records the limit on the row, so that a nack with
+/// requeue or a lapsed lease on its last delivery moves the row to the
+/// dead-letter topic instead, even when another process notices.
+/// Statements run on blocking threads. Subscribers are only notified of
+/// messages sent through this provider, not of rows inserted by other
+/// connections, and of scheduled ones only once they are due.
+pub struct SqliteProvider {
+    protocol: String,
+    conn: Arc<Mutex<Connection>>,
+    topics: Arc<RwLock<HashMap<String, Vec<Arc<Listener>>>>>,
+    last_activity: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl SqliteProvider {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    pub fn with_connection(conn: Connection) -> Result<Self> {
+        Self::init_schema(&conn)?;
+        Ok(Self {
+            protocol: "sqlite".to_string(),
+            conn: Arc::new(Mutex::new(conn)),
+            topics: Arc::new(RwLock::new(HashMap::new())),
+            last_activity: Arc::new(RwLock::new(HashMap::new())),
+        })
+    }
+
+    /// Creates the `mailbox_messages` table and index if they do not exist.
+    pub fn init_schema(conn: &Connection) -> Result<()> {
+        conn.execute_batch(SCHEMA)?;
+        // Tables created before messages could expire lack the column.
+        if conn.prepare("SELECT expires_at FROM mailbox_messages LIMIT 0").is_err() {
+            conn.execute_batch("ALTER TABLE mailbox_messages ADD COLUMN expires_at INTEGER")?;
+        }
+        Ok(())
+    }
+
+    /// Inserts `message` through `conn` without notifying subscribers and
+    /// returns the id it was stored under, generated if `message.id` is empty.
+    ///
+    /// Pass a `rusqlite::Transaction` to commit the message atomically with
+    /// your own writes.
+    pub fn enqueue(conn: &Connection, message: &MailMessage) -> Result<String> {
+        insert(conn, &get_canonical_mailbox_address_identifier(&message.to), message)
+    }
+
+    /// Runs `f` inside a transaction on the provider's own connection and
+    /// commits it if `f` succeeds.
+    pub fn transaction<R>(&self, f: impl FnOnce(&Transaction) -> Result<R>) -> Result<R> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let result = f(&tx)?;
+        tx.commit()?;
+        Ok(result)
+    }
+
+    fn touch(&self, topic: &str) {
+        self.last_activity
+            .write()
+            .unwrap()
+            .insert(topic.to_string(), chrono::Utc::now().to_rfc3339());
+    }
+}
+
+/// Runs `f` on the provider's connection from a blocking thread, so that
+/// SQLite's file I/O and syncs do not hold up the async executor.
+async fn with_conn<R: Send + 'static>(
+    conn: Arc<Mutex<Connection>>,
+    f: impl FnOnce(&mut Connection) -> Result<R> + Send + 'static,
+) -> Result<R> {
+    tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap()))
+        .await
+        .map_err(|e| MailboxError::ProviderError(format!("sqlite: {}", e)))?
+}
+
+/// Appends `message` to `topic` as a pending row, returning its id.
+fn insert(conn: &Connection, topic: &str, message: &MailMessage) -> Result<String> {
+    let id = if message.id.is_empty() {
+        Uuid::new_v4().to_string()
+    } else {
+        message.id.clone()
+    };
+
+    conn.execute(
+        "INSERT INTO mailbox_messages
+            (id, topic, from_address, to_address, body, headers, meta, state, visible_at, expires_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![
+            id,
+            topic,
+            message.from.as_str(),
+            message.to.as_str(),
+            serde_json::to_string(&message.body)?,
+            serde_json::to_string(&message.headers)?,
+            serde_json::to_string(&message.meta)?,
+            STATE_PENDING,
+            message.deliver_at().map_or_else(now_millis, |at| at.timestamp_millis()),
+            message.expires_at().map(|at| at.timestamp_millis()),
+        ],
+    )?;
+    Ok(id)
+}
+
+/// Replaces the row `seq` with a copy of `message` at the back of its
+/// dead-letter topic.
+fn dead_letter(conn: &Connection, seq: i64, dead: DeadLetter<MailMessage>) -> Result<()> {
+    let (topic, message) = dead.into_message();
+    conn.execute("DELETE FROM mailbox_messages WHERE seq = ?1", params![seq])?;
+    insert(conn, &topic, &message)?;
+    Ok(())
+}
+
+fn read_message(row: &rusqlite::Row) -> rusqlite::Result<(i64, [String; 6])> {
+    Ok((row.get(0)?, [row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?]))
+}
+
+fn decode_message([id, from, to, body, headers, meta]: [String; 6]) -> Result<MailMessage> {
+    Ok(MailMessage {
+        id,
+        from: from.parse()?,
+        to: to.parse()?,
+        body: serde_json::from_str(&body)?,
+        headers: serde_json::from_str(&headers)?,
+        meta: serde_json::from_str(&meta)?,
+    })
+}
+
+struct SqliteSubscription {
+    topics: Arc<RwLock<HashMap<String, Vec<Arc<Listener>>>>>,
+    topic: String,
+    listener: Arc<Listener>,
+}
+
+#[async_trait]
+impl Subscription for SqliteSubscription {
+    async fn unsubscribe(&mut self) -> Result<()> {
+        self.unsubscribe_now();
+        Ok(())
+    }
+
+    fn unsubscribe_now(&mut self) -> bool {
+        let mut topics = self.topics.write().unwrap();
+        if let Some(listeners) = topics.get_mut(&self.topic) {
+            listeners.retain(|l| !Arc::ptr_eq(l, &self.listener));
+        }
+        true
+    }
+}
+
+#[async_trait]
+impl MailboxProvider for SqliteProvider {
+    fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    async fn send(&self, mut message: MailMessage) -> Result<MailMessage> {
+        let topic = get_canonical_mailbox_address_identifier(&message.to);
+        let stored = message.clone();
+        message.id = with_conn(self.conn.clone(), move |conn| Self::enqueue(conn, &stored)).await?;
+        self.touch(&topic);
+
+        // Push to subscribers, once due for a scheduled message
+        let delay = message.deliver_at()
+            .and_then(|at| (at - chrono::Utc::now()).to_std().ok());
+        let topics = self.topics.clone();
+        let msg = message.clone();
+        tokio::spawn(async move {
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+            if msg.is_expired(chrono::Utc::now()) {
+                return;
+            }
+            let listeners = topics.read().unwrap().get(&topic).cloned().unwrap_or_default();
+            for listener in listeners {
+                let msg = msg.clone();
+                tokio::spawn(async move {
+                    (listener)(msg).await;
+                });
+            }
+        });
+
+        Ok(message)
+    }
+
+    async fn subscribe(
+        &self,
+        address: Url,
+        callback: Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>,
+    ) -> Result<Box<dyn Subscription>> {
+        let topic = get_canonical_mailbox_address_identifier(&address);
+
+        let listener = Arc::new(callback);
+        self.topics
+            .write()
+            .unwrap()
+            .entry(topic.clone())
+            .or_default()
+            .push(listener.clone());
+
+        self.touch(&topic);
+
+        Ok(Box::new(SqliteSubscription {
+            topics: self.topics.clone(),
+            topic,
+            listener,
+        }))
+    }
+
+    async fn fetch(&self, address: Url, options: FetchOptions) -> Result<Option<AckableMessage>> {
+        let topic = get_canonical_mailbox_address_identifier(&address);
+        self.touch(&topic);
+
+        let manual_ack = options.manual_ack;
+        let lease = Uuid::new_v4().to_string();
+        let dead_letter_topic = options.max_deliveries.map(|_| {
+            let address = options.dead_letter_address.clone()
+                .unwrap_or_else(|| default_dead_letter_address(&address));
+            get_canonical_mailbox_address_identifier(&address)
+        });
+
+        let (fetch_topic, fetch_lease, fetch_dead_letter_topic) = (topic.clone(), lease.clone(), dead_letter_topic.clone());
+        let fetched = with_conn(self.conn.clone(), move |conn| {
+            let topic = fetch_topic;
+            let tx = conn.transaction()?;
+            let now = now_millis();
+
+            // Expired rows go, unless someone still holds a lease on them.
+            tx.execute(
+                "DELETE FROM mailbox_messages
+                 WHERE topic = ?1 AND expires_at <= ?4
+                   AND (state = ?2 OR (state = ?3 AND visible_at <= ?4))",
+                params![topic, STATE_PENDING, STATE_IN_FLIGHT, now],
+            )?;
+
+            let (seq, message, deliveries) = loop {
+                let row = tx.query_row(
+                    "SELECT seq, id, from_address, to_address, body, headers, meta,
+                            state, deliveries, max_deliveries, dead_letter_topic
+                     FROM mailbox_messages
+                     WHERE topic = ?1
+                       AND ((state = ?2 AND (visible_at IS NULL OR visible_at <= ?4))
+                            OR (state = ?3 AND visible_at <= ?4))
+                       AND (expires_at IS NULL OR expires_at > ?4)
+                     ORDER BY seq
+                     LIMIT 1",
+                    params![topic, STATE_PENDING, STATE_IN_FLIGHT, now],
+                    |row| Ok((
+                        read_message(row)?,
+                        row.get::<_, String>(7)?,
+                        row.get::<_, u32>(8)?,
+                        row.get::<_, Option<u32>>(9)?,
+                        row.get::<_, Option<String>>(10)?,
+                    )),
+                ).optional()?;
+
+                let Some(((seq, columns), state, deliveries, max_deliveries, dead_letter_topic)) = row else {
+                    tx.commit()?;
+                    return Ok(None);
+                };
+                let message = decode_message(columns)?;
+
+                // A lease that ran out on its last delivery dead-letters the message.
+                match (max_deliveries, dead_letter_topic) {
+                    (Some(max_deliveries), Some(dead_letter_topic))
+                        if state == STATE_IN_FLIGHT && deliveries >= max_deliveries =>
+                    {
+                        dead_letter(&tx, seq, DeadLetter {
+                            topic: topic.clone(),
+                            dead_letter_topic,
+                            message,
+                            deliveries,
+                            reason: DeadLetterReason::AckTimeout,
+                        })?;
+                    }
+                    _ => break (seq, message, deliveries),
+                }
+            };
+
+            if !options.manual_ack {
+                tx.execute("DELETE FROM mailbox_messages WHERE seq = ?1", params![seq])?;
+                tx.commit()?;
+                return Ok(Some((seq, message, deliveries)));
+            }
+
+            let visible_at = options.ack_timeout.map(|timeout| now + timeout as i64);
+            let deliveries = deliveries + 1;
+            tx.execute(
+                "UPDATE mailbox_messages
+                 SET state = ?1, visible_at = ?2, lease = ?3, deliveries = ?4,
+                     max_deliveries = ?5, dead_letter_topic = ?6
+                 WHERE seq = ?7",
+                params![
+                    STATE_IN_FLIGHT, visible_at, fetch_lease, deliveries,
+                    options.max_deliveries, fetch_dead_letter_topic, seq,
+                ],
+            )?;
+            tx.commit()?;
+            Ok(Some((seq, message, deliveries)))
+        }).await?;
+
+        let Some((seq, message, deliveries)) = fetched else {
+            return Ok(None);
+        };
+        if !manual_ack {
+            return Ok(Some(AckableMessage {
+                message,
+                ack: Box::new(|| Box::pin(async { Ok(()) })),
+                nack: Box::new(|_| Box::pin(async { Ok(()) })),
+            }));
+        }
+
+        // Dead-lettered instead of requeued on a nack once the limit is reached.
+        let last_delivery = options.max_deliveries
+            .filter(|max_deliveries| deliveries >= *max_deliveries)
+            .and(dead_letter_topic)
+            .map(|dead_letter_topic| (dead_letter_topic, message.clone()));
+
+        let mut message = message;
+        message.meta.insert("delivery_count".to_string(), deliveries.into());
+
+        let ack_conn = self.conn.clone();
+        let nack_conn = self.conn.clone();
+        let ack_lease = lease.clone();
+
+        Ok(Some(AckableMessage {
+            message,
+            ack: Box::new(move || Box::pin(with_conn(ack_conn, move |conn| {
+                conn.execute(
+                    "DELETE FROM mailbox_messages WHERE seq = ?1 AND lease = ?2",
+                    params![seq, ack_lease],
+                )?;
+                Ok(())
+            }))),
+            nack: Box::new(move |requeue| Box::pin(with_conn(nack_conn, move |conn| {
+                match (requeue, last_delivery) {
+                    (true, Some((dead_letter_topic, message))) => {
+                        let tx = conn.transaction()?;
+                        let held = tx.query_row(
+                            "SELECT 1 FROM mailbox_messages WHERE seq = ?1 AND lease = ?2",
+                            params![seq, lease],
+                            |_| Ok(()),
+                        ).optional()?;
+                        if held.is_some() {
+                            dead_letter(&tx, seq, DeadLetter {
+                                topic,
+                                dead_letter_topic,
+                                message,
+                                deliveries,
+                                reason: DeadLetterReason::Nacked,
+                            })?;
+                        }
+                        tx.commit()?;
+                    }
+                    (true, None) => {
+                        // Keeping the original seq puts the message back at the head.
+                        conn.execute(
+                            "UPDATE mailbox_messages SET state = ?1, visible_at = ?2, lease = NULL
+                             WHERE seq = ?3 AND lease = ?4",
+                            params![STATE_PENDING, now_millis(), seq, lease],
+                        )?;
+                    }
+                    (false, _) => {
+                        conn.execute(
+                            "DELETE FROM mailbox_messages WHERE seq = ?1 AND lease = ?2",
+                            params![seq, lease],
+                        )?;
+                    }
+                }
+                Ok(())
+            }))),
+        }))
+    }
+
+    async fn status(&self, address: Url) -> Result<MailboxStatus> {
+        let topic = get_canonical_mailbox_address_identifier(&address);
+
+        // In-flight rows whose lease has run out are fetchable again, so they
+        // count as unread rather than in flight. Pending rows that are not
+        // due yet are scheduled. Expired rows only count while leased.
+        let query_topic = topic.clone();
+        let (unread_count, in_flight_count, scheduled_count): (i64, i64, i64) = with_conn(self.conn.clone(), move |conn| {
+            Ok(conn.query_row(
+                "SELECT
+                    COALESCE(SUM(((state = ?2 AND (visible_at IS NULL OR visible_at <= ?4))
+                                   OR (state = ?3 AND visible_at <= ?4))
+                                  AND (expires_at IS NULL OR expires_at > ?4)), 0),
+                    COALESCE(SUM(state = ?3 AND (visible_at IS NULL OR visible_at > ?4)), 0),
+                    COALESCE(SUM(state = ?2 AND visible_at > ?4 AND (expires_at IS NULL OR expires_at > ?4)), 0)
+                 FROM mailbox_messages WHERE topic = ?1",
+                params![query_topic, STATE_PENDING, STATE_IN_FLIGHT, now_millis()],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )?)
+        }).await?;
+        let last_activity_time = self.last_activity.read().unwrap().get(&topic).cloned();
+
+        let mut extra = HashMap::new();
+        extra.insert("in_flight_count".to_string(), in_flight_count.into());
+        extra.insert("scheduled_count".to_string(), scheduled_count.into());
+
+        Ok(MailboxStatus {
+            state: "online".to_string(),
+            unread_count: Some(unread_count as usize),
+            last_activity_time,
+            extra,
+        })
+    }
+
+    async fn purge(&self, address: Url) -> Result<()> {
+        let topic = get_canonical_mailbox_address_identifier(&address);
+        let purged = topic.clone();
+        // In-flight rows stay so that their holders can still settle them.
+        with_conn(self.conn.clone(), move |conn| {
+            conn.execute(
+                "DELETE FROM mailbox_messages WHERE topic = ?1 AND state = ?2",
+                params![purged, STATE_PENDING],
+            )?;
+            Ok(())
+        }).await?;
+        self.last_activity.write().unwrap().remove(&topic);
+        Ok(())
+    }
+
+    fn generate_id(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::test_mail;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_fetch_ack_and_status() -> Result<()> {
+        let provider = SqliteProvider::open_in_memory()?;
+        let address: Url = "sqlite:test/inbox".parse()?;
+
+        provider.send(test_mail("msg1", &address).into()).await?;
+        provider.send(test_mail("msg2", &address).into()).await?;
+
+        let status = provider.status(address.clone()).await?;
+        assert_eq!(status.unread_count, Some(2));
+
+        let options = FetchOptions {
+            manual_ack: true,
+            ack_timeout: None,
+            ..Default::default()
+        };
+        let msg = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        assert_eq!(msg.message.id, "msg1");
+        assert_eq!(msg.message.body, json!({"n": "msg1"}));
+        assert_eq!(msg.message.headers["kind"], "test");
+
+        let status = provider.status(address.clone()).await?;
+        assert_eq!(status.unread_count, Some(1));
+        assert_eq!(status.extra["in_flight_count"], json!(1));
+
+        msg.ack().await?;
+        let status = provider.status(address.clone()).await?;
+        assert_eq!(status.extra["in_flight_count"], json!(0));
+
+        let msg = provider.fetch(address, FetchOptions::default()).await?.unwrap();
+        assert_eq!(msg.message.id, "msg2");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nack_requeue_and_visibility_timeout() -> Result<()> {
+        let provider = SqliteProvider::open_in_memory()?;
+        let address: Url = "sqlite:test/nack".parse()?;
+
+        provider.send(test_mail("msg1", &address).into()).await?;
+        provider.send(test_mail("msg2", &address).into()).await?;
+
+        let options = FetchOptions {
+            manual_ack: true,
+            ack_timeout: Some(0),
+            ..Default::default()
+        };
+        let msg = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        msg.nack(true).await?;
+
+        // Requeued at the head
+        let msg = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        assert_eq!(msg.message.id, "msg1");
+
+        // Lease already expired, so it is redelivered without a nack
+        let status = provider.status(address.clone()).await?;
+        assert_eq!(status.unread_count, Some(2));
+        assert_eq!(status.extra["in_flight_count"], json!(0));
+        let again = provider.fetch(address, options).await?.unwrap();
+        assert_eq!(again.message.id, "msg1");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stale_ack_and_nack_ignore_new_lease() -> Result<()> {
+        let provider = SqliteProvider::open_in_memory()?;
+        let address: Url = "sqlite:test/stale".parse()?;
+        provider.send(test_mail("msg1", &address).into()).await?;
+
+        let expiring = FetchOptions {
+            manual_ack: true,
+            ack_timeout: Some(0),
+            ..Default::default()
+        };
+        let stale = provider.fetch(address.clone(), expiring.clone()).await?.unwrap();
+        let stale_nack = provider.fetch(address.clone(), expiring).await?.unwrap();
+
+        let options = FetchOptions {
+            manual_ack: true,
+            ack_timeout: None,
+            ..Default::default()
+        };
+        let current = provider.fetch(address.clone(), options).await?.unwrap();
+        assert_eq!(current.message.id, "msg1");
+
+        stale.ack().await?;
+        stale_nack.nack(true).await?;
+        let status = provider.status(address.clone()).await?;
+        assert_eq!(status.unread_count, Some(0));
+        assert_eq!(status.extra["in_flight_count"], json!(1));
+
+        current.ack().await?;
+        let status = provider.status(address).await?;
+        assert_eq!(status.extra["in_flight_count"], json!(0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter_after_max_deliveries() -> Result<()> {
+        let provider = SqliteProvider::open_in_memory()?;
+        let address: Url = "sqlite:test/retry".parse()?;
+        let dlq: Url = "sqlite:test/retry/dlq".parse()?;
+        provider.send(test_mail("msg1", &address).into()).await?;
+        provider.send(test_mail("msg2", &address).into()).await?;
+
+        let options = FetchOptions::default().manual_ack().max_deliveries(2);
+        for attempt in 1..=2 {
+            let msg = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+            assert_eq!(msg.message.id, "msg1");
+            assert_eq!(msg.message.meta["delivery_count"], json!(attempt));
+            msg.nack(true).await?;
+        }
+
+        // Lapsed leases count too.
+        let expiring = options.clone().ack_timeout(0);
+        for _ in 1..=2 {
+            let msg = provider.fetch(address.clone(), expiring.clone()).await?.unwrap();
+            assert_eq!(msg.message.id, "msg2");
+        }
+        assert!(provider.fetch(address.clone(), expiring).await?.is_none());
+
+        assert_eq!(provider.status(dlq.clone()).await?.unread_count, Some(2));
+        let dead = provider.fetch(dlq.clone(), FetchOptions::default()).await?.unwrap();
+        assert_eq!(dead.message.id, "msg1");
+        assert_eq!(dead.message.meta["dead_letter_reason"], json!("nacked"));
+        assert_eq!(dead.message.meta["delivery_count"], json!(2));
+        assert_eq!(dead.message.meta["original_topic"], json!("sqlite:test/retry"));
+        let dead = provider.fetch(dlq, FetchOptions::default()).await?.unwrap();
+        assert_eq!(dead.message.id, "msg2");
+        assert_eq!(dead.message.meta["dead_letter_reason"], json!("ack_timeout"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_delivery() -> Result<()> {
+        let provider = SqliteProvider::open_in_memory()?;
+        let address: Url = "sqlite:test/scheduled".parse()?;
+
+        let later = test_mail("later", &address).deliver_after(std::time::Duration::from_millis(50));
+        provider.send(later.into()).await?;
+        provider.send(test_mail("now", &address).into()).await?;
+
+        let status = provider.status(address.clone()).await?;
+        assert_eq!(status.unread_count, Some(1));
+        assert_eq!(status.extra["scheduled_count"], json!(1));
+        let msg = provider.fetch(address.clone(), FetchOptions::default()).await?.unwrap();
+        assert_eq!(msg.message.id, "now");
+        assert!(provider.fetch(address.clone(), FetchOptions::default()).await?.is_none());
+
+        tokio::time::sleep(std::time::Duration::from_millis(80)).await;
+        let msg = provider.fetch(address, FetchOptions::default()).await?.unwrap();
+        assert_eq!(msg.message.id, "later");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_returns_generated_id() -> Result<()> {
+        let provider = SqliteProvider::open_in_memory()?;
+        let address: Url = "sqlite:test/ids".parse()?;
+
+        let sent = provider.send(test_mail("", &address).into()).await?;
+        assert!(!sent.id.is_empty());
+        let fetched = provider.fetch(address, FetchOptions::default()).await?.unwrap();
+        assert_eq!(fetched.message.id, sent.id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_in_application_transaction() -> Result<()> {
+        let provider = SqliteProvider::open_in_memory()?;
+        let address: Url = "sqlite:test/tx".parse()?;
+
+        let result: Result<()> = provider.transaction(|tx| {
+            SqliteProvider::enqueue(tx, &test_mail("msg1", &address).into())?;
+            Err(MailboxError::Unknown("application write failed".to_string()))
+        });
+        assert!(result.is_err());
+        assert_eq!(provider.status(address.clone()).await?.unread_count, Some(0));
+
+        provider.transaction(|tx| {
+            tx.execute_batch("CREATE TABLE orders (id TEXT); INSERT INTO orders VALUES ('o1');")?;
+            SqliteProvider::enqueue(tx, &test_mail("msg2", &address).into())
+        })?;
+        assert_eq!(provider.status(address).await?.unread_count, Some(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_purge_keeps_in_flight_rows() -> Result<()> {
+        let provider = SqliteProvider::open_in_memory()?;
+        let address: Url = "sqlite:test/replies".parse()?;
+
+        provider.send(test_mail("msg1", &address).into()).await?;
+        provider.send(test_mail("msg2", &address).into()).await?;
+        let options = FetchOptions {
+            manual_ack: true,
+            ack_timeout: None,
+            ..Default::default()
+        };
+        let leased = provider.fetch(address.clone(), options).await?.unwrap();
+        provider.purge(address.clone()).await?;
+
+        let status = provider.status(address).await?;
+        assert_eq!(status.unread_count, Some(0));
+        assert_eq!(status.extra["in_flight_count"], json!(1));
+        leased.ack().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expired_rows_are_not_fetched() -> Result<()> {
+        let provider = SqliteProvider::open_in_memory()?;
+        let address: Url = "sqlite:test/expiring".parse()?;
+
+        let expiring = test_mail("msg1", &address).expires_after(std::time::Duration::from_millis(20));
+        provider.send(expiring.into()).await?;
+        provider.send(test_mail("msg2", &address).into()).await?;
+        assert_eq!(provider.status(address.clone()).await?.unread_count, Some(2));
+
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(provider.status(address.clone()).await?.unread_count, Some(1));
+        let fetched = provider.fetch(address.clone(), FetchOptions::default()).await?.unwrap();
+        assert_eq!(fetched.message.id, "msg2");
+        let rows: i64 = provider.transaction(|tx| {
+            Ok(tx.query_row("SELECT COUNT(*) FROM mailbox_messages", [], |row| row.get(0))?)
+        })?;
+        assert_eq!(rows, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,312 @@
+use async_trait::async_trait;
+use url::Url;
+use std::sync::Arc;
+use std::net::SocketAddr;
+use uuid::Uuid;
+use futures::future::BoxFuture;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::task::JoinHandle;
+
+use crate::error::{MailboxError, Result};
+use crate::message::{MailMessage, MailboxStatus, FetchOptions};
+use crate::provider::{MailboxProvider, Subscription, AckableMessage};
+use crate::providers::memory::{MemoryBus, MemoryProvider};
+use crate::providers::remote::{self, Connection, ConnectionPool};
+
+/// Provider for `tcp://host:port/path` addresses served by a `TcpServer`.
+///
+/// The path names a `mem:` mailbox on the server's bus, so posting to
+/// `tcp://127.0.0.1:7000/service/inbox` delivers to `mem:service/inbox` in
+/// the serving process. One connection per `host:port` is opened lazily and
+/// shared by every request and subscription; it is re-established on the
+/// next call after it drops. Subscriptions made on it end when it drops:
+/// their callbacks are released, so streams fed by them finish, and
+/// `unsubscribe` returns an error. Subscribe again to resume.
+pub struct TcpProvider {
+    protocol: String,
+    pool: ConnectionPool,
+}
+
+impl TcpProvider {
+    pub fn new() -> Self {
+        Self {
+            protocol: "tcp".to_string(),
+            pool: ConnectionPool::default(),
+        }
+    }
+
+    async fn connection(&self, address: &Url) -> Result<Arc<Connection>> {
+        let host = address.host_str()
+            .ok_or_else(|| MailboxError::InvalidAddress(address.to_string()))?;
+        let port = address.port()
+            .ok_or_else(|| MailboxError::InvalidAddress(format!("{} has no port", address)))?;
+        let endpoint = format!("{}:{}", host, port);
+
+        self.pool.get_or_connect(endpoint.clone(), async move {
+            let stream = TcpStream::connect(&endpoint).await?;
+            stream.set_nodelay(true)?;
+            Ok(Connection::spawn_stream(stream))
+        }).await
+    }
+}
+
+impl Default for TcpProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl MailboxProvider for TcpProvider {
+    fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    async fn send(&self, message: MailMessage) -> Result<MailMessage> {
+        self.connection(&message.to).await?.send(message).await
+    }
+
+    async fn subscribe(
+        &self,
+        address: Url,
+        callback: Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>,
+    ) -> Result<Box<dyn Subscription>> {
+        self.connection(&address).await?.subscribe(address, callback).await
+    }
+
+    async fn fetch(&self, address: Url, options: FetchOptions) -> Result<Option<AckableMessage>> {
+        self.connection(&address).await?.fetch(address, options).await
+    }
+
+    async fn status(&self, address: Url) -> Result<MailboxStatus> {
+        self.connection(&address).await?.status(address).await
+    }
+
+    fn generate_id(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+/// Exposes a `MemoryBus` to `TcpProvider`s in other processes.
+///
+/// Peers' un-acked fetches are requeued and their subscriptions dropped when
+/// they disconnect. The accept loop stops when the server is dropped.
+pub struct TcpServer {
+    local_addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl TcpServer {
+    pub async fn bind(addr: impl ToSocketAddrs, bus: Arc<MemoryBus>) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let provider = Arc::new(MemoryProvider::with_bus(bus));
+
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let _ = stream.set_nodelay(true);
+                tokio::spawn(remote::serve_stream(stream, provider.clone(), remote::local_address));
+            }
+        });
+
+        Ok(Self { local_addr, task })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for TcpServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::test_mail;
+    use crate::providers::memory::OverflowPolicy;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_send_reaches_server_bus() -> Result<()> {
+        let bus = Arc::new(MemoryBus::new());
+        let server = TcpServer::bind("127.0.0.1:0", bus.clone()).await?;
+        let local = MemoryProvider::with_bus(bus);
+        let provider = TcpProvider::new();
+
+        let address: Url = format!("tcp://{}/test/inbox", server.local_addr()).parse()?;
+        provider.send(test_mail("msg1", &address).into()).await?;
+
+        let status = provider.status(address).await?;
+        assert_eq!(status.unread_count, Some(1));
+
+        let fetched = local.fetch("mem:test/inbox".parse()?, FetchOptions::default()).await?;
+        assert_eq!(fetched.unwrap().message.id, "msg1");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remote_subscribe() -> Result<()> {
+        let bus = Arc::new(MemoryBus::new());
+        let server = TcpServer::bind("127.0.0.1:0", bus.clone()).await?;
+        let local = MemoryProvider::with_bus(bus);
+        let provider = TcpProvider::new();
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+
+        let address: Url = format!("tcp://{}/test/events", server.local_addr()).parse()?;
+        let mut sub = provider.subscribe(address, Box::new(move |msg| {
+            let tx = tx.clone();
+            Box::pin(async move {
+                if let Some(tx) = tx.lock().unwrap().take() {
+                    tx.send(msg).unwrap();
+                }
+            })
+        })).await?;
+
+        local.send(test_mail("msg2", &"mem:test/events".parse()?).into()).await?;
+
+        let received = rx.await.unwrap();
+        assert_eq!(received.id, "msg2");
+        sub.unsubscribe().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remote_manual_ack() -> Result<()> {
+        let bus = Arc::new(MemoryBus::new());
+        let server = TcpServer::bind("127.0.0.1:0", bus).await?;
+        let provider = TcpProvider::new();
+
+        let address: Url = format!("tcp://{}/test/ack", server.local_addr()).parse()?;
+        provider.send(test_mail("msg3", &address).into()).await?;
+
+        let options = FetchOptions {
+            manual_ack: true,
+            ack_timeout: None,
+            ..Default::default()
+        };
+        let fetched = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        assert_eq!(fetched.message.id, "msg3");
+        assert!(provider.fetch(address.clone(), options.clone()).await?.is_none());
+
+        fetched.nack(true).await?;
+        let fetched = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        assert_eq!(fetched.message.id, "msg3");
+        fetched.ack().await?;
+
+        assert!(provider.fetch(address, options).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_requeues_unacked() -> Result<()> {
+        let bus = Arc::new(MemoryBus::new());
+        let server = TcpServer::bind("127.0.0.1:0", bus.clone()).await?;
+        let local = MemoryProvider::with_bus(bus);
+
+        let address: Url = format!("tcp://{}/test/crash", server.local_addr()).parse()?;
+        let options = FetchOptions {
+            manual_ack: true,
+            ack_timeout: None,
+            ..Default::default()
+        };
+
+        {
+            let provider = TcpProvider::new();
+            provider.send(test_mail("msg4", &address).into()).await?;
+            let _fetched = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        }
+
+        let local_address: Url = "mem:test/crash".parse()?;
+        for _ in 0..50 {
+            if local.status(local_address.clone()).await?.unread_count == Some(1) {
+                return Ok(());
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("un-acked message was not requeued after disconnect");
+    }
+
+    #[tokio::test]
+    async fn test_blocked_send_does_not_stall_fetch() -> Result<()> {
+        let bus = Arc::new(MemoryBus::new());
+        bus.set_capacity(&"mem:test/full".parse()?, 1, OverflowPolicy::Block(Duration::from_secs(5)));
+        let server = TcpServer::bind("127.0.0.1:0", bus).await?;
+        let provider = Arc::new(TcpProvider::new());
+
+        let address: Url = format!("tcp://{}/test/full", server.local_addr()).parse()?;
+        provider.send(test_mail("msg5", &address).into()).await?;
+
+        let blocked = tokio::spawn({
+            let provider = provider.clone();
+            let message: MailMessage = test_mail("msg6", &address).into();
+            async move { provider.send(message).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert!(!blocked.is_finished());
+
+        // Same connection as the blocked send
+        let fetched = provider.fetch(address.clone(), FetchOptions::default()).await?.unwrap();
+        assert_eq!(fetched.message.id, "msg5");
+        blocked.await.unwrap()?;
+
+        let fetched = provider.fetch(address, FetchOptions::default()).await?.unwrap();
+        assert_eq!(fetched.message.id, "msg6");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_reply_fails_request() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            while let Ok(Some(_)) = remote::read_frame(&mut stream).await {
+                let _ = remote::write_frame(&mut stream, b"not json").await;
+            }
+        });
+
+        let provider = TcpProvider::new();
+        let address: Url = format!("tcp://{}/test/inbox", addr).parse()?;
+        let result = tokio::time::timeout(Duration::from_secs(1), provider.status(address)).await;
+        match result {
+            Ok(Err(MailboxError::ProviderError(e))) => assert!(e.contains("invalid frame"), "{}", e),
+            other => panic!("expected the request to fail, got {:?}", other.map(|r| r.is_ok())),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_ends_subscriptions() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let frame = remote::read_frame(&mut stream).await.unwrap().unwrap();
+            let request: remote::RequestFrame = serde_json::from_slice(&frame).unwrap();
+            let reply = remote::ServerFrame::Reply { id: request.id, reply: remote::Reply::Done };
+            remote::write_frame(&mut stream, &serde_json::to_vec(&reply).unwrap()).await.unwrap();
+            // Dropping the stream closes the connection.
+        });
+
+        let provider = TcpProvider::new();
+        let address: Url = format!("tcp://{}/test/events", addr).parse()?;
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<MailMessage>();
+        let mut sub = provider.subscribe(address, Box::new(move |msg| {
+            let _ = tx.send(msg);
+            Box::pin(async {})
+        })).await?;
+
+        // The callback, and with it the sender, is dropped.
+        let ended = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await;
+        assert!(matches!(ended, Ok(None)));
+        assert!(sub.unsubscribe().await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+use async_trait::async_trait;
+use url::Url;
+use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use uuid::Uuid;
+use futures::future::BoxFuture;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+
+use crate::error::{MailboxError, Result};
+use crate::message::{MailMessage, MailboxStatus, FetchOptions};
+use crate::provider::{MailboxProvider, Subscription, AckableMessage};
+use crate::providers::memory::{MemoryBus, MemoryProvider};
+use crate::providers::remote::{self, Connection, ConnectionPool};
+
+/// Splits a `unix:` address into the socket file and the mailbox path after it.
+///
+/// The socket file ends at the first path segment with a `.sock` extension;
+/// without one, the whole path is the socket and the mailbox path is empty.
+/// `unix:///run/app.sock/billing/inbox` is the mailbox `billing/inbox` served
+/// on `/run/app.sock`.
+pub fn split_socket_path(address: &Url) -> (PathBuf, String) {
+    let path = address.path();
+    let mut end = path.len();
+    let mut offset = 0;
+    for segment in path.split('/') {
+        offset += segment.len();
+        if segment.ends_with(".sock") {
+            end = offset;
+            break;
+        }
+        offset += 1;
+    }
+
+    let mailbox = path[end..].trim_start_matches('/').to_string();
+    (PathBuf::from(&path[..end]), mailbox)
+}
+
+fn local_address(address: &Url) -> Result<Url> {
+    let (_, mailbox) = split_socket_path(address);
+    Ok(format!("mem:{}", mailbox).parse()?)
+}
+
+/// Provider for `unix:` addresses served by a `UnixServer` on the same host.
+///
+/// The socket file is named by the address path up to its `.sock` segment
+/// (see `split_socket_path`) and the rest of the path names a `mem:` mailbox
+/// on the server's bus. Supports push via `subscribe` and pull via `fetch`
+/// with manual ack, over one shared connection per socket. As with
+/// `TcpProvider`, subscriptions end if that connection drops.
+pub struct UnixProvider {
+    protocol: String,
+    pool: ConnectionPool,
+}
+
+impl UnixProvider {
+    pub fn new() -> Self {
+        Self {
+            protocol: "unix".to_string(),
+            pool: ConnectionPool::default(),
+        }
+    }
+
+    async fn connection(&self, address: &Url) -> Result<Arc<Connection>> {
+        let (socket, _) = split_socket_path(address);
+        if socket.as_os_str().is_empty() {
+            return Err(MailboxError::InvalidAddress(address.to_string()));
+        }
+
+        self.pool.get_or_connect(socket.to_string_lossy().into_owned(), async move {
+            let stream = UnixStream::connect(&socket).await?;
+            Ok(Connection::spawn_stream(stream))
+        }).await
+    }
+}
+
+impl Default for UnixProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl MailboxProvider for UnixProvider {
+    fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    async fn send(&self, message: MailMessage) -> Result<MailMessage> {
+        self.connection(&message.to).await?.send(message).await
+    }
+
+    async fn subscribe(
+        &self,
+        address: Url,
+        callback: Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>,
+    ) -> Result<Box<dyn Subscription>> {
+        self.connection(&address).await?.subscribe(address, callback).await
+    }
+
+    async fn fetch(&self, address: Url, options: FetchOptions) -> Result<Option<AckableMessage>> {
+        self.connection(&address).await?.fetch(address, options).await
+    }
+
+    async fn status(&self, address: Url) -> Result<MailboxStatus> {
+        self.connection(&address).await?.status(address).await
+    }
+
+    fn generate_id(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+/// Exposes a `MemoryBus` to `UnixProvider`s on a socket file.
+///
+/// A stale socket file left behind by a crashed server is replaced; one
+/// that still accepts connections, or any path that is not a socket, is
+/// reported as in use. The socket file is removed when the server is dropped,
+/// unless something else has replaced it by then.
+pub struct UnixServer {
+    path: PathBuf,
+    // Device and inode of the socket file this server created
+    socket: (u64, u64),
+    task: JoinHandle<()>,
+}
+
+impl UnixServer {
+    pub async fn bind(path: impl AsRef<Path>, bus: Arc<MemoryBus>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+            if !metadata.file_type().is_socket() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    format!("{} exists and is not a socket", path.display()),
+                ).into());
+            }
+            if UnixStream::connect(&path).await.is_ok() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    format!("{} is already being served", path.display()),
+                ).into());
+            }
+            std::fs::remove_file(&path)?;
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        let metadata = std::fs::symlink_metadata(&path)?;
+        let socket = (metadata.dev(), metadata.ino());
+        let provider = Arc::new(MemoryProvider::with_bus(bus));
+
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(remote::serve_stream(stream, provider.clone(), local_address));
+            }
+        });
+
+        Ok(Self { path, socket, task })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for UnixServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        let is_ours = std::fs::symlink_metadata(&self.path)
+            .map(|metadata| (metadata.dev(), metadata.ino()) == self.socket)
+            .unwrap_or(false);
+        if is_ours {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::test_mail;
+    use std::sync::Mutex;
+
+    fn temp_socket() -> PathBuf {
+        std::env::temp_dir().join(format!("mailbox-{}.sock", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_split_socket_path() -> Result<()> {
+        let (socket, mailbox) = split_socket_path(&"unix:///run/app.sock/billing/inbox".parse()?);
+        assert_eq!(socket, PathBuf::from("/run/app.sock"));
+        assert_eq!(mailbox, "billing/inbox");
+
+        let (socket, mailbox) = split_socket_path(&"unix:///run/daemon".parse()?);
+        assert_eq!(socket, PathBuf::from("/run/daemon"));
+        assert_eq!(mailbox, "");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_push_and_pull() -> Result<()> {
+        let socket = temp_socket();
+        let bus = Arc::new(MemoryBus::new());
+        let server = UnixServer::bind(&socket, bus.clone()).await?;
+        let local = MemoryProvider::with_bus(bus);
+        let provider = UnixProvider::new();
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+
+        let events: Url = format!("unix://{}/test/events", socket.display()).parse()?;
+        let _sub = provider.subscribe(events.clone(), Box::new(move |msg| {
+            let tx = tx.clone();
+            Box::pin(async move {
+                if let Some(tx) = tx.lock().unwrap().take() {
+                    tx.send(msg).unwrap();
+                }
+            })
+        })).await?;
+        provider.send(test_mail("msg1", &events).into()).await?;
+        assert_eq!(rx.await.unwrap().id, "msg1");
+
+        let jobs: Url = format!("unix://{}/test/jobs", socket.display()).parse()?;
+        local.send(test_mail("msg2", &"mem:test/jobs".parse()?).into()).await?;
+        let options = FetchOptions {
+            manual_ack: true,
+            ack_timeout: None,
+            ..Default::default()
+        };
+        let fetched = provider.fetch(jobs.clone(), options.clone()).await?.unwrap();
+        assert_eq!(fetched.message.id, "msg2");
+        fetched.ack().await?;
+        assert_eq!(provider.status(jobs).await?.unread_count, Some(0));
+
+        drop(server);
+        assert!(!socket.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stale_socket_is_replaced() -> Result<()> {
+        let socket = temp_socket();
+        drop(std::os::unix::net::UnixListener::bind(&socket)?);
+        assert!(socket.exists());
+
+        let server = UnixServer::bind(&socket, Arc::new(MemoryBus::new())).await?;
+        assert!(UnixServer::bind(&socket, Arc::new(MemoryBus::new())).await.is_err());
+        drop(server);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replacing_socket_is_left_alone() -> Result<()> {
+        let socket = temp_socket();
+        let server = UnixServer::bind(&socket, Arc::new(MemoryBus::new())).await?;
+
+        // Another server takes over the path while this one still runs.
+        std::fs::remove_file(&socket)?;
+        let other = UnixServer::bind(&socket, Arc::new(MemoryBus::new())).await?;
+        drop(server);
+        assert!(socket.exists());
+
+        drop(other);
+        assert!(!socket.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_regular_file_is_not_replaced() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("mailbox-{}.txt", Uuid::new_v4()));
+        std::fs::write(&path, "keep me")?;
+
+        assert!(UnixServer::bind(&path, Arc::new(MemoryBus::new())).await.is_err());
+        assert_eq!(std::fs::read_to_string(&path)?, "keep me");
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,371 @@
+use async_trait::async_trait;
+use url::Url;
+use std::sync::Arc;
+use uuid::Uuid;
+use futures::future::BoxFuture;
+use tokio::sync::mpsc;
+
+use crate::error::{MailboxError, Result};
+use crate::message::{MailMessage, MailboxStatus, FetchOptions};
+use crate::provider::{MailboxProvider, Subscription, AckableMessage};
+use crate::providers::remote::{Connection, ConnectionPool, FRAME_QUEUE_LEN};
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::net::SocketAddr;
+#[cfg(not(target_arch = "wasm32"))]
+use futures::{SinkExt, StreamExt};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::net::{TcpListener, ToSocketAddrs};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::task::JoinHandle;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_tungstenite::tungstenite::Message;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::providers::memory::{MemoryBus, MemoryProvider};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::providers::remote::{self, ServerSession};
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<tokio_tungstenite::tungstenite::Error> for MailboxError {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        MailboxError::ProviderError(format!("websocket: {}", e))
+    }
+}
+
+/// Provider for `ws://host:port/path` (or, via `WsProvider::secure`, `wss:`)
+/// addresses served by a `WsServer`.
+///
+/// Speaks the same protocol as `TcpProvider`, one JSON frame per WebSocket
+/// message, so the path names a `mem:` mailbox on the server's bus. Every
+/// address on the same host and port shares one connection, and as with
+/// `TcpProvider` subscriptions end if it drops. Works on native targets via
+/// tokio-tungstenite and in the browser via the `WebSocket` API.
+pub struct WsProvider {
+    protocol: String,
+    pool: ConnectionPool,
+}
+
+impl WsProvider {
+    pub fn new() -> Self {
+        Self {
+            protocol: "ws".to_string(),
+            pool: ConnectionPool::default(),
+        }
+    }
+
+    pub fn secure() -> Self {
+        Self {
+            protocol: "wss".to_string(),
+            pool: ConnectionPool::default(),
+        }
+    }
+
+    async fn connection(&self, address: &Url) -> Result<Arc<Connection>> {
+        let host = address.host_str()
+            .ok_or_else(|| MailboxError::InvalidAddress(address.to_string()))?;
+        let port = address.port_or_known_default()
+            .ok_or_else(|| MailboxError::InvalidAddress(address.to_string()))?;
+        let endpoint = format!("{}://{}:{}/", address.scheme(), host, port);
+
+        self.pool.get_or_connect(endpoint.clone(), connect(endpoint)).await
+    }
+}
+
+impl Default for WsProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn connect(endpoint: String) -> Result<Arc<Connection>> {
+    let (stream, _) = tokio_tungstenite::connect_async(endpoint).await?;
+    let (mut sink, mut source) = stream.split();
+    let (outgoing, mut frames) = mpsc::channel::<Vec<u8>>(FRAME_QUEUE_LEN);
+    let connection = Connection::new(outgoing);
+
+    tokio::spawn(async move {
+        while let Some(frame) = frames.recv().await {
+            let Ok(text) = String::from_utf8(frame) else { break };
+            if sink.send(Message::text(text)).await.is_err() {
+                break;
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    let reading = Arc::downgrade(&connection);
+    tokio::spawn(async move {
+        while let Some(Ok(message)) = source.next().await {
+            let frame = match message {
+                Message::Text(text) => text.as_bytes().to_vec(),
+                Message::Binary(bytes) => bytes.to_vec(),
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            match reading.upgrade() {
+                Some(connection) if connection.handle_frame(&frame).is_ok() => {}
+                _ => return,
+            }
+        }
+        if let Some(connection) = reading.upgrade() {
+            connection.close();
+        }
+    });
+
+    Ok(connection)
+}
+
+#[cfg(target_arch = "wasm32")]
+async fn connect(endpoint: String) -> Result<Arc<Connection>> {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use tokio::sync::oneshot;
+    use wasm_bindgen::closure::Closure;
+    use wasm_bindgen::JsCast;
+    use web_sys::{BinaryType, MessageEvent, WebSocket};
+
+    let (outgoing, mut frames) = mpsc::channel::<Vec<u8>>(FRAME_QUEUE_LEN);
+    let connection = Connection::new(outgoing);
+    let (ready_tx, ready_rx) = oneshot::channel::<std::result::Result<(), String>>();
+    let reading = Arc::downgrade(&connection);
+
+    // The socket and its callbacks are not `Send`, so they live entirely in a
+    // local task; the connection only talks to it through channels.
+    wasm_bindgen_futures::spawn_local(async move {
+        let ws = match WebSocket::new(&endpoint) {
+            Ok(ws) => ws,
+            Err(e) => {
+                let _ = ready_tx.send(Err(format!("{:?}", e)));
+                return;
+            }
+        };
+        ws.set_binary_type(BinaryType::Arraybuffer);
+        let ready = Rc::new(RefCell::new(Some(ready_tx)));
+
+        let onopen = {
+            let ready = ready.clone();
+            Closure::<dyn FnMut()>::new(move || {
+                if let Some(tx) = ready.borrow_mut().take() {
+                    let _ = tx.send(Ok(()));
+                }
+            })
+        };
+        let onclose = {
+            let ready = ready.clone();
+            let closing = reading.clone();
+            Closure::<dyn FnMut()>::new(move || {
+                if let Some(tx) = ready.borrow_mut().take() {
+                    let _ = tx.send(Err("connection refused".to_string()));
+                }
+                if let Some(connection) = closing.upgrade() {
+                    connection.close();
+                }
+            })
+        };
+        let onmessage = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+            let data = event.data();
+            let frame = if let Some(text) = data.as_string() {
+                text.into_bytes()
+            } else if let Ok(buffer) = data.dyn_into::<js_sys::ArrayBuffer>() {
+                js_sys::Uint8Array::new(&buffer).to_vec()
+            } else {
+                return;
+            };
+            if let Some(connection) = reading.upgrade() {
+                let _ = connection.handle_frame(&frame);
+            }
+        });
+
+        ws.set_onopen(Some(onopen.as_ref().unchecked_ref()));
+        ws.set_onclose(Some(onclose.as_ref().unchecked_ref()));
+        ws.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+
+        while let Some(frame) = frames.recv().await {
+            let Ok(text) = std::str::from_utf8(&frame) else { break };
+            if ws.send_with_str(text).is_err() {
+                break;
+            }
+        }
+
+        ws.set_onopen(None);
+        ws.set_onclose(None);
+        ws.set_onmessage(None);
+        let _ = ws.close();
+    });
+
+    match ready_rx.await {
+        Ok(Ok(())) => Ok(connection),
+        Ok(Err(e)) => Err(MailboxError::ProviderError(format!("websocket: {}", e))),
+        Err(_) => Err(MailboxError::ProviderError("websocket: connection closed".to_string())),
+    }
+}
+
+#[async_trait]
+impl MailboxProvider for WsProvider {
+    fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    async fn send(&self, message: MailMessage) -> Result<MailMessage> {
+        self.connection(&message.to).await?.send(message).await
+    }
+
+    async fn subscribe(
+        &self,
+        address: Url,
+        callback: Box<dyn Fn(MailMessage) -> BoxFuture<'static, ()> + Send + Sync>,
+    ) -> Result<Box<dyn Subscription>> {
+        self.connection(&address).await?.subscribe(address, callback).await
+    }
+
+    async fn fetch(&self, address: Url, options: FetchOptions) -> Result<Option<AckableMessage>> {
+        self.connection(&address).await?.fetch(address, options).await
+    }
+
+    async fn status(&self, address: Url) -> Result<MailboxStatus> {
+        self.connection(&address).await?.status(address).await
+    }
+
+    fn generate_id(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+/// Exposes a `MemoryBus` to `WsProvider`s, including ones running in a browser.
+///
+/// Behaves like `TcpServer`: peers' un-acked fetches are requeued and their
+/// subscriptions dropped when they disconnect, and the accept loop stops
+/// when the server is dropped.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct WsServer {
+    local_addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl WsServer {
+    pub async fn bind(addr: impl ToSocketAddrs, bus: Arc<MemoryBus>) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let provider = Arc::new(MemoryProvider::with_bus(bus));
+
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let provider = provider.clone();
+                tokio::spawn(async move {
+                    if let Ok(stream) = tokio_tungstenite::accept_async(stream).await {
+                        serve(stream, provider).await;
+                    }
+                });
+            }
+        });
+
+        Ok(Self { local_addr, task })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for WsServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn serve(
+    stream: tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
+    provider: Arc<MemoryProvider>,
+) {
+    let (mut sink, source) = stream.split();
+    let (outgoing, mut frames) = mpsc::channel::<Vec<u8>>(FRAME_QUEUE_LEN);
+
+    let writing = tokio::spawn(async move {
+        while let Some(frame) = frames.recv().await {
+            let Ok(text) = String::from_utf8(frame) else { break };
+            if sink.send(Message::text(text)).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let requests = futures::stream::unfold(source, |mut source| async move {
+        while let Some(Ok(message)) = source.next().await {
+            let frame = match message {
+                Message::Text(text) => text.as_bytes().to_vec(),
+                Message::Binary(bytes) => bytes.to_vec(),
+                Message::Close(_) => return None,
+                _ => continue,
+            };
+            return Some((frame, source));
+        }
+        None
+    });
+    ServerSession::new(provider, remote::local_address, outgoing).run(Box::pin(requests)).await;
+    writing.abort();
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::message::test_mail;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_multiplexed_subscriptions() -> Result<()> {
+        let bus = Arc::new(MemoryBus::new());
+        let server = WsServer::bind("127.0.0.1:0", bus.clone()).await?;
+        let local = MemoryProvider::with_bus(bus);
+        let provider = WsProvider::new();
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let tx = Arc::new(Mutex::new(tx));
+
+        let mut subs = Vec::new();
+        for inbox in ["a", "b"] {
+            let tx = tx.clone();
+            let address: Url = format!("ws://{}/test/{}", server.local_addr(), inbox).parse()?;
+            subs.push(provider.subscribe(address, Box::new(move |msg| {
+                let tx = tx.clone();
+                Box::pin(async move {
+                    tx.lock().unwrap().send(msg.id).unwrap();
+                })
+            })).await?);
+        }
+
+        local.send(test_mail("msg1", &"mem:test/a".parse()?).into()).await?;
+        local.send(test_mail("msg2", &"mem:test/b".parse()?).into()).await?;
+
+        let mut received = vec![rx.recv().await.unwrap(), rx.recv().await.unwrap()];
+        received.sort();
+        assert_eq!(received, ["msg1", "msg2"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_post_fetch_and_status() -> Result<()> {
+        let bus = Arc::new(MemoryBus::new());
+        let server = WsServer::bind("127.0.0.1:0", bus).await?;
+        let provider = WsProvider::new();
+
+        let address: Url = format!("ws://{}/test/inbox", server.local_addr()).parse()?;
+        provider.send(test_mail("msg3", &address).into()).await?;
+        assert_eq!(provider.status(address.clone()).await?.unread_count, Some(1));
+
+        let options = FetchOptions {
+            manual_ack: true,
+            ack_timeout: None,
+            ..Default::default()
+        };
+        let fetched = provider.fetch(address.clone(), options.clone()).await?.unwrap();
+        assert_eq!(fetched.message.id, "msg3");
+        fetched.ack().await?;
+
+        assert!(provider.fetch(address, options).await?.is_none());
+        Ok(())
+    }
+}
@@ -1,5 +1,8 @@
 use url::Url;
 
+use crate::error::{MailboxError, Result};
+use crate::message::{FetchOptions, MailMessage, DELIVER_AT_HEADER, EXPIRES_AT_HEADER};
+
 pub fn get_canonical_mailbox_address_identifier(url: &Url) -> String {
     let scheme = url.scheme();
     let username = url.username();
@@ -12,3 +15,46 @@ pub fn get_canonical_mailbox_address_identifier(url: &Url) -> String {
         format!("{}:{}{}", scheme, host, path)
     }
 }
+
+/// The dead-letter mailbox used for `address` when none is configured:
+/// `<address>/dlq`.
+pub fn default_dead_letter_address(address: &Url) -> Url {
+    let mut dlq = address.clone();
+    dlq.set_path(&format!("{}/dlq", address.path().trim_end_matches('/')));
+    dlq.set_query(None);
+    dlq.set_fragment(None);
+    dlq
+}
+
+/// Fails a fetch that asks for a delivery limit on a provider that cannot
+/// count deliveries, rather than silently ignoring it.
+pub(crate) fn reject_delivery_limit(protocol: &str, options: &FetchOptions) -> Result<()> {
+    if options.max_deliveries.is_some() {
+        return Err(MailboxError::ProviderError(format!(
+            "{}: max_deliveries is not supported", protocol
+        )));
+    }
+    Ok(())
+}
+
+/// Fails a send of a scheduled message on a provider that cannot hold it
+/// back until its `deliver-at` time, rather than delivering it early.
+pub(crate) fn reject_deliver_at(protocol: &str, message: &MailMessage) -> Result<()> {
+    if message.headers.contains_key(DELIVER_AT_HEADER) {
+        return Err(MailboxError::ProviderError(format!(
+            "{}: {} is not supported", protocol, DELIVER_AT_HEADER
+        )));
+    }
+    Ok(())
+}
+
+/// Fails a send of an expiring message on a provider that cannot drop it
+/// once its `expires-at` time passes, rather than delivering it stale.
+pub(crate) fn reject_expires_at(protocol: &str, message: &MailMessage) -> Result<()> {
+    if message.headers.contains_key(EXPIRES_AT_HEADER) {
+        return Err(MailboxError::ProviderError(format!(
+            "{}: {} is not supported", protocol, EXPIRES_AT_HEADER
+        )));
+    }
+    Ok(())
+}